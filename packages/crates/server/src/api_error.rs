@@ -0,0 +1,579 @@
+/*
+Errors returned by the API, rendered as RFC 7807 problem details with the
+`application/problem+json` content type. https://www.rfc-editor.org/rfc/rfc7807
+
+`code` is stable and meant for clients to match on, `detail` is meant for humans and may change.
+Errors about specific request fields list each of them in `errors`. Every problem carries the id
+of the request it answers, which is also sent back as the `x-request-id` header on every response.
+A request id sent by the client is kept, otherwise one is generated.
+*/
+
+use std::error::Error;
+
+use axum::{
+    body::Body,
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection, StringRejection},
+        OriginalUri, Path, Query, Request,
+    },
+    http::{header, HeaderName, HeaderValue, Method, Response, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+    Json,
+};
+use axum_macros::{FromRequest, FromRequestParts};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api_models::ImportReport;
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Longer client request ids are replaced rather than echoed back
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Stable, machine-readable error codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request body or query string is malformed or out of range
+    InvalidRequest,
+    /// The request is well-formed but some fields are invalid, `errors` lists them
+    ValidationFailed,
+    /// The request body isn't JSON
+    UnsupportedMediaType,
+    /// None of the representations in `Accept` is offered
+    NotAcceptable,
+    /// No route matches the path
+    NotFound,
+    /// The route doesn't support the method, `Allow` lists those it does
+    MethodNotAllowed,
+    PayloadTooLarge,
+    PromptNotFound,
+    /// The prompt is in the trash
+    PromptArchived,
+    CategoryNotFound,
+    /// Import ids are taken by different prompts, nothing was written
+    ImportConflict,
+    /// A lineage root can't be purged while other versions of the lineage exist
+    LineageNotEmpty,
+    BackupsDisabled,
+    CacheDisabled,
+    /// No database connection became available in time, retry after `Retry-After` seconds
+    ServiceUnavailable,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::InvalidRequest => "invalid_request",
+            Self::ValidationFailed => "validation_failed",
+            Self::UnsupportedMediaType => "unsupported_media_type",
+            Self::NotAcceptable => "not_acceptable",
+            Self::NotFound => "not_found",
+            Self::MethodNotAllowed => "method_not_allowed",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::PromptNotFound => "prompt_not_found",
+            Self::PromptArchived => "prompt_archived",
+            Self::CategoryNotFound => "category_not_found",
+            Self::ImportConflict => "import_conflict",
+            Self::LineageNotEmpty => "lineage_not_empty",
+            Self::BackupsDisabled => "backups_disabled",
+            Self::CacheDisabled => "cache_disabled",
+            Self::ServiceUnavailable => "service_unavailable",
+            Self::InternalError => "internal_error",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::InvalidRequest => StatusCode::BAD_REQUEST,
+            Self::ValidationFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::NotFound
+            | Self::PromptNotFound
+            | Self::CategoryNotFound
+            | Self::BackupsDisabled
+            | Self::CacheDisabled => StatusCode::NOT_FOUND,
+            Self::PromptArchived => StatusCode::GONE,
+            Self::ImportConflict | Self::LineageNotEmpty => StatusCode::CONFLICT,
+            Self::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::InvalidRequest => "Invalid request",
+            Self::ValidationFailed => "Validation failed",
+            Self::UnsupportedMediaType => "Unsupported media type",
+            Self::NotAcceptable => "Not acceptable",
+            Self::NotFound => "Not found",
+            Self::MethodNotAllowed => "Method not allowed",
+            Self::PayloadTooLarge => "Payload too large",
+            Self::PromptNotFound => "Prompt not found",
+            Self::PromptArchived => "Prompt is archived",
+            Self::CategoryNotFound => "Category not found",
+            Self::ImportConflict => "Import conflict",
+            Self::LineageNotEmpty => "Lineage still has other versions",
+            Self::BackupsDisabled => "Backups are not enabled",
+            Self::CacheDisabled => "Content cache is disabled",
+            Self::ServiceUnavailable => "Service unavailable",
+            Self::InternalError => "Internal server error",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    /// The offending field, ie `limit` or `tags[2]`
+    pub field: String,
+    pub message: String,
+}
+
+/// RFC 7807 problem details
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ProblemDetails {
+    /// URI identifying the problem type, `urn:sps:error:<code>`
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// Short summary of the problem type
+    pub title: String,
+    /// The HTTP status code
+    pub status: u16,
+    /// Explanation of this occurrence of the problem
+    pub detail: String,
+    pub code: ErrorCode,
+    /// Id of the request, also sent as the `x-request-id` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Every offending request field, if the problem is about specific fields
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// What the import would have done, for import conflicts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub import: Option<ImportReport>,
+}
+
+/// No database connection became available in time. Clients should retry after `Retry-After`
+/// seconds instead of piling more requests onto a saturated pool.
+pub struct ServiceUnavailable;
+
+pub const RETRY_AFTER_SECS: u64 = 1;
+
+/// The error type of every handler
+#[derive(Debug)]
+pub struct ApiError {
+    code: ErrorCode,
+    detail: String,
+    errors: Vec<FieldError>,
+    import: Option<Box<ImportReport>>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, detail: impl Into<String>) -> Self {
+        Self {
+            code,
+            detail: detail.into(),
+            errors: Vec::new(),
+            import: None,
+        }
+    }
+
+    /// A single invalid request field
+    pub fn invalid_field(field: &str, message: impl Into<String>) -> Self {
+        let message = message.into();
+        Self {
+            errors: vec![FieldError {
+                field: field.to_string(),
+                message: message.clone(),
+            }],
+            ..Self::new(ErrorCode::InvalidRequest, format!("{field}: {message}"))
+        }
+    }
+
+    /// Every invalid field of a request
+    pub fn validation(errors: Vec<FieldError>) -> Self {
+        let detail = errors
+            .iter()
+            .map(|e| format!("{}: {}", e.field, e.message))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self {
+            errors,
+            ..Self::new(ErrorCode::ValidationFailed, detail)
+        }
+    }
+
+    pub fn prompt_not_found(id: &str) -> Self {
+        Self::new(
+            ErrorCode::PromptNotFound,
+            format!("Prompt {id} does not exist"),
+        )
+    }
+
+    pub fn prompt_archived(id: &str) -> Self {
+        Self::new(
+            ErrorCode::PromptArchived,
+            format!("Prompt {id} is in the trash"),
+        )
+    }
+
+    /// Details are logged where the error happens and never sent to the client
+    pub fn internal() -> Self {
+        Self::new(
+            ErrorCode::InternalError,
+            "The request could not be completed",
+        )
+    }
+
+    pub fn import_conflict(report: ImportReport) -> Self {
+        Self {
+            import: Some(Box::new(report)),
+            ..Self::new(
+                ErrorCode::ImportConflict,
+                "Ids are taken by different prompts, nothing was imported",
+            )
+        }
+    }
+}
+
+impl From<ServiceUnavailable> for ApiError {
+    fn from(_: ServiceUnavailable) -> Self {
+        Self::new(
+            ErrorCode::ServiceUnavailable,
+            "No database connection is available, retry later",
+        )
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let detail = rejection.body_text();
+        match rejection {
+            JsonRejection::JsonDataError(e) => {
+                // Where in the body deserializing failed, unless it's the body as a whole
+                let path = std::iter::successors(e.source(), |&source| source.source())
+                    .find_map(|source| {
+                        source.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>()
+                    })
+                    .map(|error| error.path().to_string())
+                    .filter(|path| path != ".");
+                Self {
+                    errors: path
+                        .map(|field| FieldError {
+                            field,
+                            message: detail.clone(),
+                        })
+                        .into_iter()
+                        .collect(),
+                    ..Self::new(ErrorCode::ValidationFailed, detail)
+                }
+            }
+            JsonRejection::MissingJsonContentType(_) => {
+                Self::new(ErrorCode::UnsupportedMediaType, detail)
+            }
+            rejection if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                Self::new(ErrorCode::PayloadTooLarge, detail)
+            }
+            _ => Self::new(ErrorCode::InvalidRequest, detail),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(ErrorCode::InvalidRequest, rejection.body_text())
+    }
+}
+
+impl From<StringRejection> for ApiError {
+    fn from(rejection: StringRejection) -> Self {
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => {
+                Self::new(ErrorCode::PayloadTooLarge, rejection.body_text())
+            }
+            _ => Self::new(ErrorCode::InvalidRequest, rejection.body_text()),
+        }
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        // A handler asking for path parameters its route doesn't have is a bug, not a bad request
+        match rejection.status() {
+            StatusCode::INTERNAL_SERVER_ERROR => Self::internal(),
+            _ => Self::new(ErrorCode::InvalidRequest, rejection.body_text()),
+        }
+    }
+}
+
+/// `Json` whose rejections are problem details
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+/// `Query` whose rejections are problem details
+#[derive(FromRequestParts)]
+#[from_request(via(Query), rejection(ApiError))]
+pub struct ApiQuery<T>(pub T);
+
+/// `Path` whose rejections are problem details
+#[derive(FromRequestParts)]
+#[from_request(via(Path), rejection(ApiError))]
+pub struct ApiPath<T>(pub T);
+
+/// The body as UTF-8 text, whose rejections are problem details
+pub struct ApiText(pub String);
+
+impl<S: Send + Sync> axum::extract::FromRequest<S> for ApiText {
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let text = <String as axum::extract::FromRequest<S>>::from_request(request, state).await?;
+        Ok(Self(text))
+    }
+}
+
+/// Router fallback, so unknown paths get problem details too
+pub async fn not_found(OriginalUri(uri): OriginalUri) -> ApiError {
+    ApiError::new(ErrorCode::NotFound, format!("No route for {}", uri.path()))
+}
+
+/// Fallback for a method a route doesn't support, the router adds the `Allow` header
+pub async fn method_not_allowed(method: Method, OriginalUri(uri): OriginalUri) -> ApiError {
+    ApiError::new(
+        ErrorCode::MethodNotAllowed,
+        format!("{} is not allowed on {}", method, uri.path()),
+    )
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response<Body> {
+        let status = self.code.status();
+        let problem = ProblemDetails {
+            problem_type: format!("urn:sps:error:{}", self.code.as_str()),
+            title: self.code.title().to_string(),
+            status: status.as_u16(),
+            detail: self.detail,
+            code: self.code,
+            request_id: REQUEST_ID.try_with(Clone::clone).ok(),
+            errors: self.errors,
+            import: self.import.map(|report| *report),
+        };
+        let mut response = (
+            status,
+            [(header::CONTENT_TYPE, PROBLEM_CONTENT_TYPE)],
+            Json(problem),
+        )
+            .into_response();
+        if self.code == ErrorCode::ServiceUnavailable {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+        }
+        response
+    }
+}
+
+/// Middleware giving every request an id, made available to the errors it produces and sent
+/// back as `x-request-id`
+pub async fn request_id(request: Request, next: Next) -> Response<Body> {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_models::{CreatePromptRequest, GetPromptsRequest};
+    use axum::{body::to_bytes, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn problem(response: Response<Body>) -> serde_json::Value {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_problem_response() {
+        let app = Router::new()
+            .route(
+                "/limit",
+                get(|| async { Err::<(), _>(ApiError::invalid_field("limit", "too large")) }),
+            )
+            .route(
+                "/busy",
+                get(|| async { Err::<(), ApiError>(ServiceUnavailable.into()) }),
+            )
+            .layer(axum::middleware::from_fn(request_id));
+
+        let request = Request::get("/limit")
+            .header(REQUEST_ID_HEADER, "abc")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            PROBLEM_CONTENT_TYPE
+        );
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "abc");
+        let body = problem(response).await;
+        assert_eq!(body["type"], "urn:sps:error:invalid_request");
+        assert_eq!(body["code"], "invalid_request");
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["request_id"], "abc");
+        assert_eq!(body["errors"][0]["field"], "limit");
+
+        // Without a request id one is generated
+        let request = Request::get("/busy").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        let id = response.headers()[REQUEST_ID_HEADER].clone();
+        let body = problem(response).await;
+        assert_eq!(body["request_id"], id.to_str().unwrap());
+        assert!(body.get("errors").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_json_rejections() {
+        let app = Router::new().route(
+            "/prompt",
+            axum::routing::post(
+                |ApiJson(request): ApiJson<CreatePromptRequest>| async move { request.content },
+            ),
+        );
+        let post = |content_type: &str, body: &'static str| {
+            Request::post("/prompt")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(post(
+                "application/json",
+                r#"{"content": "Hi", "tags": "a"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = problem(response).await;
+        assert_eq!(body["code"], "validation_failed");
+        assert_eq!(body["errors"][0]["field"], "tags");
+
+        let response = app
+            .clone()
+            .oneshot(post("application/json", "{"))
+            .await
+            .unwrap();
+        assert_eq!(problem(response).await["code"], "invalid_request");
+
+        let response = app.oneshot(post("text/plain", "Hi")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_query_and_path_rejections() {
+        let app = Router::new()
+            .route(
+                "/prompts",
+                get(|ApiQuery(params): ApiQuery<GetPromptsRequest>| async move {
+                    params.limit.unwrap_or_default().to_string()
+                }),
+            )
+            .route(
+                "/prompt/{version}",
+                get(|ApiPath(version): ApiPath<u32>| async move { version.to_string() }),
+            );
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(get("/prompts?limit=5")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for uri in ["/prompts?limit=abc", "/prompts?sort=bogus", "/prompt/abc"] {
+            let response = app.clone().oneshot(get(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                PROBLEM_CONTENT_TYPE
+            );
+            assert_eq!(problem(response).await["code"], "invalid_request");
+        }
+    }
+    #[tokio::test]
+    async fn test_text_rejections() {
+        let app = Router::new()
+            .route(
+                "/import",
+                axum::routing::post(|ApiText(body): ApiText| async move { body }),
+            )
+            .layer(axum::extract::DefaultBodyLimit::max(8));
+        let post = |body: Vec<u8>| Request::post("/import").body(Body::from(body)).unwrap();
+
+        let response = app.clone().oneshot(post(b"ok".to_vec())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone().oneshot(post(vec![0xff, 0xfe])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(problem(response).await["code"], "invalid_request");
+
+        let response = app.oneshot(post(vec![b'a'; 16])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(problem(response).await["code"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_fallbacks() {
+        let api = Router::new()
+            .route("/prompts", get(|| async { "[]" }))
+            .method_not_allowed_fallback(method_not_allowed);
+        let app = Router::new()
+            .nest("/v1", api)
+            .fallback(not_found)
+            .layer(axum::middleware::from_fn(request_id));
+
+        let request = Request::get("/v1/nope").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            PROBLEM_CONTENT_TYPE
+        );
+        let body = problem(response).await;
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["detail"], "No route for /v1/nope");
+        assert!(body["request_id"].is_string());
+
+        let request = Request::delete("/v1/prompts").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(response.headers().contains_key(header::ALLOW));
+        let body = problem(response).await;
+        assert_eq!(body["code"], "method_not_allowed");
+        assert_eq!(body["detail"], "DELETE is not allowed on /v1/prompts");
+        assert!(body["request_id"].is_string());
+    }
+}
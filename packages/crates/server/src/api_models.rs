@@ -1,12 +1,16 @@
-use axum::{
-    body::Body,
-    http::{Response, StatusCode},
-    response::IntoResponse,
-};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use utoipa::ToSchema;
 
-use crate::cache::{now_timestamp, DbPrompt, DbPromptMetadata};
+use crate::api_error::{ErrorCode, FieldError};
+use crate::backup::Snapshot;
+use crate::cache::{
+    now_timestamp, DbCategoryCount, DbCommit, DbMetadataPatch, DbPrompt, DbPromptHead,
+    DbPromptMetadata, DbSimilarPrompt, PromptPage, PromptQuery, PromptSort, SortOrder,
+    DEFAULT_PAGE_SIZE,
+};
+use crate::read_cache::ReadCacheStats;
+use crate::transfer::{ConflictPolicy, ExportFormat, IdMode, ImportPlan};
 
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct CreatePromptRequest {
@@ -20,12 +24,67 @@ pub struct CreatePromptRequest {
     pub category: Option<String>,
     /// The tags of the prompt
     pub tags: Option<Vec<String>>,
-    /// The parent of the prompt. If its a new prompt with no lineage, this should be None.
+    /// Any version of the lineage the prompt is a new version of, it is linked to the lineage
+    /// root. None for a new prompt with no lineage.
     pub parent: Option<String>,
     /// Whether the prompt is being branched
     pub branched: Option<bool>,
 }
 
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct BatchCreatePromptsRequest {
+    /// Prompts to create, at most `validation.max_batch_items`
+    pub prompts: Vec<CreatePromptRequest>,
+}
+
+/// Outcome of one prompt of a batch create, in the order of the request
+#[derive(Serialize, ToSchema, Debug)]
+pub struct BatchCreateResult {
+    /// The created prompt, absent when the item is invalid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<Prompt>,
+    /// Why the item was not created
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct BatchCreatePromptsResponse {
+    /// Number of prompts created
+    pub created: usize,
+    pub results: Vec<BatchCreateResult>,
+}
+
+#[derive(Deserialize, ToSchema, Debug)]
+pub struct BatchGetPromptsRequest {
+    /// Ids of the prompts, at most `validation.max_batch_items`
+    pub ids: Vec<String>,
+    /// Whether to include metadata in the response
+    pub metadata: Option<bool>,
+    /// Resolve each id to the highest version of its lineage, the same version
+    /// `GET /prompt/{id}/content?latest=true` answers with
+    pub latest: Option<bool>,
+    /// Whether to return archived prompts
+    pub include_archived: Option<bool>,
+}
+
+/// Outcome of one id of a batch get, in the order of the request
+#[derive(Serialize, ToSchema, Debug)]
+pub struct BatchGetResult {
+    /// The requested id
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<Prompt>,
+    /// Why there is no prompt, `prompt_not_found` or `prompt_archived`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorCode>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct BatchGetPromptsResponse {
+    pub results: Vec<BatchGetResult>,
+}
+
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct UpdateMetadataRequest {
     /// The id of the prompt
@@ -52,6 +111,50 @@ impl From<UpdateMetadataRequest> for DbPromptMetadata {
         }
     }
 }
+
+/// Tells an explicit null, `Some(None)`, apart from a missing field, `None`
+fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// A JSON Merge Patch (RFC 7396) of a prompt's metadata. Omitted fields are left as they are and
+/// null clears a field.
+#[derive(Deserialize, ToSchema, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PatchMetadataRequest {
+    /// The name of the prompt
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable)]
+    pub name: Option<Option<String>>,
+    /// The description of the prompt
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable)]
+    pub description: Option<Option<String>>,
+    /// The category of the prompt
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable)]
+    pub category: Option<Option<String>>,
+    /// The tags of the prompt, replacing the current ones
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<Vec<String>>, nullable)]
+    pub tags: Option<Option<Vec<String>>>,
+}
+
+impl From<PatchMetadataRequest> for DbMetadataPatch {
+    fn from(request: PatchMetadataRequest) -> Self {
+        Self {
+            name: request.name,
+            description: request.description,
+            category: request.category,
+            tags: request.tags,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct Prompt {
     /// The id of the prompt
@@ -70,6 +173,9 @@ pub struct Prompt {
     pub created_at: i64,
     /// The metadata of the prompt
     pub metadata: Option<PromptMetadata>,
+    /// Number of versions in the lineage. Only set when listing with `view=heads`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_count: Option<i64>,
 }
 
 impl From<DbPrompt> for Prompt {
@@ -83,6 +189,16 @@ impl From<DbPrompt> for Prompt {
             archived: db_prompt.archived,
             created_at: db_prompt.created_at,
             metadata: db_prompt.metadata.map(PromptMetadata::from),
+            version_count: None,
+        }
+    }
+}
+
+impl From<DbPromptHead> for Prompt {
+    fn from(head: DbPromptHead) -> Self {
+        Self {
+            version_count: Some(head.version_count),
+            ..Prompt::from(head.prompt)
         }
     }
 }
@@ -113,103 +229,409 @@ impl From<DbPromptMetadata> for PromptMetadata {
 
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct GetPromptsRequest {
+    /// Whether to list every version or one entry per lineage
+    #[schema(value_type = Option<String>)]
+    pub view: Option<PromptView>,
     /// The category of the prompts to return
     pub category: Option<String>,
-    /// The pagination offset to start from (0-based)
-    pub offset: Option<i32>,
+    /// Only return prompts with this tag
+    pub tag: Option<String>,
+    /// Only return prompts created at or after this unix timestamp
+    pub created_after: Option<i64>,
+    /// Only return prompts created before this unix timestamp
+    pub created_before: Option<i64>,
+    /// Only return branched (or non-branched) prompts
+    pub branched: Option<bool>,
+    /// Only return archived (or non-archived) prompts
+    pub archived: Option<bool>,
+    /// Whether to include archived prompts. Ignored when `archived` is set.
+    pub include_archived: Option<bool>,
+    /// Only return prompts with this parent
+    pub parent: Option<String>,
+    /// The field to sort by
+    #[schema(value_type = Option<String>)]
+    pub sort: Option<PromptSort>,
+    /// The sort direction
+    #[schema(value_type = Option<String>)]
+    pub order: Option<SortOrder>,
+    /// Opaque cursor from a previous page's `next_cursor`
+    pub cursor: Option<String>,
     /// The number of prompts to return
-    pub limit: Option<i32>,
+    pub limit: Option<u32>,
+}
+
+impl From<GetPromptsRequest> for PromptQuery {
+    fn from(request: GetPromptsRequest) -> Self {
+        Self {
+            category: request.category,
+            tag: request.tag,
+            created_after: request.created_after,
+            created_before: request.created_before,
+            branched: request.branched,
+            archived: request.archived,
+            include_archived: request.include_archived.unwrap_or(false),
+            parent: request.parent,
+            sort: request.sort.unwrap_or_default(),
+            order: request.order.unwrap_or_default(),
+            cursor: request.cursor,
+            limit: request.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptView {
+    /// Every version of every lineage as a separate entry
+    #[default]
+    Versions,
+    /// One entry per lineage root holding the head version
+    Heads,
 }
 
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
-pub struct GetPromptContentRequest {
-    /// Whether to get the latest version of the prompt
-    pub latest: Option<bool>,
+pub struct PromptList {
+    /// The prompts in this page
+    pub items: Vec<Prompt>,
+    /// Cursor for the next page, absent on the last page
+    pub next_cursor: Option<String>,
+    /// Total number of prompts matching the filters
+    pub total: i64,
+}
+
+impl<T: Into<Prompt>> From<PromptPage<T>> for PromptList {
+    fn from(page: PromptPage<T>) -> Self {
+        Self {
+            items: page.items.into_iter().map(Into::into).collect(),
+            next_cursor: page.next_cursor,
+            total: page.total,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
-pub struct GetPromptRequest {
-    /// Whether to include metadata in the response
-    pub metadata: Option<bool>,
+pub struct CategoryCount {
+    /// The category, nested categories are separated by `/` ie frontend/react
+    pub name: String,
+    /// Number of prompts directly in this category
+    pub count: i64,
+    /// Number of prompts in this category or any category nested below it
+    pub total: i64,
 }
 
-// TODO: https://docs.rs/axum-derive-error/latest/axum_derive_error/
-pub enum GetPromptError {
-    NotFound,
-    InternalServerError,
+impl From<DbCategoryCount> for CategoryCount {
+    fn from(category: DbCategoryCount) -> Self {
+        Self {
+            name: category.name,
+            count: category.count,
+            total: category.total,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct RenameCategoryRequest {
+    /// The category to rename
+    pub from: String,
+    /// The new name of the category
+    pub to: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct MergeCategoriesRequest {
+    /// The categories to merge
+    pub sources: Vec<String>,
+    /// The category the sources are merged into
+    pub target: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct UpdateCategoriesResponse {
+    /// Number of prompts whose category changed
+    pub updated: usize,
 }
 
-impl IntoResponse for GetPromptError {
-    fn into_response(self) -> Response<Body> {
-        let status = match self {
-            Self::NotFound => StatusCode::NOT_FOUND,
-            Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct CacheStats {
+    /// Content lookups served from the cache
+    pub hits: u64,
+    /// Content lookups that went to the database
+    pub misses: u64,
+    /// Entries currently cached
+    pub entries: usize,
+    /// Maximum number of cached entries
+    pub capacity: usize,
+}
 
-        status.into_response()
+impl From<ReadCacheStats> for CacheStats {
+    fn from(stats: ReadCacheStats) -> Self {
+        Self {
+            hits: stats.hits,
+            misses: stats.misses,
+            entries: stats.entries,
+            capacity: stats.capacity,
+        }
     }
 }
-pub enum DeletePromptError {
-    NotFoundError,
-    InternalServerError,
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct BackupSnapshot {
+    /// File name of the snapshot in the backup directory
+    pub name: String,
+    /// Size of the snapshot in bytes
+    pub size_bytes: u64,
+    /// Unix timestamp of when the snapshot was taken
+    pub created_at: i64,
 }
 
-impl IntoResponse for DeletePromptError {
-    fn into_response(self) -> Response<Body> {
-        let status = match self {
-            Self::NotFoundError => StatusCode::NOT_FOUND,
-            Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+impl From<Snapshot> for BackupSnapshot {
+    fn from(snapshot: Snapshot) -> Self {
+        Self {
+            name: snapshot.name(),
+            size_bytes: snapshot.size_bytes,
+            created_at: snapshot.created_at / 1000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ExportedMetadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub category: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// Unix timestamp of the last metadata update
+    pub updated_at: i64,
+}
+
+/// The git commit a version was imported from
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ExportedCommit {
+    pub author_name: String,
+    pub author_email: String,
+    pub message: String,
+}
 
-        status.into_response()
+/// One version of a prompt in an export, with its metadata and lineage link
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct ExportedPrompt {
+    pub id: String,
+    pub version: i32,
+    pub content: String,
+    /// Root of the lineage, the prompt's own id for a root
+    pub parent: String,
+    pub branched: Option<bool>,
+    pub archived: Option<bool>,
+    /// Unix timestamp of when the version was created
+    pub created_at: i64,
+    pub metadata: Option<ExportedMetadata>,
+    pub commit: Option<ExportedCommit>,
+}
+
+impl From<DbPrompt> for ExportedPrompt {
+    fn from(prompt: DbPrompt) -> Self {
+        Self {
+            id: prompt.id,
+            version: prompt.version,
+            content: prompt.content,
+            parent: prompt.parent,
+            branched: prompt.branched,
+            archived: prompt.archived,
+            created_at: prompt.created_at,
+            metadata: prompt.metadata.map(|metadata| ExportedMetadata {
+                name: metadata.name,
+                description: metadata.description,
+                category: metadata.category,
+                tags: metadata.tags,
+                updated_at: metadata.updated_at,
+            }),
+            commit: prompt.commit.map(|commit| ExportedCommit {
+                author_name: commit.author_name,
+                author_email: commit.author_email,
+                message: commit.message,
+            }),
+        }
+    }
+}
+
+impl From<ExportedPrompt> for DbPrompt {
+    fn from(prompt: ExportedPrompt) -> Self {
+        Self {
+            metadata: prompt.metadata.map(|metadata| DbPromptMetadata {
+                id: prompt.id.clone(),
+                name: metadata.name,
+                description: metadata.description,
+                category: metadata.category,
+                tags: metadata.tags,
+                updated_at: metadata.updated_at,
+            }),
+            id: prompt.id,
+            version: prompt.version,
+            content: prompt.content,
+            parent: prompt.parent,
+            branched: prompt.branched,
+            archived: prompt.archived,
+            created_at: prompt.created_at,
+            commit: prompt.commit.map(|commit| DbCommit {
+                author_name: commit.author_name,
+                author_email: commit.author_email,
+                message: commit.message,
+            }),
+        }
     }
 }
 
-pub enum GetPromptsError {
-    InvalidRequest,
-    InternalServerError,
+/// The YAML form of an export
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ExportBundle {
+    /// Unix timestamp of when the export was taken
+    pub exported_at: i64,
+    pub prompts: Vec<ExportedPrompt>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ExportRequest {
+    /// ndjson (default) or yaml
+    pub format: Option<ExportFormat>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ImportRequest {
+    /// Only plan the import, nothing is written
+    pub dry_run: Option<bool>,
+    /// keep (default) the exported ids or remap them to new ones
+    pub ids: Option<IdMode>,
+    /// fail (default) the whole import on a conflict or skip conflicting versions
+    pub on_conflict: Option<ConflictPolicy>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ImportConflict {
+    /// Id of the exported version
+    pub id: String,
+    pub reason: String,
 }
 
-impl IntoResponse for GetPromptsError {
-    fn into_response(self) -> Response<Body> {
-        let status = match self {
-            Self::InvalidRequest => StatusCode::BAD_REQUEST,
-            Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ImportReport {
+    /// Whether the versions were written. False on a dry run or when conflicts stopped the
+    /// import.
+    pub applied: bool,
+    /// Versions created, or that would be created
+    pub created: usize,
+    /// Versions already present with the same content and lineage
+    pub unchanged: usize,
+    /// Versions whose id is taken by a different prompt
+    pub conflicts: Vec<ImportConflict>,
+    /// New id of every exported id, only when ids are remapped
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub id_map: BTreeMap<String, String>,
+}
 
-        status.into_response()
+impl From<ImportPlan> for ImportReport {
+    fn from(plan: ImportPlan) -> Self {
+        Self {
+            applied: plan.applied,
+            created: plan.prompts.len(),
+            unchanged: plan.unchanged,
+            conflicts: plan
+                .conflicts
+                .into_iter()
+                .map(|conflict| ImportConflict {
+                    id: conflict.id,
+                    reason: conflict.reason,
+                })
+                .collect(),
+            id_map: plan.id_map,
+        }
     }
 }
 
-// TODO: https://docs.rs/axum-derive-error/latest/axum_derive_error/
-pub enum CreatePromptError {
-    InvalidRequestBody,
-    InternalServerError,
+pub const DEFAULT_SIMILARITY_THRESHOLD: f64 = 0.8;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetSimilarPromptsRequest {
+    /// Minimum estimated similarity, 0.0 to 1.0
+    pub threshold: Option<f64>,
+    /// The number of prompts to return
+    pub limit: Option<u32>,
+    /// Whether to include other versions of the same lineage
+    pub include_lineage: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetRelatedPromptsRequest {
+    /// The number of prompts to return
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetDuplicatesRequest {
+    /// Minimum estimated similarity, 0.0 to 1.0
+    pub threshold: Option<f64>,
 }
 
-impl IntoResponse for CreatePromptError {
-    fn into_response(self) -> Response<Body> {
-        let status = match self {
-            Self::InvalidRequestBody => StatusCode::BAD_REQUEST,
-            Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct SimilarPrompt {
+    /// The id of the prompt
+    pub id: String,
+    /// The parent of the prompt
+    pub parent: String,
+    /// The name of the prompt
+    pub name: Option<String>,
+    /// Similarity to the requested prompt, 0.0 to 1.0
+    pub similarity: f64,
+}
 
-        status.into_response()
+impl From<DbSimilarPrompt> for SimilarPrompt {
+    fn from(prompt: DbSimilarPrompt) -> Self {
+        Self {
+            id: prompt.id,
+            parent: prompt.parent,
+            name: prompt.name,
+            similarity: prompt.similarity,
+        }
     }
 }
 
-pub enum UpdateMetadataError {
-    NotFound,
-    InternalServerError,
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct DuplicateGroup {
+    /// Lineage heads that are near-duplicates of each other. Each similarity is the highest
+    /// similarity to another member of the group.
+    pub prompts: Vec<SimilarPrompt>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetPromptContentRequest {
+    /// Whether to get the highest non-archived version in the lineage of the prompt
+    pub latest: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetPromptRequest {
+    /// Whether to include metadata in the response
+    pub metadata: Option<bool>,
+    /// Whether to return the prompt even if it is archived
+    pub include_archived: Option<bool>,
 }
 
-impl IntoResponse for UpdateMetadataError {
-    fn into_response(self) -> Response<Body> {
-        let status = match self {
-            Self::NotFound => StatusCode::NOT_FOUND,
-            Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetTrashRequest {
+    /// The category of the archived prompts to return
+    pub category: Option<String>,
+    /// Opaque cursor from a previous page's `next_cursor`
+    pub cursor: Option<String>,
+    /// The number of prompts to return
+    pub limit: Option<u32>,
+}
 
-        status.into_response()
+impl From<GetTrashRequest> for PromptQuery {
+    fn from(request: GetTrashRequest) -> Self {
+        Self {
+            category: request.category,
+            cursor: request.cursor,
+            limit: request.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+            ..Default::default()
+        }
     }
 }
@@ -0,0 +1,201 @@
+/*
+API versions. The API is served under `/v1`. The same routes are still mounted at the root for
+clients written before versioning, ie the generated SDKs. Responses from the root answer with
+`Deprecation` (RFC 9745) and `Sunset` (RFC 8594) headers, plus a `successor-version` link to the
+`/v1` path that replaces them.
+
+Each version gets its own OpenAPI document, built from `ApiDoc` with the paths moved under the
+version prefix. The root routes get one too, with every operation marked deprecated.
+*/
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use utoipa::openapi::{path::Operation, Deprecated, OpenApi, PathItem};
+
+use crate::config::LegacyApiConfig;
+
+pub const V1_PREFIX: &str = "/v1";
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// Path of a prompt in the current version
+pub fn prompt_location(id: &str) -> String {
+    format!("{}/prompt/{}", V1_PREFIX, id)
+}
+
+/// Headers sent with every response from the unversioned routes
+#[derive(Debug, Clone)]
+pub struct Deprecation {
+    deprecation: HeaderValue,
+    sunset: HeaderValue,
+}
+
+impl From<&LegacyApiConfig> for Deprecation {
+    fn from(config: &LegacyApiConfig) -> Self {
+        let sunset =
+            httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(config.sunset_at as u64));
+        Self {
+            deprecation: HeaderValue::from_str(&format!("@{}", config.deprecated_at))
+                .expect("Invalid Deprecation header"),
+            sunset: HeaderValue::from_str(&sunset).expect("Invalid Sunset header"),
+        }
+    }
+}
+
+/// Middleware for the unversioned routes
+pub async fn deprecated(
+    State(deprecation): State<Deprecation>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let successor = format!(
+        "<{}{}>; rel=\"successor-version\"",
+        V1_PREFIX,
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(DEPRECATION, deprecation.deprecation);
+    headers.insert(SUNSET, deprecation.sunset);
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.append(header::LINK, link);
+    }
+    response
+}
+
+/// Headers browsers may read from cross-origin responses
+pub fn exposed_headers() -> [HeaderName; 3] {
+    [DEPRECATION, SUNSET, header::LINK]
+}
+
+fn operations(item: &mut PathItem) -> impl Iterator<Item = &mut Operation> {
+    [
+        &mut item.get,
+        &mut item.put,
+        &mut item.post,
+        &mut item.delete,
+        &mut item.options,
+        &mut item.head,
+        &mut item.patch,
+        &mut item.trace,
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// The document of `/v1`
+pub fn openapi_v1(mut doc: OpenApi) -> OpenApi {
+    doc.paths.paths = std::mem::take(&mut doc.paths.paths)
+        .into_iter()
+        .map(|(path, item)| (format!("{}{}", V1_PREFIX, path), item))
+        .collect();
+    doc.info.version = "1".to_string();
+    doc
+}
+
+/// The document of the unversioned routes, every operation deprecated in favour of `/v1`
+pub fn openapi_legacy(mut doc: OpenApi) -> OpenApi {
+    for item in doc.paths.paths.values_mut() {
+        for operation in operations(item) {
+            operation.deprecated = Some(Deprecated::True);
+        }
+    }
+    let notice = format!(
+        "Unversioned aliases of the {} API, kept for older clients until their sunset.",
+        V1_PREFIX
+    );
+    doc.info.description = Some(match doc.info.description.take() {
+        Some(description) => format!("{}\n\n{}", notice, description),
+        None => notice,
+    });
+    doc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::ServiceExt;
+    use utoipa::openapi::{path::HttpMethod, OpenApiBuilder, PathsBuilder};
+
+    #[tokio::test]
+    async fn test_deprecated() {
+        let config = LegacyApiConfig {
+            enabled: true,
+            deprecated_at: 1_700_000_000,
+            sunset_at: 1_800_000_000,
+        };
+        let app = Router::new()
+            .route("/prompts", get(|| async { "[]" }))
+            .layer(middleware::from_fn_with_state(
+                Deprecation::from(&config),
+                deprecated,
+            ));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/prompts?limit=5")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[DEPRECATION], "@1700000000");
+        assert_eq!(headers[SUNSET], "Fri, 15 Jan 2027 08:00:00 GMT");
+        assert_eq!(
+            headers[header::LINK],
+            "</v1/prompts>; rel=\"successor-version\""
+        );
+    }
+
+    #[test]
+    fn test_openapi() {
+        let doc = OpenApiBuilder::new()
+            .paths(
+                PathsBuilder::new()
+                    .path("/prompts", PathItem::new(HttpMethod::Get, Operation::new()))
+                    .path("/prompt", PathItem::new(HttpMethod::Post, Operation::new())),
+            )
+            .build();
+
+        let v1 = openapi_v1(doc.clone());
+        assert_eq!(
+            v1.paths.paths.keys().collect::<Vec<_>>(),
+            vec!["/v1/prompt", "/v1/prompts"]
+        );
+        assert!(v1.paths.paths["/v1/prompt"]
+            .post
+            .as_ref()
+            .unwrap()
+            .deprecated
+            .is_none());
+
+        let legacy = openapi_legacy(doc);
+        assert!(matches!(
+            legacy.paths.paths["/prompt"]
+                .post
+                .as_ref()
+                .unwrap()
+                .deprecated,
+            Some(Deprecated::True)
+        ));
+        assert!(matches!(
+            legacy.paths.paths["/prompts"]
+                .get
+                .as_ref()
+                .unwrap()
+                .deprecated,
+            Some(Deprecated::True)
+        ));
+    }
+}
@@ -0,0 +1,334 @@
+/*
+Hot snapshots of the prompt database with SQLite's online backup API.
+https://www.sqlite.org/backup.html
+
+Snapshots are taken from a separate read-only connection while the server keeps serving traffic.
+The whole database is copied in one backup step: in WAL mode that holds a read transaction, which
+doesn't block writers, and a single step can't be restarted by writes landing halfway through.
+
+Each snapshot is named after the millisecond it was taken, so names sort by age. Retention keeps
+the newest snapshot of each of the last N hours and of each of the last M days, the newest
+snapshot is always kept.
+*/
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::cache::{CacheError, CacheResult};
+use crate::migrations;
+
+const SNAPSHOT_PREFIX: &str = "prompts-";
+const SNAPSHOT_EXTENSION: &str = "db";
+const BACKUP_RETRY_PAUSE: Duration = Duration::from_millis(250);
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+const DAY_MILLIS: i64 = 24 * HOUR_MILLIS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    /// Unix timestamp in milliseconds
+    pub created_at: i64,
+    pub size_bytes: u64,
+}
+
+impl Snapshot {
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("System time before UNIX EPOCH")
+        .as_millis() as i64
+}
+
+/// Copies the main database of `from` into `to` with the online backup API
+fn copy_database(from: &Connection, to: &mut Connection) -> CacheResult<()> {
+    Backup::new(from, to)?.run_to_completion(i32::MAX, BACKUP_RETRY_PAUSE, None)?;
+    Ok(())
+}
+
+/// Fails unless `path` is an intact database with a schema this server understands
+pub fn verify(path: &Path) -> CacheResult<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let problems = conn
+        .prepare("PRAGMA integrity_check")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    if problems != ["ok"] {
+        return Err(CacheError::InvalidRequest(format!(
+            "{} failed integrity check: {}",
+            path.display(),
+            problems.join("; ")
+        )));
+    }
+    migrations::plan(&conn)?;
+    Ok(())
+}
+
+/// Replaces the contents of the database at `db_path` with a verified snapshot. Meant to be run
+/// while the server is stopped, pending migrations are applied on the next start.
+pub fn restore(snapshot: &Path, db_path: &str) -> CacheResult<()> {
+    verify(snapshot)?;
+    let source = Connection::open_with_flags(snapshot, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut target = Connection::open(db_path)?;
+    copy_database(&source, &mut target)?;
+    info!("Restored {} from {}", db_path, snapshot.display());
+    Ok(())
+}
+
+/// Snapshots to keep: the newest of each of the last `keep_hourly` hours and `keep_daily` days
+/// that have one
+fn retained(snapshots: &[Snapshot], retention: Retention) -> HashSet<PathBuf> {
+    let mut sorted: Vec<&Snapshot> = snapshots.iter().collect();
+    sorted.sort_by_key(|snapshot| Reverse(snapshot.created_at));
+
+    let mut keep: HashSet<PathBuf> = sorted
+        .first()
+        .map(|newest| newest.path.clone())
+        .into_iter()
+        .collect();
+    for (period, limit) in [
+        (HOUR_MILLIS, retention.keep_hourly),
+        (DAY_MILLIS, retention.keep_daily),
+    ] {
+        let mut periods = HashSet::new();
+        for snapshot in &sorted {
+            if periods.len() == limit {
+                break;
+            }
+            if periods.insert(snapshot.created_at.div_euclid(period)) {
+                keep.insert(snapshot.path.clone());
+            }
+        }
+    }
+    keep
+}
+
+pub struct Backups {
+    db_path: String,
+    dir: PathBuf,
+    retention: Retention,
+}
+
+impl Backups {
+    pub fn new(db_path: &str, dir: impl Into<PathBuf>, retention: Retention) -> Self {
+        Self {
+            db_path: db_path.to_string(),
+            dir: dir.into(),
+            retention,
+        }
+    }
+
+    /// Takes a snapshot of the live database, then applies retention
+    pub fn snapshot(&self) -> CacheResult<Snapshot> {
+        std::fs::create_dir_all(&self.dir)?;
+        let created_at = now_millis();
+        let path = self.dir.join(format!(
+            "{SNAPSHOT_PREFIX}{created_at}.{SNAPSHOT_EXTENSION}"
+        ));
+        if path.exists() {
+            return Err(CacheError::InvalidRequest(format!(
+                "Snapshot {} already exists",
+                path.display()
+            )));
+        }
+
+        let source = Connection::open_with_flags(&self.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut target = Connection::open(&path)?;
+        if let Err(e) = copy_database(&source, &mut target) {
+            drop(target);
+            let _ = std::fs::remove_file(&path);
+            return Err(e);
+        }
+        drop(target);
+
+        let snapshot = Snapshot {
+            size_bytes: std::fs::metadata(&path)?.len(),
+            path,
+            created_at,
+        };
+        info!(
+            "Wrote snapshot {} ({} bytes)",
+            snapshot.path.display(),
+            snapshot.size_bytes
+        );
+        self.prune()?;
+        Ok(snapshot)
+    }
+
+    /// Snapshots in the backup directory, newest first
+    pub fn list(&self) -> CacheResult<Vec<Snapshot>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut snapshots = std::fs::read_dir(&self.dir)?
+            .map(|entry| {
+                let entry = entry?;
+                let path = entry.path();
+                let created_at = path
+                    .extension()
+                    .filter(|extension| *extension == SNAPSHOT_EXTENSION)
+                    .and(path.file_stem())
+                    .and_then(|stem| stem.to_str()?.strip_prefix(SNAPSHOT_PREFIX)?.parse().ok());
+                created_at
+                    .map(|created_at| {
+                        Ok(Snapshot {
+                            size_bytes: entry.metadata()?.len(),
+                            path,
+                            created_at,
+                        })
+                    })
+                    .transpose()
+            })
+            .filter_map(|snapshot: std::io::Result<Option<Snapshot>>| snapshot.transpose())
+            .collect::<std::io::Result<Vec<Snapshot>>>()?;
+        snapshots.sort_by_key(|snapshot| Reverse(snapshot.created_at));
+        Ok(snapshots)
+    }
+
+    /// Deletes snapshots outside the retention policy, returns how many were deleted
+    pub fn prune(&self) -> CacheResult<usize> {
+        let snapshots = self.list()?;
+        let keep = retained(&snapshots, self.retention);
+        let expired: Vec<&Snapshot> = snapshots
+            .iter()
+            .filter(|snapshot| !keep.contains(&snapshot.path))
+            .collect();
+        for snapshot in &expired {
+            if let Err(e) = std::fs::remove_file(&snapshot.path) {
+                warn!(
+                    "Failed to remove snapshot {}: {}",
+                    snapshot.path.display(),
+                    e
+                );
+            }
+        }
+        if !expired.is_empty() {
+            info!("Pruned {} expired snapshots", expired.len());
+        }
+        Ok(expired.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheConfig, ConnectionOptions, DbPrompt};
+    use crate::store::PromptStore;
+
+    fn snapshot(created_at: i64) -> Snapshot {
+        Snapshot {
+            path: PathBuf::from(format!("{SNAPSHOT_PREFIX}{created_at}.db")),
+            created_at,
+            size_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn test_retention() {
+        // Two snapshots per hour over two days
+        let snapshots: Vec<Snapshot> = (0..96)
+            .map(|i| snapshot(10 * DAY_MILLIS + i * HOUR_MILLIS / 2))
+            .collect();
+        let newest = &snapshots[95];
+
+        let keep = retained(
+            &snapshots,
+            Retention {
+                keep_hourly: 3,
+                keep_daily: 2,
+            },
+        );
+        // Newest of each of the last 3 hours and of each of the 2 days, the newest overlaps
+        let mut kept: Vec<i64> = snapshots
+            .iter()
+            .filter(|s| keep.contains(&s.path))
+            .map(|s| s.created_at)
+            .collect();
+        kept.sort();
+        assert_eq!(
+            kept,
+            vec![
+                snapshots[47].created_at,
+                snapshots[91].created_at,
+                snapshots[93].created_at,
+                newest.created_at
+            ]
+        );
+
+        let keep = retained(
+            &snapshots,
+            Retention {
+                keep_hourly: 0,
+                keep_daily: 0,
+            },
+        );
+        assert_eq!(keep, HashSet::from([newest.path.clone()]));
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let db = CacheConfig::new(db_path, ConnectionOptions::default()).unwrap();
+        db.insert_prompt(DbPrompt {
+            id: "kept".to_string(),
+            version: 1,
+            content: "Content".to_string(),
+            parent: "kept".to_string(),
+            branched: None,
+            archived: Some(false),
+            created_at: 1_000,
+            metadata: None,
+            commit: None,
+        })
+        .unwrap();
+
+        let backups = Backups::new(
+            db_path,
+            temp_dir.path().join("backups"),
+            Retention {
+                keep_hourly: 24,
+                keep_daily: 7,
+            },
+        );
+        let snapshot = backups.snapshot().unwrap();
+        assert_eq!(backups.list().unwrap(), vec![snapshot.clone()]);
+        verify(&snapshot.path).unwrap();
+
+        // Writes after the snapshot are undone by restoring it
+        db.delete_prompt("kept").unwrap();
+        drop(db);
+        restore(&snapshot.path, db_path).unwrap();
+        let db = CacheConfig::new(db_path, ConnectionOptions::default()).unwrap();
+        assert_eq!(db.get_prompt_content("kept").unwrap(), "Content");
+
+        // Anything that isn't an intact database is refused
+        let corrupt = temp_dir.path().join("corrupt.db");
+        std::fs::write(&corrupt, b"not a database").unwrap();
+        assert!(verify(&corrupt).is_err());
+        assert!(restore(&corrupt, db_path).is_err());
+    }
+}
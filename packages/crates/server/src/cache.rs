@@ -1,12 +1,22 @@
 use crate::api_models::CreatePromptRequest;
+use crate::fingerprint::{self, MinHash};
+use crate::migrations;
+use crate::store::{latest_version, PromptStore};
+use crate::tfidf;
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 #[cfg(not(test))]
 use log::{debug, error, info};
+use parking_lot::Mutex;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, Result, Statement};
+use rusqlite::{
+    params, params_from_iter, types::Value, Connection, OptionalExtension, Params, Result, Row,
+    Transaction, TransactionBehavior,
+};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 #[cfg(test)]
 use std::{println as info, println as error, println as debug};
 use uuid::Uuid;
@@ -22,11 +32,23 @@ pub enum CacheError {
     #[error("invalid request:`{0}`")]
     InvalidRequest(String),
 
-    #[error("error with pool")]
-    PoolError(#[from] r2d2::Error),
+    #[error("record is archived")]
+    Archived,
+
+    #[error("parent prompt {0} does not exist")]
+    UnknownParent(String),
+
+    #[error("prompt {0} is the root of a lineage that still has other versions")]
+    LineageNotEmpty(String),
+
+    #[error("database schema version {found} is newer than the supported version {supported}")]
+    UnsupportedSchema { found: i64, supported: i64 },
+
+    #[error("error with pool: {0}")]
+    PoolError(String),
 }
 
-type CacheResult<T> = Result<T, CacheError>;
+pub type CacheResult<T> = Result<T, CacheError>;
 
 impl From<rusqlite::Error> for CacheError {
     fn from(err: rusqlite::Error) -> Self {
@@ -39,6 +61,18 @@ impl From<rusqlite::Error> for CacheError {
     }
 }
 
+impl From<r2d2::Error> for CacheError {
+    fn from(err: r2d2::Error) -> Self {
+        CacheError::PoolError(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for CacheError {
+    fn from(err: std::io::Error) -> Self {
+        CacheError::UnhandledError(err.to_string())
+    }
+}
+
 pub fn now_timestamp() -> i64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -57,6 +91,16 @@ pub struct DbPrompt {
     pub archived: Option<bool>,
     pub created_at: i64,
     pub metadata: Option<DbPromptMetadata>,
+    /// The git commit the version was imported from
+    pub commit: Option<DbCommit>,
+}
+
+/// Author and message of a git commit, kept so mirroring commits the version the same way
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DbCommit {
+    pub author_name: String,
+    pub author_email: String,
+    pub message: String,
 }
 
 impl From<CreatePromptRequest> for DbPrompt {
@@ -83,6 +127,7 @@ impl From<CreatePromptRequest> for DbPrompt {
 
         Self {
             id: id.clone(),
+            // The store numbers it after the newest version of the lineage
             version: 1,
             content: prompt.content,
             parent: prompt.parent.unwrap_or(id.clone()),
@@ -90,6 +135,7 @@ impl From<CreatePromptRequest> for DbPrompt {
             archived: Some(false),
             created_at: now,
             metadata,
+            commit: None,
         }
     }
 }
@@ -111,20 +157,513 @@ impl DbPromptMetadata {
     }
 }
 
+/// A JSON Merge Patch of a prompt's metadata. `None` leaves a field as is, `Some(None)` clears it.
+#[derive(Debug, Clone, Default)]
+pub struct DbMetadataPatch {
+    pub name: Option<Option<String>>,
+    pub description: Option<Option<String>>,
+    pub category: Option<Option<String>>,
+    pub tags: Option<Option<Vec<String>>>,
+}
+
+impl DbMetadataPatch {
+    /// Applies the patch to `metadata`, or to empty metadata for `id` when there is none
+    pub fn apply(&self, id: &str, metadata: Option<DbPromptMetadata>) -> DbPromptMetadata {
+        let mut metadata = metadata.unwrap_or_else(|| DbPromptMetadata {
+            id: id.to_string(),
+            name: None,
+            description: None,
+            category: None,
+            tags: None,
+            updated_at: 0,
+        });
+        if let Some(name) = &self.name {
+            metadata.name = name.clone();
+        }
+        if let Some(description) = &self.description {
+            metadata.description = description.clone();
+        }
+        if let Some(category) = &self.category {
+            metadata.category = category.clone();
+        }
+        if let Some(tags) = &self.tags {
+            // An empty list would be stored as "" and read back as one blank tag
+            metadata.tags = tags.clone().filter(|tags| !tags.is_empty());
+        }
+        metadata.updated_at = now_timestamp();
+        metadata
+    }
+}
+
+/// Columns selected for a prompt joined with its metadata, in the order expected by
+/// `prompt_from_row`
+const PROMPT_COLUMNS: &str = "p.id, p.version, p.content, p.parent, p.branched, p.archived, \
+     p.created_at, m.id, m.name, m.description, m.category, m.tags, m.updated_at, \
+     p.author_name, p.author_email, p.commit_message";
+
+/// Number of `PROMPT_COLUMNS`, extra columns selected after them start at this index
+const PROMPT_COLUMN_COUNT: usize = 16;
+
+/// Maps the author name, email and message columns starting at `first`, present together or
+/// not at all
+fn commit_from_row(row: &Row, first: usize) -> rusqlite::Result<Option<DbCommit>> {
+    Ok(
+        match (row.get(first)?, row.get(first + 1)?, row.get(first + 2)?) {
+            (Some(author_name), Some(author_email), Some(message)) => Some(DbCommit {
+                author_name,
+                author_email,
+                message,
+            }),
+            _ => None,
+        },
+    )
+}
+
+/// Maps a row selected with `PROMPT_COLUMNS`. Metadata is only present when the join matched.
+fn prompt_from_row(row: &Row) -> rusqlite::Result<DbPrompt> {
+    let metadata = match row.get::<_, Option<String>>(7)? {
+        Some(metadata_id) => Some(DbPromptMetadata {
+            id: metadata_id,
+            name: row.get(8)?,
+            description: row.get(9)?,
+            category: row.get(10)?,
+            tags: row
+                .get::<_, Option<String>>(11)?
+                .map(|tags| tags.split(',').map(|s| s.to_string()).collect()),
+            updated_at: row.get(12)?,
+        }),
+        None => None,
+    };
+
+    Ok(DbPrompt {
+        id: row.get(0)?,
+        version: row.get(1)?,
+        content: row.get(2)?,
+        parent: row.get(3)?,
+        branched: row.get(4)?,
+        archived: row.get(5)?,
+        created_at: row.get(6)?,
+        metadata,
+        commit: commit_from_row(row, 13)?,
+    })
+}
+
+pub const DEFAULT_PAGE_SIZE: u32 = 10;
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptSort {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    Name,
+}
+
+impl PromptSort {
+    fn sql_expr(self) -> &'static str {
+        match self {
+            Self::CreatedAt => "p.created_at",
+            Self::UpdatedAt => "COALESCE(m.updated_at, p.created_at)",
+            Self::Name => "COALESCE(m.name, '')",
+        }
+    }
+
+    /// The value `sql_expr` evaluates to, for stores that sort in memory
+    pub fn sort_key(self, prompt: &DbPrompt) -> Value {
+        let metadata = prompt.metadata.as_ref();
+        match self {
+            Self::CreatedAt => Value::Integer(prompt.created_at),
+            Self::UpdatedAt => Value::Integer(metadata.map_or(prompt.created_at, |m| m.updated_at)),
+            Self::Name => Value::Text(metadata.and_then(|m| m.name.clone()).unwrap_or_default()),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::CreatedAt => "created_at",
+            Self::UpdatedAt => "updated_at",
+            Self::Name => "name",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    fn sql_keyword(self) -> &'static str {
+        match self {
+            Self::Asc => "ASC",
+            Self::Desc => "DESC",
+        }
+    }
+
+    /// Orders sort keys with the id as tie breaker, matching the SQL keyset ordering
+    pub fn compare(self, a: (&Value, &str), b: (&Value, &str)) -> std::cmp::Ordering {
+        let key_order = match (a.0, b.0) {
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            _ => std::cmp::Ordering::Equal,
+        };
+        let ordering = key_order.then_with(|| a.1.cmp(b.1));
+        match self {
+            Self::Asc => ordering,
+            Self::Desc => ordering.reverse(),
+        }
+    }
+}
+
+/// Filters, ordering and keyset position for listing prompts
+#[derive(Debug, Clone)]
+pub struct PromptQuery {
+    pub category: Option<String>,
+    pub tag: Option<String>,
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+    pub branched: Option<bool>,
+    pub archived: Option<bool>,
+    /// Archived prompts are hidden unless this is set or `archived` is given explicitly
+    pub include_archived: bool,
+    pub parent: Option<String>,
+    pub sort: PromptSort,
+    pub order: SortOrder,
+    pub cursor: Option<String>,
+    pub limit: u32,
+}
+
+impl Default for PromptQuery {
+    fn default() -> Self {
+        Self {
+            category: None,
+            tag: None,
+            created_after: None,
+            created_before: None,
+            branched: None,
+            archived: None,
+            include_archived: false,
+            parent: None,
+            sort: PromptSort::default(),
+            order: SortOrder::default(),
+            cursor: None,
+            limit: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+impl PromptQuery {
+    /// Builds the WHERE clause shared by the page and total count queries
+    fn filter_clause(&self) -> (String, Vec<Value>) {
+        let conditions: Vec<(&str, Vec<Value>)> = [
+            // A category also matches its nested categories, ie frontend matches frontend/react
+            self.category.clone().map(|category| {
+                (
+                    "(m.category = ? OR substr(m.category, 1, length(?) + 1) = ? || '/')",
+                    vec![Value::Text(category); 3],
+                )
+            }),
+            self.tag.clone().map(|tag| {
+                (
+                    "instr(',' || m.tags || ',', ',' || ? || ',') > 0",
+                    vec![Value::Text(tag)],
+                )
+            }),
+            self.created_after
+                .map(|after| ("p.created_at >= ?", vec![Value::Integer(after)])),
+            self.created_before
+                .map(|before| ("p.created_at < ?", vec![Value::Integer(before)])),
+            self.branched.map(|branched| {
+                (
+                    "COALESCE(p.branched, false) = ?",
+                    vec![Value::Integer(branched.into())],
+                )
+            }),
+            self.archived
+                .or((!self.include_archived).then_some(false))
+                .map(|archived| {
+                    (
+                        "COALESCE(p.archived, false) = ?",
+                        vec![Value::Integer(archived.into())],
+                    )
+                }),
+            self.parent
+                .clone()
+                .map(|parent| ("p.parent = ?", vec![Value::Text(parent)])),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        if conditions.is_empty() {
+            return ("1 = 1".to_string(), Vec::new());
+        }
+
+        let (clauses, params): (Vec<&str>, Vec<Vec<Value>>) = conditions.into_iter().unzip();
+        (clauses.join(" AND "), params.concat())
+    }
+
+    pub fn check_limit(&self) -> CacheResult<()> {
+        if self.limit == 0 || self.limit > MAX_PAGE_SIZE {
+            error!("Invalid request: limit={}", self.limit);
+            return Err(CacheError::InvalidRequest(
+                "Invalid limit value".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Evaluates the same filters as `filter_clause`, for stores that filter in memory
+    pub fn matches(&self, prompt: &DbPrompt) -> bool {
+        let metadata = prompt.metadata.as_ref();
+        let category = metadata.and_then(|m| m.category.as_deref());
+        let tags = metadata.and_then(|m| m.tags.as_deref()).unwrap_or_default();
+
+        self.category.as_ref().is_none_or(|wanted| {
+            category.is_some_and(|category| {
+                category == wanted
+                    || category
+                        .strip_prefix(wanted.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+        }) && self.tag.as_ref().is_none_or(|tag| tags.contains(tag))
+            && self
+                .created_after
+                .is_none_or(|after| prompt.created_at >= after)
+            && self
+                .created_before
+                .is_none_or(|before| prompt.created_at < before)
+            && self
+                .branched
+                .is_none_or(|branched| prompt.branched.unwrap_or(false) == branched)
+            && self
+                .archived
+                .or((!self.include_archived).then_some(false))
+                .is_none_or(|archived| prompt.archived.unwrap_or(false) == archived)
+            && self
+                .parent
+                .as_ref()
+                .is_none_or(|parent| &prompt.parent == parent)
+    }
+}
+
+/// Head (highest version) of a lineage along with how many versions the lineage has
+#[derive(Debug, Clone)]
+pub struct DbPromptHead {
+    pub prompt: DbPrompt,
+    pub version_count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbCategoryCount {
+    pub name: String,
+    /// Prompts directly in this category
+    pub count: i64,
+    /// Prompts in this category or any category nested below it
+    pub total: i64,
+}
+
+/// Deduplicated merge sources, longest first, so the most specific source wins for nested
+/// categories
+pub fn merge_order(sources: &[String]) -> Vec<&str> {
+    let mut sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+    sources.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    sources.dedup();
+    sources
+}
+
+/// Rolls per-category prompt counts up into every ancestor of nested categories, sorted by name
+pub fn category_counts(direct: impl IntoIterator<Item = (String, i64)>) -> Vec<DbCategoryCount> {
+    let mut counts: BTreeMap<String, DbCategoryCount> = BTreeMap::new();
+    for (category, count) in direct {
+        let ancestors = category
+            .match_indices('/')
+            .map(|(i, _)| category[..i].to_string())
+            .chain(std::iter::once(category.clone()));
+        for name in ancestors {
+            let entry = counts
+                .entry(name.clone())
+                .or_insert_with(|| DbCategoryCount {
+                    name,
+                    count: 0,
+                    total: 0,
+                });
+            entry.total += count;
+        }
+        counts.get_mut(&category).unwrap().count += count;
+    }
+    counts.into_values().collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct DbSimilarPrompt {
+    pub id: String,
+    pub parent: String,
+    pub name: Option<String>,
+    pub similarity: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct PromptPage<T = DbPrompt> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub total: i64,
+}
+
+/// Position of the last row of a page. The encoded form also carries the sort and order it was
+/// issued for so a cursor can't be replayed against a different ordering.
+pub struct PromptCursor {
+    pub sort_key: Value,
+    pub id: String,
+}
+
+impl PromptCursor {
+    pub fn encode(&self, sort: PromptSort, order: SortOrder) -> String {
+        let (kind, key) = match &self.sort_key {
+            Value::Integer(key) => ("i", key.to_string()),
+            Value::Text(key) => ("t", key.clone()),
+            _ => ("t", String::new()),
+        };
+        [
+            sort.as_str(),
+            order.sql_keyword(),
+            kind,
+            &URL_SAFE_NO_PAD.encode(&self.id),
+            &URL_SAFE_NO_PAD.encode(key),
+        ]
+        .join(".")
+    }
+
+    pub fn decode(cursor: &str, sort: PromptSort, order: SortOrder) -> CacheResult<Self> {
+        let invalid = || CacheError::InvalidRequest("Invalid cursor".to_string());
+        let decode_part = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(invalid)
+        };
+
+        match cursor.split('.').collect::<Vec<_>>().as_slice() {
+            [cursor_sort, cursor_order, kind, id, key]
+                if *cursor_sort == sort.as_str() && *cursor_order == order.sql_keyword() =>
+            {
+                let key = decode_part(key)?;
+                let sort_key = match *kind {
+                    "i" => Value::Integer(key.parse().map_err(|_| invalid())?),
+                    "t" => Value::Text(key),
+                    _ => return Err(invalid()),
+                };
+                Ok(Self {
+                    sort_key,
+                    id: decode_part(id)?,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// https://www.sqlite.org/pragma.html#pragma_synchronous
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
+}
+
+impl std::str::FromStr for Synchronous {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "normal" => Ok(Self::Normal),
+            "full" => Ok(Self::Full),
+            "extra" => Ok(Self::Extra),
+            _ => Err(format!("invalid synchronous mode: {}", value)),
+        }
+    }
+}
+
 /*
 https://github.com/brettwooldridge/HikariCP/wiki/About-Pool-Sizing
 connections = ((core_count * 2) + effective_spindle_count)
 */
-const MAX_CONNECTIONS: u32 = 4;
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 4;
+/// How long a request waits for a pooled connection, or for its turn to write, before giving up
+/// with a 503
+const POOL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pool size and the pragmas applied to every pooled connection as it's opened
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub max_connections: u32,
+    pub busy_timeout: Duration,
+    pub foreign_keys: bool,
+    pub synchronous: Synchronous,
+    /// Pages if positive, KiB if negative
+    pub cache_size: i64,
+    /// Bytes of the database file to memory map, 0 disables it
+    pub mmap_size: i64,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            busy_timeout: Duration::from_secs(30),
+            foreign_keys: true,
+            // Durable in WAL mode except for the last commits before a power loss
+            synchronous: Synchronous::Normal,
+            cache_size: -2000,
+            mmap_size: 0,
+        }
+    }
+}
+
+impl r2d2::CustomizeConnection<Connection, rusqlite::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        conn.busy_timeout(self.busy_timeout)?;
+        conn.pragma_update(None, "foreign_keys", self.foreign_keys)?;
+        conn.pragma_update(None, "synchronous", self.synchronous.as_str())?;
+        conn.pragma_update(None, "cache_size", self.cache_size)?;
+        conn.pragma_update(None, "mmap_size", self.mmap_size)?;
+        Ok(())
+    }
+}
+
 pub struct CacheConfig {
     pool: Pool<SqliteConnectionManager>,
+    /// SQLite allows one writer at a time. Writers queue here instead of holding a pooled
+    /// connection while they wait on the database lock, so readers keep getting connections.
+    writer: Mutex<()>,
 }
 
 impl CacheConfig {
-    fn configure_pool(db_path: &str) -> Pool<SqliteConnectionManager> {
+    fn configure_pool(db_path: &str, options: ConnectionOptions) -> Pool<SqliteConnectionManager> {
         let manager = SqliteConnectionManager::file(db_path);
         Pool::builder()
-            .max_size(MAX_CONNECTIONS)
+            .max_size(options.max_connections)
+            .connection_timeout(POOL_TIMEOUT)
+            .connection_customizer(Box::new(options))
             .build(manager)
             .unwrap_or_else(|e| {
                 error!("Failed to configure connection pool: {}", e);
@@ -132,51 +671,65 @@ impl CacheConfig {
             })
     }
 
-    pub fn new(db_path: &str) -> Result<Self, CacheError> {
-        info!("Opening database: {}", db_path);
-        let pool = CacheConfig::configure_pool(db_path);
+    pub fn new(db_path: &str, options: ConnectionOptions) -> Result<Self, CacheError> {
+        info!("Opening database: {} with {:?}", db_path, options);
+        let pool = CacheConfig::configure_pool(db_path, options);
 
-        // Enable WAL mode for better concurrency and performance
+        // Enable WAL mode for better concurrency and performance. It's stored in the database
+        // file, so unlike the per-connection pragmas it only needs setting once.
         pool.get()?.pragma_update(None, "journal_mode", "WAL")?;
-        // Set busy timeout to handle concurrent access
-        pool.get()?
-            .busy_timeout(std::time::Duration::from_secs(30))?;
-
-        // Create tables if they don't exist
-        pool.get()?.execute(
-            "CREATE TABLE IF NOT EXISTS prompts (
-                id TEXT PRIMARY KEY,
-                version INTEGER NOT NULL,
-                content TEXT NOT NULL,
-                parent TEXT,
-                branched BOOLEAN,
-                archived BOOLEAN,
-                created_at INTEGER NOT NULL
-            )",
-            [],
-        )?;
 
-        // Metadata table
-        pool.get()?.execute(
-            "CREATE TABLE IF NOT EXISTS metadata (
-                id TEXT PRIMARY KEY,
-                name TEXT,
-                description TEXT,
-                category TEXT,
-                tags TEXT,
-                updated_at INTEGER NOT NULL
-            )",
-            [],
-        )?;
+        migrations::migrate(&mut *pool.get()?)?;
+
+        // Derived data for prompts stored before fingerprinting and the related prompt index
+        let cache = Self {
+            pool,
+            writer: Mutex::new(()),
+        };
+        cache.backfill_fingerprints()?;
+        cache.backfill_index()?;
 
-        Ok(Self { pool })
+        Ok(cache)
     }
 
-    pub fn insert_prompt(&self, prompt: DbPrompt) -> CacheResult<DbPrompt> {
-        info!("Inserting: {}", prompt.id);
-        self.pool.get()?.execute(
-            "INSERT INTO prompts (id, version, content, parent, branched, archived, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    /// Runs `f` in a single transaction, committing only if it succeeds. Writes spanning more
+    /// than one statement go through here so a failure leaves no partial rows behind.
+    fn transaction<T>(&self, f: impl FnOnce(&Transaction) -> CacheResult<T>) -> CacheResult<T> {
+        let _writer = self.writer.try_lock_for(POOL_TIMEOUT).ok_or_else(|| {
+            CacheError::PoolError(format!("timed out after {POOL_TIMEOUT:?} waiting to write"))
+        })?;
+        let mut pool_conn = self.pool.get()?;
+        // Take the write lock upfront, a deferred transaction upgrading to a writer can fail
+        // with SQLITE_BUSY without waiting for the busy timeout
+        let tx = pool_conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let result = f(&tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Inserts a prompt with its metadata, fingerprint and index terms. Archived prompts go
+    /// straight to the trash. A parent that isn't a lineage root is resolved to its root first.
+    fn insert_row(tx: &Transaction, prompt: &mut DbPrompt) -> CacheResult<()> {
+        if prompt.parent != prompt.id {
+            prompt.parent = tx
+                .query_row(
+                    "SELECT parent FROM prompts WHERE id = ?1",
+                    params![&prompt.parent],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .ok_or_else(|| CacheError::UnknownParent(prompt.parent.clone()))?;
+        }
+        let next: i32 = tx.query_row(
+            "SELECT COALESCE(MAX(version), 0) + 1 FROM prompts WHERE parent = ?1",
+            params![&prompt.parent],
+            |row| row.get(0),
+        )?;
+        prompt.version = prompt.version.max(next);
+        tx.execute(
+            "INSERT INTO prompts (id, version, content, parent, branched, archived, created_at,
+                                  author_name, author_email, commit_message)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
             params![
                 &prompt.id,
                 &prompt.version,
@@ -185,12 +738,15 @@ impl CacheConfig {
                 &prompt.branched,
                 &prompt.archived,
                 &prompt.created_at,
+                prompt.commit.as_ref().map(|commit| &commit.author_name),
+                prompt.commit.as_ref().map(|commit| &commit.author_email),
+                prompt.commit.as_ref().map(|commit| &commit.message),
             ],
         )?;
 
         if let Some(metadata) = &prompt.metadata {
             info!("Inserting metadata for prompt: {}", prompt.id);
-            self.pool.get()?.execute(
+            tx.execute(
                 "INSERT INTO metadata (id, name, description, category, tags, updated_at)
                  VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
                 params![
@@ -204,13 +760,530 @@ impl CacheConfig {
             )?;
         }
 
+        Self::store_fingerprint(tx, &prompt.id, &prompt.content)?;
+        if prompt.archived == Some(true) {
+            tx.execute(
+                "INSERT OR IGNORE INTO trash (id, archived_at) VALUES (?1, ?2)",
+                params![&prompt.id, now_timestamp()],
+            )?;
+        }
+        Self::index_document(tx, &prompt.id)
+    }
+
+    fn store_fingerprint(conn: &Connection, id: &str, content: &str) -> CacheResult<()> {
+        let minhash = MinHash::from_content(content);
+        conn.execute(
+            "INSERT OR REPLACE INTO fingerprints (id, minhash) VALUES (?1, ?2)",
+            params![id, minhash.to_bytes()],
+        )?;
+        conn.execute("DELETE FROM fingerprint_bands WHERE id = ?1", params![id])?;
+        let mut stmt =
+            conn.prepare("INSERT INTO fingerprint_bands (band, bucket, id) VALUES (?1, ?2, ?3)")?;
+        for (band, bucket) in minhash.band_buckets().into_iter().enumerate() {
+            stmt.execute(params![band as i64, bucket, id])?;
+        }
+        Ok(())
+    }
+
+    /// Fingerprints prompts stored before fingerprinting existed
+    fn backfill_fingerprints(&self) -> CacheResult<()> {
+        let missing = self
+            .pool
+            .get()?
+            .prepare(
+                "SELECT p.id, p.content FROM prompts p
+                 LEFT JOIN fingerprints f ON p.id = f.id
+                 WHERE f.id IS NULL",
+            )?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+
+        if !missing.is_empty() {
+            info!("Fingerprinting {} prompts", missing.len());
+        }
+        self.transaction(|tx| {
+            missing
+                .iter()
+                .try_for_each(|(id, content)| Self::store_fingerprint(tx, id, content))
+        })
+    }
+
+    fn load_fingerprints(
+        conn: &Connection,
+        ids: &[String],
+    ) -> CacheResult<HashMap<String, MinHash>> {
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, minhash FROM fingerprints WHERE id IN ({placeholders})"
+        ))?;
+        let fingerprints = stmt
+            .query_map(params_from_iter(ids), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .filter_map(|res| match res {
+                Ok((id, bytes)) => MinHash::from_bytes(&bytes).map(|minhash| Ok((id, minhash))),
+                Err(e) => Some(Err(e.into())),
+            })
+            .collect();
+        fingerprints
+    }
+
+    /// Removes a prompt's terms from the related prompt index
+    fn unindex_document(conn: &Connection, id: &str) -> CacheResult<()> {
+        conn.execute(
+            "UPDATE term_documents SET documents = documents - 1
+             WHERE term IN (SELECT term FROM index_terms WHERE id = ?1)",
+            params![id],
+        )?;
+        conn.execute("DELETE FROM term_documents WHERE documents <= 0", [])?;
+        conn.execute("DELETE FROM index_terms WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Replaces a prompt's terms in the related prompt index with its current content and
+    /// metadata
+    fn index_document(conn: &Connection, id: &str) -> CacheResult<()> {
+        let prompt = conn
+            .query_row(
+                &format!(
+                    "SELECT {PROMPT_COLUMNS} FROM prompts p
+                     LEFT JOIN metadata m ON p.id = m.id
+                     WHERE p.id = ?1"
+                ),
+                params![id],
+                prompt_from_row,
+            )
+            .optional()?
+            .ok_or(CacheError::NotFound)?;
+
+        let metadata = prompt.metadata.as_ref();
+        let weights = tfidf::term_weights(&tfidf::Document {
+            content: &prompt.content,
+            name: metadata.and_then(|m| m.name.as_deref()),
+            description: metadata.and_then(|m| m.description.as_deref()),
+            tags: metadata.and_then(|m| m.tags.as_deref()).unwrap_or_default(),
+        });
+
+        Self::unindex_document(conn, id)?;
+        let mut insert_term =
+            conn.prepare("INSERT INTO index_terms (id, term, weight) VALUES (?1, ?2, ?3)")?;
+        let mut count_term = conn.prepare(
+            "INSERT INTO term_documents (term, documents) VALUES (?1, 1)
+             ON CONFLICT (term) DO UPDATE SET documents = documents + 1",
+        )?;
+        for (term, weight) in &weights {
+            insert_term.execute(params![id, term, weight])?;
+            count_term.execute(params![term])?;
+        }
+        Ok(())
+    }
+
+    /// Indexes prompts stored before the related prompt index existed
+    fn backfill_index(&self) -> CacheResult<()> {
+        let missing = self
+            .pool
+            .get()?
+            .prepare(
+                "SELECT p.id FROM prompts p
+                 WHERE NOT EXISTS (SELECT 1 FROM index_terms t WHERE t.id = p.id)",
+            )?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        if !missing.is_empty() {
+            info!("Indexing {} prompts for related prompts", missing.len());
+        }
+        self.transaction(|tx| {
+            missing
+                .iter()
+                .try_for_each(|id| Self::index_document(tx, id))
+        })
+    }
+
+    /// Runs a keyset-paginated listing over `source` joined with metadata. Extra columns are
+    /// selected after the sort key, starting at index 14.
+    fn query_page<T>(
+        &self,
+        query: PromptQuery,
+        source: &str,
+        source_condition: &str,
+        extra_columns: &str,
+        map_row: impl Fn(&Row) -> rusqlite::Result<T>,
+    ) -> CacheResult<PromptPage<T>> {
+        query.check_limit()?;
+
+        let cursor = query
+            .cursor
+            .as_deref()
+            .map(|cursor| PromptCursor::decode(cursor, query.sort, query.order))
+            .transpose()?;
+
+        let (filter_sql, filter_params) = query.filter_clause();
+        let pool_conn = self.pool.get()?;
+
+        let total: i64 = pool_conn
+            .query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM {source}
+                     LEFT JOIN metadata m ON p.id = m.id
+                     WHERE {source_condition} AND {filter_sql}"
+                ),
+                params_from_iter(filter_params.iter()),
+                |row| row.get(0),
+            )
+            .inspect_err(|e| error!("Failed to count prompts for get_prompts: {}", e))?;
+
+        let sort_expr = query.sort.sql_expr();
+        let comparison = match query.order {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        };
+        let direction = query.order.sql_keyword();
+
+        let mut page_params = filter_params;
+        let cursor_sql = match cursor {
+            Some(cursor) => {
+                page_params.extend([
+                    cursor.sort_key.clone(),
+                    cursor.sort_key,
+                    Value::Text(cursor.id),
+                ]);
+                format!(
+                    "AND ({sort_expr} {comparison} ? OR ({sort_expr} = ? AND p.id {comparison} ?))"
+                )
+            }
+            None => String::new(),
+        };
+        page_params.push(Value::Integer(i64::from(query.limit) + 1));
+
+        let mut stmt = pool_conn
+            .prepare(&format!(
+                "SELECT {PROMPT_COLUMNS}, {sort_expr}{extra_columns}
+                 FROM {source}
+                 LEFT JOIN metadata m ON p.id = m.id
+                 WHERE {source_condition} AND {filter_sql} {cursor_sql}
+                 ORDER BY {sort_expr} {direction}, p.id {direction}
+                 LIMIT ?"
+            ))
+            .inspect_err(|e| error!("Failed to prepare statement for get_prompts: {}", e))?;
+
+        let mut rows = stmt
+            .query_map(params_from_iter(page_params.iter()), |row| {
+                Ok((
+                    map_row(row)?,
+                    row.get::<_, Value>(PROMPT_COLUMN_COUNT)?,
+                    row.get::<_, String>(0)?,
+                ))
+            })?
+            .map(|res| res.map_err(Into::into))
+            .collect::<Result<Vec<(T, Value, String)>, CacheError>>()?;
+
+        let next_cursor = if rows.len() > query.limit as usize {
+            rows.truncate(query.limit as usize);
+            rows.last().map(|(_, sort_key, id)| {
+                PromptCursor {
+                    sort_key: sort_key.clone(),
+                    id: id.clone(),
+                }
+                .encode(query.sort, query.order)
+            })
+        } else {
+            None
+        };
+
+        Ok(PromptPage {
+            items: rows.into_iter().map(|(item, _, _)| item).collect(),
+            next_cursor,
+            total,
+        })
+    }
+
+    fn purge_where(
+        tx: &Transaction,
+        condition: &str,
+        params: impl Params + Clone,
+    ) -> CacheResult<usize> {
+        let trashed = format!(
+            "SELECT p.id FROM prompts p
+             JOIN trash t ON p.id = t.id
+             WHERE COALESCE(p.archived, false) = true AND {condition}
+               AND NOT EXISTS (SELECT 1 FROM prompts v WHERE v.parent = p.id AND v.id != p.id)"
+        );
+        tx.prepare(&trashed)?
+            .query_map(params.clone(), |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?
+            .iter()
+            .try_for_each(|id| Self::unindex_document(tx, id))?;
+        tx.execute(
+            &format!("DELETE FROM metadata WHERE id IN ({trashed})"),
+            params.clone(),
+        )?;
+        let purged = tx.execute(
+            &format!("DELETE FROM prompts WHERE id IN ({trashed})"),
+            params.clone(),
+        )?;
+        tx.execute_batch(
+            "DELETE FROM trash WHERE id NOT IN (SELECT id FROM prompts);
+             DELETE FROM fingerprints WHERE id NOT IN (SELECT id FROM prompts);
+             DELETE FROM fingerprint_bands WHERE id NOT IN (SELECT id FROM prompts);",
+        )?;
+        Ok(purged)
+    }
+}
+
+impl PromptStore for CacheConfig {
+    fn insert_prompt(&self, mut prompt: DbPrompt) -> CacheResult<DbPrompt> {
+        info!("Inserting: {}", prompt.id);
+        self.transaction(|tx| Self::insert_row(tx, &mut prompt))
+            .inspect_err(|e| error!("Failed to insert prompt {}: {:?}", prompt.id, e))?;
+
         Ok(prompt)
     }
 
-    pub fn get_prompt_content(&self, id: &str) -> CacheResult<String> {
+    fn insert_prompts(&self, mut prompts: Vec<DbPrompt>) -> CacheResult<Vec<DbPrompt>> {
+        info!("Inserting {} prompts", prompts.len());
+        self.transaction(|tx| {
+            prompts
+                .iter_mut()
+                .try_for_each(|prompt| Self::insert_row(tx, prompt))
+        })
+        .inspect_err(|e| error!("Failed to insert prompts: {:?}", e))?;
+
+        Ok(prompts)
+    }
+
+    fn get_related_prompts(&self, id: &str, limit: usize) -> CacheResult<Vec<DbSimilarPrompt>> {
+        debug!("Getting prompts related to {}", id);
+        let pool_conn = self.pool.get()?;
+        let parent: String = pool_conn
+            .query_row(
+                "SELECT parent FROM prompts WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(CacheError::NotFound)?;
+
+        let documents: i64 =
+            pool_conn.query_row("SELECT COUNT(DISTINCT id) FROM index_terms", [], |row| {
+                row.get(0)
+            })?;
+
+        // Weights of the target and of every head sharing at least one term with it
+        let mut stmt = pool_conn
+            .prepare(
+                "WITH heads AS (
+                     SELECT id, parent FROM (
+                         SELECT id, parent, ROW_NUMBER() OVER (
+                             PARTITION BY parent ORDER BY version DESC, created_at DESC, id DESC
+                         ) AS lineage_rank
+                         FROM prompts
+                         WHERE COALESCE(archived, false) = false
+                     )
+                     WHERE lineage_rank = 1
+                 ),
+                 candidates AS (
+                     SELECT DISTINCT c.id FROM index_terms c
+                     JOIN index_terms target ON target.term = c.term AND target.id = ?1
+                     JOIN heads h ON h.id = c.id
+                     WHERE h.parent != ?2
+                 )
+                 SELECT t.id, t.term, t.weight, d.documents
+                 FROM index_terms t
+                 JOIN term_documents d ON d.term = t.term
+                 WHERE t.id = ?1 OR t.id IN candidates",
+            )
+            .inspect_err(|e| {
+                error!("Failed to prepare statement for get_related_prompts: {}", e)
+            })?;
+
+        let mut vectors: HashMap<String, HashMap<String, f64>> = HashMap::new();
+        stmt.query_map(params![id, parent], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, f64>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?
+        .try_for_each(|res| {
+            res.map(|(doc, term, weight, documents_with_term)| {
+                vectors
+                    .entry(doc)
+                    .or_default()
+                    .insert(term, weight * tfidf::idf(documents, documents_with_term));
+            })
+        })?;
+
+        let target = vectors.remove(id).unwrap_or_default();
+        let mut scored: Vec<(String, f64)> = vectors
+            .iter()
+            .map(|(doc, vector)| (doc.clone(), tfidf::cosine(&target, vector)))
+            .filter(|(_, score)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        scored.truncate(limit);
+
+        let mut stmt = pool_conn.prepare(
+            "SELECT p.parent, m.name FROM prompts p
+             LEFT JOIN metadata m ON m.id = p.id
+             WHERE p.id = ?1",
+        )?;
+        scored
+            .into_iter()
+            .map(|(id, similarity)| {
+                let (parent, name) = stmt.query_row(params![id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+                })?;
+                Ok(DbSimilarPrompt {
+                    id,
+                    parent,
+                    name,
+                    similarity,
+                })
+            })
+            .collect()
+    }
+
+    fn get_similar_prompts(
+        &self,
+        id: &str,
+        threshold: f64,
+        limit: usize,
+        include_lineage: bool,
+    ) -> CacheResult<Vec<DbSimilarPrompt>> {
+        debug!(
+            "Getting prompts similar to {} with threshold {}",
+            id, threshold
+        );
+        let pool_conn = self.pool.get()?;
+        let target = Self::load_fingerprints(&pool_conn, &[id.to_string()])?
+            .remove(id)
+            .ok_or(CacheError::NotFound)?;
+
+        let mut stmt = pool_conn
+            .prepare(
+                "SELECT DISTINCT p.id, p.parent, m.name, f.minhash
+                 FROM fingerprint_bands target
+                 JOIN fingerprint_bands candidate
+                   ON candidate.band = target.band AND candidate.bucket = target.bucket
+                 JOIN prompts p ON p.id = candidate.id
+                 JOIN fingerprints f ON f.id = p.id
+                 LEFT JOIN metadata m ON m.id = p.id
+                 WHERE target.id = ?1
+                   AND p.id != ?1
+                   AND COALESCE(p.archived, false) = false
+                   AND (?2 OR p.parent != (SELECT parent FROM prompts WHERE id = ?1))",
+            )
+            .inspect_err(|e| {
+                error!("Failed to prepare statement for get_similar_prompts: {}", e)
+            })?;
+
+        let mut similar = stmt
+            .query_map(params![id, include_lineage], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                ))
+            })?
+            .map(|res| res.map_err(CacheError::from))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter_map(|(id, parent, name, bytes)| {
+                MinHash::from_bytes(&bytes).map(|minhash| DbSimilarPrompt {
+                    similarity: target.similarity(&minhash),
+                    id,
+                    parent,
+                    name,
+                })
+            })
+            .filter(|prompt| prompt.similarity >= threshold)
+            .collect::<Vec<_>>();
+
+        similar.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        similar.truncate(limit);
+        Ok(similar)
+    }
+
+    fn get_duplicate_groups(&self, threshold: f64) -> CacheResult<Vec<Vec<DbSimilarPrompt>>> {
+        debug!("Getting duplicate groups with threshold {}", threshold);
+        let pool_conn = self.pool.get()?;
+        let mut stmt = pool_conn
+            .prepare(
+                "WITH heads AS (
+                     SELECT id FROM (
+                         SELECT id, ROW_NUMBER() OVER (
+                             PARTITION BY parent ORDER BY version DESC, created_at DESC, id DESC
+                         ) AS lineage_rank
+                         FROM prompts
+                         WHERE COALESCE(archived, false) = false
+                     )
+                     WHERE lineage_rank = 1
+                 )
+                 SELECT DISTINCT a.id, b.id
+                 FROM fingerprint_bands a
+                 JOIN fingerprint_bands b
+                   ON a.band = b.band AND a.bucket = b.bucket AND a.id < b.id
+                 WHERE a.id IN heads AND b.id IN heads",
+            )
+            .inspect_err(|e| {
+                error!(
+                    "Failed to prepare statement for get_duplicate_groups: {}",
+                    e
+                )
+            })?;
+
+        let candidates = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<(String, String)>, _>>()?;
+
+        let ids: Vec<String> = candidates
+            .iter()
+            .flat_map(|(a, b)| [a.clone(), b.clone()])
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let fingerprints = Self::load_fingerprints(&pool_conn, &ids)?;
+
+        let mut stmt = pool_conn.prepare(
+            "SELECT p.parent, m.name FROM prompts p
+             LEFT JOIN metadata m ON m.id = p.id
+             WHERE p.id = ?1",
+        )?;
+        fingerprint::group_duplicates(&candidates, &fingerprints, threshold)
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|(id, similarity)| {
+                        let (parent, name) = stmt.query_row(params![id], |row| {
+                            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+                        })?;
+                        Ok(DbSimilarPrompt {
+                            id,
+                            parent,
+                            name,
+                            similarity,
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn get_prompt_content(&self, id: &str) -> CacheResult<String> {
         let pool_conn = self.pool.get()?;
         let mut stmt = pool_conn
-            .prepare("SELECT content FROM prompts WHERE id = ?1")
+            .prepare("SELECT content, archived FROM prompts WHERE id = ?1")
             .inspect_err(|e| {
                 error!(
                     "Failed to prepare statement for get_prompt_content: {:?}",
@@ -218,8 +1291,10 @@ impl CacheConfig {
                 )
             })?;
 
-        let content = stmt
-            .query_row(params![id], |row| row.get(0))
+        let (content, archived) = stmt
+            .query_row(params![id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<bool>>(1)?))
+            })
             .inspect_err(|e| match e {
                 rusqlite::Error::QueryReturnedNoRows => {
                     error!("No prompt found with id {}", id);
@@ -228,13 +1303,21 @@ impl CacheConfig {
                     error!("Database error while getting prompt content: {:?}", e);
                 }
             })?;
-        Ok(content)
+
+        match archived {
+            Some(true) => Err(CacheError::Archived),
+            _ => Ok(content),
+        }
     }
 
-    pub fn get_prompt_categories(&self) -> CacheResult<Vec<String>> {
+    fn get_prompt_categories(&self) -> CacheResult<Vec<String>> {
         let pool_conn = self.pool.get()?;
         let mut stmt = pool_conn
-            .prepare("SELECT DISTINCT category FROM metadata")
+            .prepare(
+                "SELECT DISTINCT category FROM metadata
+                 WHERE category IS NOT NULL
+                 ORDER BY category",
+            )
             .inspect_err(|e| {
                 error!(
                     "Failed to prepare statement for get_prompt_categories: {}",
@@ -250,133 +1333,115 @@ impl CacheConfig {
         Ok(categories)
     }
 
-    pub fn get_prompt_content_latest_version(&self, id: &str) -> CacheResult<String> {
+    fn get_category_counts(&self) -> CacheResult<Vec<DbCategoryCount>> {
         let pool_conn = self.pool.get()?;
         let mut stmt = pool_conn
-            .prepare("SELECT content FROM prompts WHERE parent = ?1 ORDER by version DESC limit 1")
+            .prepare(
+                "SELECT m.category, COUNT(*) FROM metadata m
+                 JOIN prompts p ON p.id = m.id
+                 WHERE m.category IS NOT NULL AND COALESCE(p.archived, false) = false
+                 GROUP BY m.category",
+            )
             .inspect_err(|e| {
-                error!(
-                    "Failed to prepare statement for get_prompt_content_latest_version: {}",
-                    e
-                )
+                error!("Failed to prepare statement for get_category_counts: {}", e)
             })?;
+        let direct = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .collect::<Result<Vec<(String, i64)>, _>>()?;
 
-        let content = stmt
-            .query_row(params![id], |row| row.get(0))
-            .inspect_err(|e| match e {
-                rusqlite::Error::QueryReturnedNoRows => {
-                    error!("No prompt found with id {} for latest version", id);
-                }
-                _ => {
-                    error!(
-                        "Database error while getting latest prompt content: {:?}",
-                        e
-                    );
-                }
-            })?;
-        Ok(content)
+        Ok(category_counts(direct))
     }
 
-    // For now don't return metadata since this is just a ui endpoint for now
-    pub fn get_prompts(
-        &self,
-        category: Option<String>,
-        offset: u32,
-        limit: u32,
-    ) -> CacheResult<Vec<DbPrompt>> {
-        debug!(
-            "Getting prompts with params: category={:?}, offset={}, limit={}",
-            category, offset, limit
-        );
-        if limit <= 0 {
-            error!("Invalid request: limit={}", limit);
+    fn merge_categories(&self, sources: &[String], target: &str) -> CacheResult<usize> {
+        info!("Merging categories {:?} into {}", sources, target);
+        if target.is_empty() || sources.is_empty() || sources.iter().any(|s| s.is_empty()) {
             return Err(CacheError::InvalidRequest(
-                "Invalid limit value".to_string(),
+                "Category names must not be empty".to_string(),
             ));
         }
 
-        let pool_conn = self.pool.get()?;
-        let mut stmt: Statement;
-
-        if category.is_none() {
-            stmt = pool_conn
-                .prepare(
-                    "SELECT * FROM prompts p 
-                     LEFT JOIN metadata m ON p.id = m.id 
-                     LIMIT ?1 OFFSET ?2",
+        // Longest source first so a category nested under several sources moves with the most
+        // specific one. Every row is matched against its original category in a single statement,
+        // so a category moved into the target is never matched again by a later source.
+        let sources = merge_order(sources);
+        let matches = |i: usize| {
+            format!("category = ?{i} OR substr(category, 1, length(?{i}) + 1) = ?{i} || '/'")
+        };
+        let cases = (3..sources.len() + 3)
+            .map(|i| {
+                format!(
+                    "WHEN {} THEN ?1 || substr(category, length(?{i}) + 1)",
+                    matches(i)
                 )
-                .inspect_err(|e| error!("Failed to prepare statement for get_prompts: {}", e))?;
-
-            let prompts = stmt
-                .query_map([limit, offset], |row| {
-                    Ok(DbPrompt {
-                        id: row.get(0)?,
-                        version: row.get(1)?,
-                        content: row.get(2)?,
-                        parent: row.get(3)?,
-                        branched: row.get(4)?,
-                        archived: row.get(5)?,
-                        created_at: row.get(6)?,
-                        metadata: Some(DbPromptMetadata {
-                            id: row.get(7)?,
-                            name: row.get(8)?,
-                            description: row.get(9)?,
-                            category: row.get(10)?,
-                            tags: row
-                                .get::<_, Option<String>>(11)?
-                                .map(|tags| tags.split(',').map(|s| s.to_string()).collect()),
-                            updated_at: row.get(12)?,
-                        }),
-                    })
-                })?
-                .map(|res| res.map_err(Into::into))
-                .collect::<Result<Vec<DbPrompt>, CacheError>>()?;
-
-            return Ok(prompts);
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let filter = (3..sources.len() + 3)
+            .map(|i| format!("({})", matches(i)))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        let sql = format!(
+            "UPDATE metadata SET category = CASE {cases} END, updated_at = ?2 WHERE {filter}"
+        );
+        let values = [
+            Value::Text(target.to_string()),
+            Value::Integer(now_timestamp()),
+        ]
+        .into_iter()
+        .chain(sources.iter().map(|source| Value::Text(source.to_string())));
+
+        let updated = self.transaction(|tx| {
+            tx.execute(&sql, params_from_iter(values))
+                .inspect_err(|e| error!("Failed to merge categories {:?}: {}", sources, e))
+                .map_err(CacheError::from)
+        })?;
+
+        match updated {
+            0 => Err(CacheError::NotFound),
+            _ => Ok(updated),
         }
-        stmt = pool_conn
-            .prepare(
-                "SELECT * FROM prompts p 
-                 LEFT JOIN metadata m ON p.id = m.id 
-                 WHERE m.category = ?1 
-                 LIMIT ?2 OFFSET ?3",
-            )
-            .inspect_err(|e| {
-                error!(
-                    "Failed to prepare statement for get_prompts with category: {}",
-                    e
-                )
-            })?;
+    }
 
-        let prompts = stmt
-            .query_map(params![category.unwrap(), limit, offset], |row| {
-                Ok(DbPrompt {
-                    id: row.get(0)?,
-                    version: row.get(1)?,
-                    content: row.get(2)?,
-                    parent: row.get(3)?,
-                    branched: row.get(4)?,
-                    archived: row.get(5)?,
-                    created_at: row.get(6)?,
-                    metadata: Some(DbPromptMetadata {
-                        id: row.get(7)?,
-                        name: row.get(8)?,
-                        description: row.get(9)?,
-                        category: row.get(10)?,
-                        tags: row
-                            .get::<_, Option<String>>(11)?
-                            .map(|tags| tags.split(',').map(|s| s.to_string()).collect()),
-                        updated_at: row.get(12)?,
-                    }),
-                })
-            })?
-            .map(|res| res.map_err(Into::into))
-            .collect();
+    fn get_prompt_content_latest_version(&self, id: &str) -> CacheResult<String> {
+        latest_version(self, id, false).map(|prompt| prompt.content)
+    }
 
-        prompts
+    fn get_prompts(&self, query: PromptQuery) -> CacheResult<PromptPage> {
+        debug!("Getting prompts with query: {:?}", query);
+        self.query_page(query, "prompts p", "1 = 1", "", prompt_from_row)
+    }
+
+    fn get_prompt_heads(&self, query: PromptQuery) -> CacheResult<PromptPage<DbPromptHead>> {
+        debug!("Getting prompt heads with query: {:?}", query);
+        // Archived versions don't count towards a lineage unless they were asked for
+        let source_filter = match query.archived.is_none() && !query.include_archived {
+            true => "WHERE COALESCE(archived, false) = false",
+            false => "",
+        };
+        self.query_page(
+            query,
+            &format!(
+                "(SELECT *,
+                         COUNT(*) OVER (PARTITION BY parent) AS version_count,
+                         ROW_NUMBER() OVER (
+                             PARTITION BY parent ORDER BY version DESC, created_at DESC, id DESC
+                         ) AS lineage_rank
+                  FROM prompts {source_filter}) p"
+            ),
+            "p.lineage_rank = 1",
+            ", p.version_count",
+            |row| {
+                Ok(DbPromptHead {
+                    prompt: prompt_from_row(row)?,
+                    version_count: row.get(PROMPT_COLUMN_COUNT + 1)?,
+                })
+            },
+        )
     }
 
-    pub fn get_prompt(&self, id: &str, metadata: Option<bool>) -> CacheResult<Option<DbPrompt>> {
+    fn get_prompt(&self, id: &str, metadata: Option<bool>) -> CacheResult<Option<DbPrompt>> {
         debug!(
             "Getting prompt with id: {} and metadata: {:?}",
             id, metadata
@@ -384,49 +1449,35 @@ impl CacheConfig {
         let pool_conn = self.pool.get()?;
 
         if metadata.is_some_and(|m| m) {
-            let mut stmt = pool_conn.prepare(
-                "SELECT p.id, p.version, p.content, p.parent, p.branched, p.archived, p.created_at,
-                        m.name, m.description, m.category, m.tags, m.updated_at
-                 FROM prompts p 
-                 LEFT JOIN metadata m ON p.id = m.id 
-                 WHERE p.id = ?1",
-            )
-            .inspect_err(|e| error!("Failed to prepare statement for get_prompt with metadata: {}", e))?;
-
-            let prompt_with_metadata = stmt.query_row(params![id], |row| {
-                Ok(DbPrompt {
-                    id: row.get(0)?,
-                    version: row.get(1)?,
-                    content: row.get(2)?,
-                    parent: row.get(3)?,
-                    branched: row.get(4)?,
-                    archived: row.get(5)?,
-                    created_at: row.get(6)?,
-                    metadata: Some(DbPromptMetadata {
-                        id: row.get(0)?,
-                        name: row.get(7)?,
-                        description: row.get(8)?,
-                        category: row.get(9)?,
-                        tags: row
-                            .get::<_, Option<String>>(10)?
-                            .map(|tags| tags.split(',').map(|s| s.to_string()).collect()),
-                        updated_at: row.get(11)?,
-                    }),
-                })
-            }); // TODO: use rusqlite optional to avoid norows error handling
+            let mut stmt = pool_conn
+                .prepare(&format!(
+                    "SELECT {PROMPT_COLUMNS}
+                     FROM prompts p
+                     LEFT JOIN metadata m ON p.id = m.id
+                     WHERE p.id = ?1"
+                ))
+                .inspect_err(|e| {
+                    error!(
+                        "Failed to prepare statement for get_prompt with metadata: {}",
+                        e
+                    )
+                })?;
 
-            return match prompt_with_metadata {
-                Ok(prompt_with_metadata) => Ok(Some(prompt_with_metadata)),
-                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-                Err(e) => {
+            return stmt
+                .query_row(params![id], prompt_from_row)
+                .optional()
+                .map_err(|e| {
                     error!("Database error while getting prompt with metadata: {}", e);
-                    Err(CacheError::UnhandledError(e.to_string()))
-                }
-            };
+                    CacheError::UnhandledError(e.to_string())
+                });
         }
 
         let mut stmt = pool_conn
-            .prepare("SELECT * FROM prompts WHERE id = ?1")
+            .prepare(
+                "SELECT id, version, content, parent, branched, archived, created_at,
+                        author_name, author_email, commit_message
+                 FROM prompts WHERE id = ?1",
+            )
             .inspect_err(|e| {
                 error!(
                     "Failed to prepare statement for get_prompt without metadata: {}",
@@ -434,7 +1485,7 @@ impl CacheConfig {
                 )
             })?;
 
-        let prompt_without_metadata = stmt.query_row(params![id], |row| {
+        stmt.query_row(params![id], |row| {
             Ok(DbPrompt {
                 id: row.get(0)?,
                 version: row.get(1)?,
@@ -444,49 +1495,158 @@ impl CacheConfig {
                 archived: row.get(5)?,
                 created_at: row.get(6)?,
                 metadata: None,
+                commit: commit_from_row(row, 7)?,
             })
-        });
+        })
+        .optional()
+        .map_err(|e| {
+            error!("Database error while getting prompt: {}", e);
+            CacheError::UnhandledError(e.to_string())
+        })
+    }
+
+    fn update_prompt_metadata(&self, id: &str, metadata: DbPromptMetadata) -> CacheResult<String> {
+        let now = now_timestamp();
 
-        return match prompt_without_metadata {
-            Ok(prompt) => Ok(Some(prompt)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => {
-                error!("Database error while getting prompt: {}", e);
-                Err(CacheError::UnhandledError(e.to_string()))
+        self.transaction(|tx| {
+            let rows_affected = tx.execute(
+                "UPDATE metadata SET name = ?2, description = ?3, category = ?4, tags = ?5, updated_at = ?7
+                 WHERE id = ?1",
+                params![&id, &metadata.name, &metadata.description, &metadata.category, &metadata.tags_to_string(), now, now],
+            )
+            .inspect_err(|e| error!("Failed to update prompt metadata: {:?}", e))?;
+
+            match rows_affected {
+                0 => Err(CacheError::NotFound),
+                _ => {
+                    Self::index_document(tx, id)?;
+                    Ok(id.to_string())
+                }
             }
-        };
+        })
     }
 
-    pub fn update_prompt_metadata(
+    fn patch_prompt_metadata(
         &self,
         id: &str,
-        metadata: DbPromptMetadata,
-    ) -> CacheResult<String> {
-        let now = now_timestamp();
+        patch: DbMetadataPatch,
+    ) -> CacheResult<DbPromptMetadata> {
+        self.transaction(|tx| {
+            let exists = tx
+                .query_row("SELECT 1 FROM prompts WHERE id = ?1", params![id], |_| {
+                    Ok(())
+                })
+                .optional()?
+                .is_some();
+            if !exists {
+                return Err(CacheError::NotFound);
+            }
+            let existing = tx
+                .query_row(
+                    "SELECT id, name, description, category, tags, updated_at
+                     FROM metadata WHERE id = ?1",
+                    params![id],
+                    |row| {
+                        Ok(DbPromptMetadata {
+                            id: row.get(0)?,
+                            name: row.get(1)?,
+                            description: row.get(2)?,
+                            category: row.get(3)?,
+                            tags: row
+                                .get::<_, Option<String>>(4)?
+                                .map(|tags| tags.split(',').map(|s| s.to_string()).collect()),
+                            updated_at: row.get(5)?,
+                        })
+                    },
+                )
+                .optional()?;
 
-        let rows_affected = self.pool.get()?.execute(
-            "UPDATE metadata SET name = ?2, description = ?3, category = ?4, tags = ?5, updated_at = ?7
-             WHERE id = ?1",
-            params![&id, &metadata.name, &metadata.description, &metadata.category, &metadata.tags_to_string(), now, now],
-        )
-        .inspect_err(|e| error!("Failed to update prompt metadata: {:?}", e))?;
+            let metadata = patch.apply(id, existing);
+            tx.execute(
+                "INSERT INTO metadata (id, name, description, category, tags, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT(id) DO UPDATE SET name = excluded.name,
+                     description = excluded.description, category = excluded.category,
+                     tags = excluded.tags, updated_at = excluded.updated_at",
+                params![
+                    id,
+                    &metadata.name,
+                    &metadata.description,
+                    &metadata.category,
+                    &metadata.tags_to_string(),
+                    metadata.updated_at
+                ],
+            )
+            .inspect_err(|e| error!("Failed to patch prompt metadata: {:?}", e))?;
+            Self::index_document(tx, id)?;
+            Ok(metadata)
+        })
+    }
 
-        match rows_affected {
-            0 => Err(CacheError::NotFound),
-            _ => Ok(id.to_string()),
-        }
+    fn delete_prompt(&self, id: &str) -> CacheResult<bool> {
+        self.transaction(|tx| {
+            let rows_affected = tx
+                .execute(
+                    "UPDATE prompts SET archived = true WHERE id = ?1",
+                    params![id],
+                )
+                .inspect_err(|e| error!("Failed to delete prompt with id {}: {}", id, e))?;
+            tx.execute(
+                "INSERT OR IGNORE INTO trash (id, archived_at)
+                 SELECT id, ?2 FROM prompts WHERE id = ?1",
+                params![id, now_timestamp()],
+            )?;
+            Ok(rows_affected > 0)
+        })
     }
 
-    pub fn delete_prompt(&self, id: &str) -> CacheResult<bool> {
-        let rows_affected = self
-            .pool
-            .get()?
-            .execute(
-                "UPDATE prompts SET archived = true WHERE id = ?1",
+    fn restore_prompt(&self, id: &str) -> CacheResult<()> {
+        self.transaction(|tx| {
+            let rows_affected = tx
+                .execute(
+                    "UPDATE prompts SET archived = false WHERE id = ?1",
+                    params![id],
+                )
+                .inspect_err(|e| error!("Failed to restore prompt with id {}: {}", id, e))?;
+            tx.execute("DELETE FROM trash WHERE id = ?1", params![id])?;
+
+            match rows_affected {
+                0 => Err(CacheError::NotFound),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn purge_prompt(&self, id: &str) -> CacheResult<bool> {
+        let purged = self.transaction(|tx| {
+            let versions: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM prompts v JOIN trash t ON t.id = ?1
+                 WHERE v.parent = ?1 AND v.id != ?1",
                 params![id],
+                |row| row.get(0),
+            )?;
+            if versions > 0 {
+                return Err(CacheError::LineageNotEmpty(id.to_string()));
+            }
+            Self::purge_where(tx, "p.id = ?1", params![id])
+        })?;
+        Ok(purged > 0)
+    }
+
+    fn purge_expired(&self, retention: Duration) -> CacheResult<usize> {
+        let cutoff = now_timestamp() - retention.as_secs() as i64;
+        // Roots are only purged once the rest of their lineage is gone, so a second pass picks up
+        // the roots whose versions expired in the first
+        let purged = self.transaction(|tx| {
+            Ok(
+                Self::purge_where(tx, "t.archived_at <= ?1", params![cutoff])?
+                    + Self::purge_where(tx, "t.archived_at <= ?1", params![cutoff])?,
             )
-            .inspect_err(|e| error!("Failed to delete prompt with id {}: {}", id, e))?;
-        Ok(rows_affected > 0)
+        })?;
+        if purged > 0 {
+            info!("Purged {} expired prompts from the trash", purged);
+        }
+        Ok(purged)
     }
 }
 
@@ -494,6 +1654,84 @@ impl CacheConfig {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_schema_migrations() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // A database created before migrations existed
+        let legacy = rusqlite::Connection::open(&db_path).unwrap();
+        legacy
+            .execute_batch(
+                "CREATE TABLE prompts (
+                    id TEXT PRIMARY KEY,
+                    version INTEGER NOT NULL,
+                    content TEXT NOT NULL,
+                    parent TEXT,
+                    branched BOOLEAN,
+                    archived BOOLEAN,
+                    created_at INTEGER NOT NULL
+                );
+                CREATE TABLE metadata (
+                    id TEXT PRIMARY KEY,
+                    name TEXT,
+                    description TEXT,
+                    category TEXT,
+                    tags TEXT,
+                    updated_at INTEGER NOT NULL
+                );
+                INSERT INTO prompts VALUES ('legacy', 1, 'Legacy content', 'legacy', false, true, 1);",
+            )
+            .unwrap();
+        drop(legacy);
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+        let conn = db.pool.get().unwrap();
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, migrations::latest_version());
+        assert!(migrations::plan(&conn).unwrap().pending.is_empty());
+
+        // Data migrations and backfills reached the existing row
+        let trashed: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM trash WHERE id = 'legacy'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(trashed, 1);
+        let fingerprinted: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM fingerprints WHERE id = 'legacy'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(fingerprinted, 1);
+
+        // Reopening is a no-op
+        drop(conn);
+        drop(db);
+        CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+
+        // A database migrated by a newer server is refused
+        rusqlite::Connection::open(&db_path)
+            .unwrap()
+            .pragma_update(None, "user_version", migrations::latest_version() + 1)
+            .unwrap();
+        assert!(matches!(
+            CacheConfig::new(&db_path, ConnectionOptions::default()),
+            Err(CacheError::UnsupportedSchema { .. })
+        ));
+    }
+
     #[test]
     fn test_get_prompt_without_metadata() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -504,7 +1742,7 @@ mod tests {
             .unwrap()
             .to_string();
 
-        let db = CacheConfig::new(&db_path).unwrap();
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
         let inserted_prompt = db.insert_prompt(DbPrompt {
             id: "123".to_string(),
             version: 1,
@@ -514,6 +1752,7 @@ mod tests {
             archived: Some(false),
             created_at: now_timestamp(),
             metadata: None,
+            commit: None,
         });
 
         assert_eq!(inserted_prompt.as_ref().unwrap().id, "123");
@@ -529,7 +1768,7 @@ mod tests {
             .unwrap()
             .to_string();
 
-        let db = CacheConfig::new(&db_path).unwrap();
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
         let inserted_prompt = db.insert_prompt(DbPrompt {
             id: "123".to_string(),
             version: 1,
@@ -539,6 +1778,7 @@ mod tests {
             archived: Some(false),
             created_at: now_timestamp(),
             metadata: None,
+            commit: None,
         });
         assert_eq!(inserted_prompt.as_ref().unwrap().id, "123");
 
@@ -556,6 +1796,7 @@ mod tests {
             archived: Some(false),
             created_at: now_timestamp(),
             metadata: None,
+            commit: None,
         });
 
         let prompt = db.get_prompt_content_latest_version("123").unwrap();
@@ -566,6 +1807,62 @@ mod tests {
 
         let check_original_prompt_content = db.get_prompt_content("123").unwrap();
         assert_eq!(check_original_prompt_content, "Hello, world!");
+
+        // A version branched off a non-root version joins the root's lineage, numbered after its
+        // newest version
+        let inserted = db
+            .insert_prompt(DbPrompt {
+                id: "12345".to_string(),
+                version: 1,
+                content: "third content".to_string(),
+                parent: "1234".to_string(),
+                branched: Some(false),
+                archived: Some(false),
+                created_at: now_timestamp(),
+                metadata: None,
+                commit: None,
+            })
+            .unwrap();
+        assert_eq!((inserted.parent.as_str(), inserted.version), ("123", 3));
+        assert_eq!(db.get_prompt("12345", None).unwrap().unwrap().parent, "123");
+        assert_eq!(
+            db.get_prompt_content_latest_version("1234").unwrap(),
+            "third content"
+        );
+
+        assert!(matches!(
+            db.insert_prompt(DbPrompt {
+                id: "orphan".to_string(),
+                parent: "missing".to_string(),
+                ..inserted.clone()
+            }),
+            Err(CacheError::UnknownParent(parent)) if parent == "missing"
+        ));
+        assert!(db.get_prompt("orphan", None).unwrap().is_none());
+
+        // The commit a version was imported from is kept
+        let commit = DbCommit {
+            author_name: "Ada".to_string(),
+            author_email: "ada@example.com".to_string(),
+            message: "Add greeting".to_string(),
+        };
+        db.insert_prompt(DbPrompt {
+            id: "imported".to_string(),
+            parent: "imported".to_string(),
+            commit: Some(commit.clone()),
+            ..inserted
+        })
+        .unwrap();
+        for metadata in [None, Some(true)] {
+            let imported = db.get_prompt("imported", metadata).unwrap().unwrap();
+            assert_eq!(imported.commit.as_ref(), Some(&commit));
+        }
+        assert!(db
+            .get_prompt("123", None)
+            .unwrap()
+            .unwrap()
+            .commit
+            .is_none());
     }
 
     #[test]
@@ -578,7 +1875,7 @@ mod tests {
             .unwrap()
             .to_string();
 
-        let db = CacheConfig::new(&db_path).unwrap();
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
 
         // Insert prompts with metadata
         let _ = db.insert_prompt(DbPrompt {
@@ -597,6 +1894,7 @@ mod tests {
                 tags: Some(vec!["tag1".to_string()]),
                 updated_at: now_timestamp(),
             }),
+            commit: None,
         });
 
         let _ = db.insert_prompt(DbPrompt {
@@ -615,20 +1913,309 @@ mod tests {
                 tags: Some(vec!["tag2".to_string()]),
                 updated_at: now_timestamp(),
             }),
+            commit: None,
         });
 
         // Test get all prompts
-        let prompts = db.get_prompts(None, 0, 10).unwrap();
-        assert_eq!(prompts.len(), 2);
+        let prompts = db.get_prompts(PromptQuery::default()).unwrap();
+        assert_eq!(prompts.items.len(), 2);
+        assert_eq!(prompts.total, 2);
+        assert!(prompts.next_cursor.is_none());
 
         // Test get prompts by category
-        let test_prompts = db.get_prompts(Some("test".to_string()), 0, 10).unwrap();
-        assert_eq!(test_prompts.len(), 1);
-        assert_eq!(test_prompts[0].id, "prompt1");
+        let test_prompts = db
+            .get_prompts(PromptQuery {
+                category: Some("test".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(test_prompts.items.len(), 1);
+        assert_eq!(test_prompts.items[0].id, "prompt1");
 
         // Test pagination
-        let limited_prompts = db.get_prompts(None, 0, 1).unwrap();
-        assert_eq!(limited_prompts.len(), 1);
+        let limited_prompts = db
+            .get_prompts(PromptQuery {
+                limit: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(limited_prompts.items.len(), 1);
+        assert_eq!(limited_prompts.total, 2);
+        assert!(limited_prompts.next_cursor.is_some());
+
+        // Test invalid limit
+        let result = db.get_prompts(PromptQuery {
+            limit: 0,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(CacheError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_get_prompts_cursor_pagination() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+
+        for (i, name) in ["charlie", "alpha", "bravo", "delta", "echo"]
+            .iter()
+            .enumerate()
+        {
+            let id = format!("prompt{}", i);
+            let _ = db.insert_prompt(DbPrompt {
+                id: id.clone(),
+                version: 1,
+                content: format!("Content {}", i),
+                parent: id.clone(),
+                branched: Some(i % 2 == 0),
+                archived: Some(false),
+                created_at: 1_000 + i as i64,
+                metadata: Some(DbPromptMetadata {
+                    id,
+                    name: Some(name.to_string()),
+                    description: None,
+                    category: None,
+                    tags: None,
+                    updated_at: 2_000 - i as i64,
+                }),
+                commit: None,
+            });
+        }
+
+        // Walk every page, newest first
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db
+                .get_prompts(PromptQuery {
+                    limit: 2,
+                    cursor,
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(page.total, 5);
+            seen.extend(page.items.into_iter().map(|p| p.id));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            seen,
+            vec!["prompt4", "prompt3", "prompt2", "prompt1", "prompt0"]
+        );
+
+        // Sort by name ascending
+        let by_name = db
+            .get_prompts(PromptQuery {
+                sort: PromptSort::Name,
+                order: SortOrder::Asc,
+                limit: 2,
+                ..Default::default()
+            })
+            .unwrap();
+        let names: Vec<_> = by_name
+            .items
+            .iter()
+            .map(|p| p.metadata.as_ref().unwrap().name.clone().unwrap())
+            .collect();
+        assert_eq!(names, vec!["alpha", "bravo"]);
+
+        let next = db
+            .get_prompts(PromptQuery {
+                sort: PromptSort::Name,
+                order: SortOrder::Asc,
+                limit: 2,
+                cursor: by_name.next_cursor.clone(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            next.items[0].metadata.as_ref().unwrap().name.as_deref(),
+            Some("charlie")
+        );
+
+        // A cursor is only valid for the ordering it was issued for
+        let result = db.get_prompts(PromptQuery {
+            sort: PromptSort::UpdatedAt,
+            cursor: by_name.next_cursor,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(CacheError::InvalidRequest(_))));
+
+        // Filters
+        let filtered = db
+            .get_prompts(PromptQuery {
+                branched: Some(true),
+                created_after: Some(1_001),
+                ..Default::default()
+            })
+            .unwrap();
+        let ids: Vec<_> = filtered.items.into_iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec!["prompt4", "prompt2"]);
+        assert_eq!(filtered.total, 2);
+    }
+
+    #[test]
+    fn test_get_prompt_heads() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+
+        // Three versions of one lineage and a standalone prompt
+        for (id, version, category, tags) in [
+            ("root", 1, "react", "react"),
+            ("root-v2", 2, "react", "react,typescript"),
+            ("root-v3", 3, "react", "react,typescript"),
+            ("other", 1, "python", "python"),
+        ] {
+            let _ = db.insert_prompt(DbPrompt {
+                id: id.to_string(),
+                version,
+                content: format!("{} content", id),
+                parent: if id == "other" { "other" } else { "root" }.to_string(),
+                branched: Some(false),
+                archived: Some(false),
+                created_at: now_timestamp(),
+                metadata: Some(DbPromptMetadata {
+                    id: id.to_string(),
+                    name: Some(id.to_string()),
+                    description: None,
+                    category: Some(category.to_string()),
+                    tags: Some(tags.split(',').map(|t| t.to_string()).collect()),
+                    updated_at: now_timestamp(),
+                }),
+                commit: None,
+            });
+        }
+
+        let heads = db.get_prompt_heads(PromptQuery::default()).unwrap();
+        assert_eq!(heads.total, 2);
+        let root = heads
+            .items
+            .iter()
+            .find(|head| head.prompt.parent == "root")
+            .unwrap();
+        assert_eq!(root.prompt.id, "root-v3");
+        assert_eq!(root.prompt.content, "root-v3 content");
+        assert_eq!(root.version_count, 3);
+
+        let react = db
+            .get_prompt_heads(PromptQuery {
+                category: Some("react".to_string()),
+                tag: Some("typescript".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(react.items.len(), 1);
+        assert_eq!(react.items[0].prompt.id, "root-v3");
+
+        // Tags match whole values only
+        let partial = db
+            .get_prompt_heads(PromptQuery {
+                tag: Some("type".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(partial.total, 0);
+    }
+
+    #[test]
+    fn test_connection_options() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(
+            &db_path,
+            ConnectionOptions {
+                max_connections: 3,
+                busy_timeout: Duration::from_millis(1234),
+                synchronous: Synchronous::Full,
+                cache_size: -4000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(db.pool.max_size(), 3);
+
+        // Hold every connection at once so none of them is checked twice
+        let connections: Vec<_> = (0..3).map(|_| db.pool.get().unwrap()).collect();
+        for conn in &connections {
+            let pragma = |name: &str| -> i64 {
+                conn.pragma_query_value(None, name, |row| row.get(0))
+                    .unwrap()
+            };
+            assert_eq!(pragma("busy_timeout"), 1234);
+            assert_eq!(pragma("foreign_keys"), 1);
+            assert_eq!(pragma("synchronous"), 2);
+            assert_eq!(pragma("cache_size"), -4000);
+        }
+
+        assert_eq!("normal".parse(), Ok(Synchronous::Normal));
+        assert!("sometimes".parse::<Synchronous>().is_err());
+    }
+
+    #[test]
+    fn test_insert_prompt_is_atomic() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+        let prompt = |id: &str, metadata_id: &str| DbPrompt {
+            id: id.to_string(),
+            version: 1,
+            content: "Summarize the release notes".to_string(),
+            parent: id.to_string(),
+            branched: Some(false),
+            archived: Some(false),
+            created_at: 1_000,
+            metadata: Some(DbPromptMetadata {
+                id: metadata_id.to_string(),
+                name: Some("release notes".to_string()),
+                description: None,
+                category: None,
+                tags: None,
+                updated_at: 1_000,
+            }),
+            commit: None,
+        };
+        db.insert_prompt(prompt("first", "first")).unwrap();
+
+        // The metadata insert collides, so the prompt row must not land either
+        assert!(db.insert_prompt(prompt("second", "first")).is_err());
+        assert!(db.get_prompt("second", None).unwrap().is_none());
+
+        let conn = db.pool.get().unwrap();
+        let fingerprints: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM fingerprints WHERE id = 'second'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(fingerprints, 0);
     }
 
     #[test]
@@ -641,7 +2228,7 @@ mod tests {
             .unwrap()
             .to_string();
 
-        let db = CacheConfig::new(&db_path).unwrap();
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
 
         let _ = db.insert_prompt(DbPrompt {
             id: "test_id".to_string(),
@@ -659,6 +2246,7 @@ mod tests {
                 tags: Some(vec!["tag1".to_string(), "tag2".to_string()]),
                 updated_at: now_timestamp(),
             }),
+            commit: None,
         });
 
         // Test get prompt with metadata
@@ -690,7 +2278,7 @@ mod tests {
             .unwrap()
             .to_string();
 
-        let db = CacheConfig::new(&db_path).unwrap();
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
 
         let _ = db.insert_prompt(DbPrompt {
             id: "update_test".to_string(),
@@ -708,6 +2296,7 @@ mod tests {
                 tags: Some(vec!["original".to_string()]),
                 updated_at: now_timestamp(),
             }),
+            commit: None,
         });
 
         // Update metadata
@@ -745,6 +2334,68 @@ mod tests {
         assert!(matches!(result, Err(CacheError::NotFound)));
     }
 
+    #[test]
+    fn test_patch_prompt_metadata() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+
+        db.insert_prompt(DbPrompt {
+            id: "patch_test".to_string(),
+            version: 1,
+            content: "Content".to_string(),
+            parent: "patch_test".to_string(),
+            branched: Some(false),
+            archived: Some(false),
+            created_at: now_timestamp(),
+            metadata: None,
+            commit: None,
+        })
+        .unwrap();
+
+        // A prompt without metadata gets it
+        let metadata = db
+            .patch_prompt_metadata(
+                "patch_test",
+                DbMetadataPatch {
+                    name: Some(Some("Name".to_string())),
+                    tags: Some(Some(vec!["a".to_string(), "b".to_string()])),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(metadata.name, Some("Name".to_string()));
+
+        // Omitted fields are untouched, null clears
+        db.patch_prompt_metadata(
+            "patch_test",
+            DbMetadataPatch {
+                name: Some(None),
+                category: Some(Some("writing".to_string())),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let metadata = db
+            .get_prompt("patch_test", Some(true))
+            .unwrap()
+            .unwrap()
+            .metadata
+            .unwrap();
+        assert_eq!(metadata.name, None);
+        assert_eq!(metadata.category, Some("writing".to_string()));
+        assert_eq!(metadata.tags, Some(vec!["a".to_string(), "b".to_string()]));
+
+        let result = db.patch_prompt_metadata("non_existent", DbMetadataPatch::default());
+        assert!(matches!(result, Err(CacheError::NotFound)));
+    }
+
     #[test]
     fn test_delete_prompt() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -755,7 +2406,7 @@ mod tests {
             .unwrap()
             .to_string();
 
-        let db = CacheConfig::new(&db_path).unwrap();
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
 
         let _ = db.insert_prompt(DbPrompt {
             id: "delete_test".to_string(),
@@ -766,6 +2417,7 @@ mod tests {
             archived: Some(false),
             created_at: now_timestamp(),
             metadata: None,
+            commit: None,
         });
 
         // Verify prompt exists before deletion
@@ -787,6 +2439,308 @@ mod tests {
         assert!(!result);
     }
 
+    #[test]
+    fn test_trash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+
+        for (id, version) in [("trash_root", 1), ("trash_v2", 2)] {
+            let _ = db.insert_prompt(DbPrompt {
+                id: id.to_string(),
+                version,
+                content: format!("{} content", id),
+                parent: "trash_root".to_string(),
+                branched: Some(false),
+                archived: Some(false),
+                created_at: now_timestamp(),
+                metadata: None,
+                commit: None,
+            });
+        }
+
+        // Archiving the head falls back to the previous version
+        assert!(db.delete_prompt("trash_v2").unwrap());
+        assert_eq!(
+            db.get_prompt_content_latest_version("trash_root").unwrap(),
+            "trash_root content"
+        );
+        assert!(matches!(
+            db.get_prompt_content("trash_v2"),
+            Err(CacheError::Archived)
+        ));
+
+        // Archived prompts are hidden from listings unless asked for
+        let prompts = db.get_prompts(PromptQuery::default()).unwrap();
+        assert_eq!(prompts.total, 1);
+        let prompts = db
+            .get_prompts(PromptQuery {
+                include_archived: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(prompts.total, 2);
+        let heads = db.get_prompt_heads(PromptQuery::default()).unwrap();
+        assert_eq!(heads.items[0].prompt.id, "trash_root");
+        assert_eq!(heads.items[0].version_count, 1);
+
+        let trash = db.get_trash(PromptQuery::default()).unwrap();
+        assert_eq!(trash.items.len(), 1);
+        assert_eq!(trash.items[0].id, "trash_v2");
+
+        // Restore
+        db.restore_prompt("trash_v2").unwrap();
+        assert_eq!(
+            db.get_prompt_content_latest_version("trash_root").unwrap(),
+            "trash_v2 content"
+        );
+        assert_eq!(db.get_trash(PromptQuery::default()).unwrap().total, 0);
+        assert!(matches!(
+            db.restore_prompt("non_existent"),
+            Err(CacheError::NotFound)
+        ));
+
+        // An archived root still resolves to the newest live version, until every version is
+        // archived
+        db.delete_prompt("trash_root").unwrap();
+        assert_eq!(
+            db.get_prompt_content_latest_version("trash_root").unwrap(),
+            "trash_v2 content"
+        );
+        db.delete_prompt("trash_v2").unwrap();
+        assert!(matches!(
+            db.get_prompt_content_latest_version("trash_root"),
+            Err(CacheError::Archived)
+        ));
+        assert!(matches!(
+            db.get_prompt_content_latest_version("non_existent"),
+            Err(CacheError::NotFound)
+        ));
+        db.restore_prompt("trash_root").unwrap();
+        db.restore_prompt("trash_v2").unwrap();
+
+        // Only archived prompts can be purged, and a root only once the rest of its lineage is
+        // gone
+        assert!(!db.purge_prompt("trash_v2").unwrap());
+        db.delete_prompt("trash_root").unwrap();
+        assert!(matches!(
+            db.purge_prompt("trash_root"),
+            Err(CacheError::LineageNotEmpty(_))
+        ));
+        assert_eq!(db.purge_expired(Duration::ZERO).unwrap(), 0);
+        db.restore_prompt("trash_root").unwrap();
+        db.delete_prompt("trash_v2").unwrap();
+        assert!(db.purge_prompt("trash_v2").unwrap());
+        assert!(db.get_prompt("trash_v2", None).unwrap().is_none());
+
+        // Retention purges whole lineages in one go
+        let _ = db.insert_prompt(DbPrompt {
+            id: "trash_v3".to_string(),
+            version: 3,
+            content: "trash_v3 content".to_string(),
+            parent: "trash_root".to_string(),
+            branched: Some(false),
+            archived: Some(false),
+            created_at: now_timestamp(),
+            metadata: None,
+            commit: None,
+        });
+        db.delete_prompt("trash_root").unwrap();
+        db.delete_prompt("trash_v3").unwrap();
+        assert_eq!(db.purge_expired(Duration::from_secs(60 * 60)).unwrap(), 0);
+        assert_eq!(db.purge_expired(Duration::ZERO).unwrap(), 2);
+        assert!(db.get_prompt("trash_root", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_similar_prompts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+
+        let rules = "You are an expert in TypeScript, Node.js, React, Vite and Tailwind. \
+            Write concise, technical TypeScript code with accurate examples. Use functional and \
+            declarative programming patterns and avoid classes. Prefer iteration and \
+            modularization over code duplication. Use descriptive variable names with auxiliary \
+            verbs such as isLoading and hasError. Structure files as exported component, \
+            subcomponents, helpers, static content and types.";
+
+        for (id, parent, content) in [
+            ("react_rules", "react_rules", rules.to_string()),
+            (
+                "react_rules_copy",
+                "react_rules_copy",
+                rules.replace("Tailwind", "Tailwind CSS"),
+            ),
+            (
+                "react_rules_v2",
+                "react_rules",
+                rules.replace("Vite", "Next.js"),
+            ),
+            (
+                "python_rules",
+                "python_rules",
+                "You are an expert in Python, FastAPI and scalable API development. \
+                 Write concise technical responses with accurate Python examples."
+                    .to_string(),
+            ),
+        ] {
+            let _ = db.insert_prompt(DbPrompt {
+                id: id.to_string(),
+                version: 1,
+                content,
+                parent: parent.to_string(),
+                branched: Some(false),
+                archived: Some(false),
+                created_at: now_timestamp(),
+                metadata: None,
+                commit: None,
+            });
+        }
+
+        let similar = db
+            .get_similar_prompts("react_rules", 0.6, 10, false)
+            .unwrap();
+        let ids: Vec<_> = similar.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids, vec!["react_rules_copy"]);
+        assert!(similar[0].similarity < 1.0);
+
+        let with_lineage = db
+            .get_similar_prompts("react_rules", 0.6, 10, true)
+            .unwrap();
+        assert_eq!(with_lineage.len(), 2);
+
+        assert!(matches!(
+            db.get_similar_prompts("non_existent", 0.6, 10, false),
+            Err(CacheError::NotFound)
+        ));
+
+        // Only lineage heads are compared
+        let groups = db.get_duplicate_groups(0.6).unwrap();
+        assert_eq!(groups.len(), 1);
+        let mut group_ids: Vec<_> = groups[0].iter().map(|p| p.id.as_str()).collect();
+        group_ids.sort();
+        assert_eq!(group_ids, vec!["react_rules_copy", "react_rules_v2"]);
+    }
+
+    #[test]
+    fn test_related_prompts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+
+        for (id, content, name, tags) in [
+            (
+                "react",
+                "Build React components with hooks and TypeScript",
+                "React rules",
+                vec!["react", "frontend"],
+            ),
+            (
+                "nextjs",
+                "Server components and routing in Next.js with React",
+                "Next.js rules",
+                vec!["react", "nextjs"],
+            ),
+            (
+                "django",
+                "Write Django views, models and migrations in Python",
+                "Django rules",
+                vec!["python", "backend"],
+            ),
+            (
+                "fastapi",
+                "Async endpoints with FastAPI and pydantic models",
+                "FastAPI rules",
+                vec!["fastapi"],
+            ),
+        ] {
+            let _ = db.insert_prompt(DbPrompt {
+                id: id.to_string(),
+                version: 1,
+                content: content.to_string(),
+                parent: id.to_string(),
+                branched: Some(false),
+                archived: Some(false),
+                created_at: now_timestamp(),
+                metadata: Some(DbPromptMetadata {
+                    id: id.to_string(),
+                    name: Some(name.to_string()),
+                    description: None,
+                    category: None,
+                    tags: Some(tags.into_iter().map(|t| t.to_string()).collect()),
+                    updated_at: now_timestamp(),
+                }),
+                commit: None,
+            });
+        }
+
+        let related = db.get_related_prompts("react", 10).unwrap();
+        assert_eq!(related[0].id, "nextjs");
+        assert!(related.iter().all(|p| p.id != "react"));
+
+        // FastAPI shares "models" with Django, tagging it python makes them closer
+        let before = db.get_related_prompts("django", 10).unwrap();
+        let score = |related: &[DbSimilarPrompt]| {
+            related
+                .iter()
+                .find(|p| p.id == "fastapi")
+                .map(|p| p.similarity)
+                .unwrap()
+        };
+        db.update_prompt_metadata(
+            "fastapi",
+            DbPromptMetadata {
+                id: "fastapi".to_string(),
+                name: Some("FastAPI rules".to_string()),
+                description: Some("Python backend".to_string()),
+                category: None,
+                tags: Some(vec!["fastapi".to_string(), "python".to_string()]),
+                updated_at: now_timestamp(),
+            },
+        )
+        .unwrap();
+        let after = db.get_related_prompts("django", 10).unwrap();
+        assert!(score(&after) > score(&before));
+        assert_eq!(after[0].id, "fastapi");
+
+        // Document frequencies follow updates instead of double counting
+        let documents: i64 = db
+            .pool
+            .get()
+            .unwrap()
+            .query_row(
+                "SELECT documents FROM term_documents WHERE term = 'python'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(documents, 2);
+
+        assert!(matches!(
+            db.get_related_prompts("non_existent", 10),
+            Err(CacheError::NotFound)
+        ));
+    }
+
     #[test]
     fn test_get_prompt_categories() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -797,7 +2751,7 @@ mod tests {
             .unwrap()
             .to_string();
 
-        let db = CacheConfig::new(&db_path).unwrap();
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
 
         let _ = db.insert_prompt(DbPrompt {
             id: "test_id".to_string(),
@@ -815,9 +2769,109 @@ mod tests {
                 tags: Some(vec!["tag1".to_string(), "tag2".to_string()]),
                 updated_at: now_timestamp(),
             }),
+            commit: None,
         });
 
         let categories = db.get_prompt_categories().unwrap();
         assert_eq!(categories, vec!["test"]);
     }
+
+    #[test]
+    fn test_category_management() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let db = CacheConfig::new(&db_path, ConnectionOptions::default()).unwrap();
+
+        for (id, category) in [
+            ("react1", Some("frontend/react")),
+            ("react2", Some("frontend/react")),
+            ("vue", Some("frontend/vue")),
+            ("css", Some("frontend")),
+            ("django", Some("python")),
+            ("uncategorized", None),
+        ] {
+            let _ = db.insert_prompt(DbPrompt {
+                id: id.to_string(),
+                version: 1,
+                content: "Content".to_string(),
+                parent: id.to_string(),
+                branched: Some(false),
+                archived: Some(false),
+                created_at: now_timestamp(),
+                metadata: Some(DbPromptMetadata {
+                    id: id.to_string(),
+                    name: None,
+                    description: None,
+                    category: category.map(|c| c.to_string()),
+                    tags: None,
+                    updated_at: now_timestamp(),
+                }),
+                commit: None,
+            });
+        }
+
+        // NULL categories are not listed
+        assert_eq!(
+            db.get_prompt_categories().unwrap(),
+            vec!["frontend", "frontend/react", "frontend/vue", "python"]
+        );
+
+        let counts = db.get_category_counts().unwrap();
+        let frontend = counts.iter().find(|c| c.name == "frontend").unwrap();
+        assert_eq!((frontend.count, frontend.total), (1, 4));
+        let react = counts.iter().find(|c| c.name == "frontend/react").unwrap();
+        assert_eq!((react.count, react.total), (2, 2));
+
+        // Filtering by a category includes nested categories
+        let frontend_prompts = db
+            .get_prompts(PromptQuery {
+                category: Some("frontend".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(frontend_prompts.total, 4);
+
+        // Renaming moves nested categories along
+        assert_eq!(db.rename_category("frontend", "web").unwrap(), 4);
+        assert_eq!(
+            db.get_prompt_categories().unwrap(),
+            vec!["python", "web", "web/react", "web/vue"]
+        );
+
+        assert_eq!(
+            db.merge_categories(&["web/react".to_string(), "web/vue".to_string()], "web/spa")
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            db.get_prompt_categories().unwrap(),
+            vec!["python", "web", "web/spa"]
+        );
+
+        // Sources overlapping the target are matched on their original category only once
+        assert_eq!(
+            db.merge_categories(&["python".to_string(), "web".to_string()], "web/legacy")
+                .unwrap(),
+            5
+        );
+        assert_eq!(
+            db.get_prompt_categories().unwrap(),
+            vec!["web/legacy", "web/legacy/spa"]
+        );
+
+        assert!(matches!(
+            db.rename_category("missing", "other"),
+            Err(CacheError::NotFound)
+        ));
+        assert!(matches!(
+            db.rename_category("python", ""),
+            Err(CacheError::InvalidRequest(_))
+        ));
+    }
 }
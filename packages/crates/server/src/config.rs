@@ -0,0 +1,833 @@
+/*
+Layered server configuration, each layer overrides the one before it:
+1. Defaults, suited to local development
+2. A TOML file passed with `--config` or `SPS_CONFIG`
+3. Environment variables
+4. Command line flags
+
+Environment variables and flags are the same clap arguments, a flag wins over its variable.
+`server config check` prints the effective settings without starting the server.
+*/
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::http::HeaderValue;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::backup::Retention;
+use crate::cache::{ConnectionOptions, Synchronous};
+use crate::git_mirror::Author;
+use crate::transfer::{ConflictPolicy, ExportFormat, IdMode};
+use crate::validation::Limits;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Sqlite,
+    Memory,
+}
+
+/// The `STAGE` variable from before layered configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Stage {
+    Dev,
+    Prod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:8080".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub storage: StorageBackend,
+    pub path: String,
+    pub max_connections: u32,
+    pub busy_timeout_ms: u64,
+    pub foreign_keys: bool,
+    pub synchronous: Synchronous,
+    /// Pages if positive, KiB if negative
+    pub cache_size: i64,
+    /// Bytes of the database file to memory map, 0 disables it
+    pub mmap_size: i64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        let options = ConnectionOptions::default();
+        Self {
+            storage: StorageBackend::Sqlite,
+            path: "prompts-dev.db".to_string(),
+            max_connections: options.max_connections,
+            busy_timeout_ms: options.busy_timeout.as_millis() as u64,
+            foreign_keys: options.foreign_keys,
+            synchronous: options.synchronous,
+            cache_size: options.cache_size,
+            mmap_size: options.mmap_size,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            max_connections: self.max_connections,
+            busy_timeout: Duration::from_millis(self.busy_timeout_ms),
+            foreign_keys: self.foreign_keys,
+            synchronous: self.synchronous,
+            cache_size: self.cache_size,
+            mmap_size: self.mmap_size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// `EnvFilter` directives, `RUST_LOG` takes precedence when set
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Full,
+            filter: "debug,axum::rejection=trace,tower_http=warn".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API from a browser, "*" allows any. Empty disables CORS.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadCacheConfig {
+    /// Number of cached content lookups, 0 disables the cache
+    pub capacity: usize,
+    pub ttl_secs: u64,
+}
+
+impl Default for ReadCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            ttl_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    /// Archived prompts are purged once they're older than this, 0 keeps them forever
+    pub retention_days: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self { retention_days: 30 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    /// Minutes between scheduled snapshots, 0 disables them
+    pub interval_mins: u64,
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("backups"),
+            interval_mins: 0,
+            keep_hourly: 24,
+            keep_daily: 7,
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn retention(&self) -> Retention {
+        Retention {
+            keep_hourly: self.keep_hourly,
+            keep_daily: self.keep_daily,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitMirrorConfig {
+    /// Bare repository every lineage is mirrored into, unset disables the mirror
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    pub author_name: String,
+    pub author_email: String,
+}
+
+impl Default for GitMirrorConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            author_name: "sps".to_string(),
+            author_email: "sps@localhost".to_string(),
+        }
+    }
+}
+
+impl GitMirrorConfig {
+    pub fn author(&self) -> Author {
+        Author {
+            name: self.author_name.clone(),
+            email: self.author_email.clone(),
+        }
+    }
+}
+
+/// The unversioned routes kept for clients written before `/v1`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LegacyApiConfig {
+    /// Serve the routes at the root as well as under /v1
+    pub enabled: bool,
+    /// Unix timestamp sent in the Deprecation header
+    pub deprecated_at: i64,
+    /// Unix timestamp sent in the Sunset header, after which the routes may go away
+    pub sunset_at: i64,
+}
+
+impl Default for LegacyApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            // 2026-10-18 and six months later
+            deprecated_at: 1_792_281_600,
+            sunset_at: 1_808_006_400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    /// Serve the Swagger UI at /swagger-ui
+    pub swagger_ui: bool,
+    /// Write the OpenAPI specs, openapi.yaml and openapi.v1.yaml, on startup
+    pub write_openapi: bool,
+    /// Directory the OpenAPI specs are written to
+    pub openapi_dir: PathBuf,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            swagger_ui: true,
+            write_openapi: true,
+            openapi_dir: PathBuf::from("."),
+        }
+    }
+}
+
+/// Limits on prompts and metadata sent to the API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    pub max_content_bytes: usize,
+    pub max_name_chars: usize,
+    pub max_description_chars: usize,
+    pub max_category_chars: usize,
+    pub max_tags: usize,
+    pub max_tag_chars: usize,
+    /// Most ids or prompts in one batch request
+    pub max_batch_items: usize,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            max_content_bytes: 100 * 1024,
+            max_name_chars: 200,
+            max_description_chars: 2000,
+            max_category_chars: 200,
+            max_tags: 32,
+            max_tag_chars: 64,
+            max_batch_items: 100,
+        }
+    }
+}
+
+impl ValidationConfig {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_content_bytes: self.max_content_bytes,
+            max_name_chars: self.max_name_chars,
+            max_description_chars: self.max_description_chars,
+            max_category_chars: self.max_category_chars,
+            max_tags: self.max_tags,
+            max_tag_chars: self.max_tag_chars,
+            max_batch_items: self.max_batch_items,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub log: LogConfig,
+    pub cors: CorsConfig,
+    pub read_cache: ReadCacheConfig,
+    pub trash: TrashConfig,
+    pub backups: BackupConfig,
+    pub git_mirror: GitMirrorConfig,
+    pub validation: ValidationConfig,
+    pub legacy_api: LegacyApiConfig,
+    pub features: FeatureConfig,
+}
+
+impl Config {
+    /// Defaults, then the file at `path` if any, then `overrides`
+    pub fn load(path: Option<&Path>, overrides: &Overrides) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        overrides.apply(&mut config)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn bind_addr(&self) -> Result<SocketAddr, ConfigError> {
+        self.server.bind.parse().map_err(|_| {
+            ConfigError::Invalid(format!("server.bind: invalid address {}", self.server.bind))
+        })
+    }
+
+    pub fn read_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.read_cache.ttl_secs)
+    }
+
+    /// None when the trash is never purged
+    pub fn trash_retention(&self) -> Option<Duration> {
+        (self.trash.retention_days > 0)
+            .then(|| Duration::from_secs(self.trash.retention_days * 24 * 60 * 60))
+    }
+
+    /// Catches what the types alone don't, so a bad value fails at startup rather than later
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.bind_addr()?;
+        if self.database.max_connections == 0 {
+            return Err(ConfigError::Invalid(
+                "database.max_connections must be at least 1".to_string(),
+            ));
+        }
+        if self.database.storage == StorageBackend::Sqlite && self.database.path.is_empty() {
+            return Err(ConfigError::Invalid(
+                "database.path is required with sqlite storage".to_string(),
+            ));
+        }
+        if self.validation.max_batch_items == 0 {
+            return Err(ConfigError::Invalid(
+                "validation.max_batch_items must be at least 1".to_string(),
+            ));
+        }
+        if self.legacy_api.sunset_at < self.legacy_api.deprecated_at {
+            return Err(ConfigError::Invalid(
+                "legacy_api.sunset_at must not be before legacy_api.deprecated_at".to_string(),
+            ));
+        }
+        EnvFilter::try_new(&self.log.filter)
+            .map_err(|e| ConfigError::Invalid(format!("log.filter: {}", e)))?;
+        for origin in &self.cors.allowed_origins {
+            if origin != "*" && HeaderValue::from_str(origin).is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "cors.allowed_origins: invalid origin {}",
+                    origin
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The effective settings, as a config file would set them
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Failed to serialize config to TOML")
+    }
+}
+
+/// Settings that can be overridden by an environment variable or a flag
+#[derive(Debug, Clone, Default, Args)]
+pub struct Overrides {
+    /// Address to listen on
+    #[arg(long, env = "BIND_ADDR", global = true)]
+    pub bind: Option<String>,
+    /// Port to listen on, replaces the port of the bind address
+    #[arg(long, env = "PORT", global = true)]
+    pub port: Option<u16>,
+    /// Deprecated, set DATABASE_PATH instead. `prod` keeps the database in DATA_DIR.
+    #[arg(long, env = "STAGE", global = true, hide = true)]
+    pub stage: Option<Stage>,
+    /// Deprecated, only used with STAGE=prod
+    #[arg(long, env = "DATA_DIR", global = true, hide = true)]
+    pub data_dir: Option<PathBuf>,
+    #[arg(long, env = "STORAGE", global = true)]
+    pub storage: Option<StorageBackend>,
+    /// SQLite database file
+    #[arg(long = "database", env = "DATABASE_PATH", global = true)]
+    pub database_path: Option<String>,
+    #[arg(long, env = "MAX_CONNECTIONS", global = true)]
+    pub max_connections: Option<u32>,
+    #[arg(long, env = "SQLITE_BUSY_TIMEOUT_MS", global = true)]
+    pub busy_timeout_ms: Option<u64>,
+    #[arg(long, env = "SQLITE_FOREIGN_KEYS", global = true)]
+    pub foreign_keys: Option<bool>,
+    #[arg(long, env = "SQLITE_SYNCHRONOUS", global = true)]
+    pub synchronous: Option<Synchronous>,
+    #[arg(
+        long,
+        env = "SQLITE_CACHE_SIZE",
+        global = true,
+        allow_negative_numbers = true
+    )]
+    pub cache_size: Option<i64>,
+    #[arg(long, env = "SQLITE_MMAP_SIZE", global = true)]
+    pub mmap_size: Option<i64>,
+    #[arg(long, env = "LOG_FORMAT", global = true)]
+    pub log_format: Option<LogFormat>,
+    /// Comma separated origins allowed by CORS
+    #[arg(
+        long,
+        env = "CORS_ALLOWED_ORIGINS",
+        global = true,
+        value_delimiter = ','
+    )]
+    pub cors_allowed_origins: Option<Vec<String>>,
+    #[arg(long, env = "READ_CACHE_CAPACITY", global = true)]
+    pub read_cache_capacity: Option<usize>,
+    #[arg(long, env = "READ_CACHE_TTL_SECS", global = true)]
+    pub read_cache_ttl_secs: Option<u64>,
+    #[arg(long, env = "TRASH_RETENTION_DAYS", global = true)]
+    pub trash_retention_days: Option<u64>,
+    #[arg(long, env = "BACKUP_DIR", global = true)]
+    pub backup_dir: Option<PathBuf>,
+    #[arg(long, env = "BACKUP_INTERVAL_MINS", global = true)]
+    pub backup_interval_mins: Option<u64>,
+    #[arg(long, env = "BACKUP_KEEP_HOURLY", global = true)]
+    pub backup_keep_hourly: Option<usize>,
+    #[arg(long, env = "BACKUP_KEEP_DAILY", global = true)]
+    pub backup_keep_daily: Option<usize>,
+    /// Bare git repository to mirror lineages into
+    #[arg(long = "git-mirror", env = "GIT_MIRROR_PATH", global = true)]
+    pub git_mirror_path: Option<PathBuf>,
+    #[arg(long, env = "GIT_MIRROR_AUTHOR_NAME", global = true)]
+    pub git_mirror_author_name: Option<String>,
+    #[arg(long, env = "GIT_MIRROR_AUTHOR_EMAIL", global = true)]
+    pub git_mirror_author_email: Option<String>,
+    #[arg(long, env = "VALIDATION_MAX_CONTENT_BYTES", global = true)]
+    pub max_content_bytes: Option<usize>,
+    #[arg(long, env = "VALIDATION_MAX_NAME_CHARS", global = true)]
+    pub max_name_chars: Option<usize>,
+    #[arg(long, env = "VALIDATION_MAX_DESCRIPTION_CHARS", global = true)]
+    pub max_description_chars: Option<usize>,
+    #[arg(long, env = "VALIDATION_MAX_CATEGORY_CHARS", global = true)]
+    pub max_category_chars: Option<usize>,
+    #[arg(long, env = "VALIDATION_MAX_TAGS", global = true)]
+    pub max_tags: Option<usize>,
+    #[arg(long, env = "VALIDATION_MAX_TAG_CHARS", global = true)]
+    pub max_tag_chars: Option<usize>,
+    #[arg(long, env = "VALIDATION_MAX_BATCH_ITEMS", global = true)]
+    pub max_batch_items: Option<usize>,
+    /// Serve the unversioned routes alongside /v1
+    #[arg(long, env = "LEGACY_API", global = true)]
+    pub legacy_api: Option<bool>,
+    #[arg(long, env = "SWAGGER_UI", global = true)]
+    pub swagger_ui: Option<bool>,
+    #[arg(long, env = "WRITE_OPENAPI", global = true)]
+    pub write_openapi: Option<bool>,
+    #[arg(long, env = "OPENAPI_DIR", global = true)]
+    pub openapi_dir: Option<PathBuf>,
+}
+
+fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+    if let Some(value) = value {
+        *target = value.clone();
+    }
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) -> Result<(), ConfigError> {
+        set(&mut config.server.bind, &self.bind);
+        if let Some(port) = self.port {
+            let mut addr = config.bind_addr()?;
+            addr.set_port(port);
+            config.server.bind = addr.to_string();
+        }
+        set(&mut config.database.storage, &self.storage);
+        set(&mut config.database.path, &self.database_path);
+        if let Some(path) = self.legacy_database_path()? {
+            config.database.path = path;
+        }
+        set(&mut config.database.max_connections, &self.max_connections);
+        set(&mut config.database.busy_timeout_ms, &self.busy_timeout_ms);
+        set(&mut config.database.foreign_keys, &self.foreign_keys);
+        set(&mut config.database.synchronous, &self.synchronous);
+        set(&mut config.database.cache_size, &self.cache_size);
+        set(&mut config.database.mmap_size, &self.mmap_size);
+        set(&mut config.log.format, &self.log_format);
+        set(&mut config.cors.allowed_origins, &self.cors_allowed_origins);
+        set(&mut config.read_cache.capacity, &self.read_cache_capacity);
+        set(&mut config.read_cache.ttl_secs, &self.read_cache_ttl_secs);
+        set(&mut config.trash.retention_days, &self.trash_retention_days);
+        set(&mut config.backups.dir, &self.backup_dir);
+        set(
+            &mut config.backups.interval_mins,
+            &self.backup_interval_mins,
+        );
+        set(&mut config.backups.keep_hourly, &self.backup_keep_hourly);
+        set(&mut config.backups.keep_daily, &self.backup_keep_daily);
+        if self.git_mirror_path.is_some() {
+            config.git_mirror.path = self.git_mirror_path.clone();
+        }
+        set(
+            &mut config.git_mirror.author_name,
+            &self.git_mirror_author_name,
+        );
+        set(
+            &mut config.git_mirror.author_email,
+            &self.git_mirror_author_email,
+        );
+        set(
+            &mut config.validation.max_content_bytes,
+            &self.max_content_bytes,
+        );
+        set(&mut config.validation.max_name_chars, &self.max_name_chars);
+        set(
+            &mut config.validation.max_description_chars,
+            &self.max_description_chars,
+        );
+        set(
+            &mut config.validation.max_category_chars,
+            &self.max_category_chars,
+        );
+        set(&mut config.validation.max_tags, &self.max_tags);
+        set(&mut config.validation.max_tag_chars, &self.max_tag_chars);
+        set(
+            &mut config.validation.max_batch_items,
+            &self.max_batch_items,
+        );
+        set(&mut config.legacy_api.enabled, &self.legacy_api);
+        set(&mut config.features.swagger_ui, &self.swagger_ui);
+        set(&mut config.features.write_openapi, &self.write_openapi);
+        set(&mut config.features.openapi_dir, &self.openapi_dir);
+        Ok(())
+    }
+
+    /// Whether the deprecated STAGE or DATA_DIR variables are set
+    pub fn uses_legacy_stage(&self) -> bool {
+        self.stage.is_some() || self.data_dir.is_some()
+    }
+
+    /// Where STAGE and DATA_DIR used to put the database. Combinations that can't be mapped
+    /// onto `database.path` unambiguously are refused rather than silently ignored.
+    fn legacy_database_path(&self) -> Result<Option<String>, ConfigError> {
+        let path = match (self.stage, &self.data_dir) {
+            (None | Some(Stage::Dev), None) => return Ok(None),
+            (Some(Stage::Prod), Some(data_dir)) => data_dir.join("prompts-prod.db"),
+            (Some(Stage::Prod), None) => {
+                return Err(ConfigError::Invalid(
+                    "STAGE=prod needs DATA_DIR, or unset STAGE and set DATABASE_PATH".to_string(),
+                ))
+            }
+            (_, Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "DATA_DIR is only used with STAGE=prod, set DATABASE_PATH instead".to_string(),
+                ))
+            }
+        };
+        let path = path.to_string_lossy().into_owned();
+        match &self.database_path {
+            Some(database_path) if *database_path != path => Err(ConfigError::Invalid(format!(
+                "STAGE and DATA_DIR point the database at {path} but DATABASE_PATH is {database_path}, unset one of them"
+            ))),
+            _ => Ok(Some(path)),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "server", about = "Simple prompt storage API")]
+pub struct Cli {
+    /// TOML configuration file
+    #[arg(long, short, env = "SPS_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: Overrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Run the API server, the default
+    Serve,
+    /// Apply pending schema migrations, stop the server first
+    Migrate {
+        /// Only report pending migrations
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Take a snapshot of the database, even while the server is running
+    Backup,
+    /// Replace the database with a verified snapshot, stop the server first
+    Restore { snapshot: PathBuf },
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Export every prompt with its metadata and lineage
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Ndjson)]
+        format: ExportFormat,
+        /// File to write, stdout if not set
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Replay an export into the database, yaml files are detected by extension. Stop the
+    /// server first.
+    Import {
+        file: PathBuf,
+        /// Only report what would be imported
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long, value_enum, default_value_t = IdMode::Keep)]
+        ids: IdMode,
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::Fail)]
+        on_conflict: ConflictPolicy,
+    },
+    /// Manage the git mirror, stop the server first
+    #[command(subcommand)]
+    Mirror(MirrorCommand),
+    /// Two-way sync of a directory of markdown prompt files with the database, stop the server
+    /// first
+    Sync {
+        dir: PathBuf,
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+        /// Also write prompts no file follows yet to new files
+        #[arg(long)]
+        pull_new: bool,
+        /// Only pull new prompts of this category
+        #[arg(long, requires = "pull_new")]
+        category: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the configuration and print the effective settings
+    Check,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum MirrorCommand {
+    /// Commit everything the git mirror is missing
+    Sync,
+    /// Import the history of a git repository into lineages
+    Import {
+        /// Repository to import, bare or not
+        repo: PathBuf,
+        /// Branch, tag or commit whose first-parent history is imported
+        #[arg(long, default_value = "HEAD")]
+        reference: String,
+    },
+}
+
+impl Cli {
+    pub fn config(&self) -> Result<Config, ConfigError> {
+        Config::load(self.config.as_deref(), &self.overrides)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layering() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("server.toml");
+        std::fs::write(
+            &path,
+            r#"
+[server]
+bind = "127.0.0.1:9000"
+
+[database]
+path = "/data/prompts.db"
+synchronous = "full"
+
+[cors]
+allowed_origins = ["https://example.com"]
+"#,
+        )
+        .unwrap();
+
+        // The file overrides defaults, unset keys keep them
+        let config = Config::load(Some(&path), &Overrides::default()).unwrap();
+        assert_eq!(config.server.bind, "127.0.0.1:9000");
+        assert_eq!(config.database.path, "/data/prompts.db");
+        assert_eq!(config.database.synchronous, Synchronous::Full);
+        assert_eq!(config.database.max_connections, 4);
+        assert_eq!(config.read_cache, ReadCacheConfig::default());
+        assert_eq!(
+            config.trash_retention(),
+            Some(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+
+        // Flags override the file
+        let cli = Cli::try_parse_from([
+            "server",
+            "--config",
+            path.to_str().unwrap(),
+            "config",
+            "check",
+            "--bind",
+            "127.0.0.1:9001",
+            "--cors-allowed-origins",
+            "https://a.example,https://b.example",
+            "--cache-size",
+            "-4000",
+            "--trash-retention-days",
+            "0",
+        ])
+        .unwrap();
+        assert_eq!(cli.command, Some(Command::Config(ConfigCommand::Check)));
+        let config = cli.config().unwrap();
+        assert_eq!(config.server.bind, "127.0.0.1:9001");
+        assert_eq!(
+            config.cors.allowed_origins,
+            vec!["https://a.example", "https://b.example"]
+        );
+        assert_eq!(config.database.cache_size, -4000);
+        assert_eq!(config.trash_retention(), None);
+        assert_eq!(config.database.path, "/data/prompts.db");
+
+        // The printed settings load back to the same config
+        let printed = temp_dir.path().join("printed.toml");
+        std::fs::write(&printed, config.to_toml()).unwrap();
+        assert_eq!(Config::from_file(&printed).unwrap(), config);
+    }
+
+    #[test]
+    fn test_legacy_environment() {
+        // PORT replaces the port of the bind address
+        let overrides = Overrides {
+            bind: Some("127.0.0.1:9000".to_string()),
+            port: Some(3000),
+            ..Default::default()
+        };
+        let config = Config::load(None, &overrides).unwrap();
+        assert_eq!(config.server.bind, "127.0.0.1:3000");
+
+        // STAGE=prod keeps the database in DATA_DIR
+        let overrides = Overrides {
+            stage: Some(Stage::Prod),
+            data_dir: Some(PathBuf::from("/data")),
+            ..Default::default()
+        };
+        let config = Config::load(None, &overrides).unwrap();
+        assert_eq!(config.database.path, "/data/prompts-prod.db");
+
+        for overrides in [
+            Overrides {
+                stage: Some(Stage::Prod),
+                ..Default::default()
+            },
+            Overrides {
+                data_dir: Some(PathBuf::from("/data")),
+                ..Default::default()
+            },
+            Overrides {
+                stage: Some(Stage::Prod),
+                data_dir: Some(PathBuf::from("/data")),
+                database_path: Some("/other/prompts.db".to_string()),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                Config::load(None, &overrides),
+                Err(ConfigError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("server.toml");
+
+        std::fs::write(&path, "[server]\nport = 8080\n").unwrap();
+        assert!(matches!(
+            Config::from_file(&path),
+            Err(ConfigError::Parse(..))
+        ));
+
+        let mut config = Config::default();
+        config.server.bind = "localhost".to_string();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        let mut config = Config::default();
+        config.database.max_connections = 0;
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+    }
+}
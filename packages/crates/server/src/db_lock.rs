@@ -0,0 +1,66 @@
+/*
+Keeps the offline commands that write the SQLite database directly (`sync`, `import`, `mirror`,
+`restore` and `migrate`) from running against a database a server is using. They bypass the
+server's read cache and git mirror, so their writes would leave both stale.
+
+The server holds an exclusive lock on `<database>.lock` while it runs, and the commands take the
+same lock, failing straight away if it's held. The OS drops the lock when the process exits, even
+if it crashes, so a leftover lock file doesn't block anything.
+*/
+
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+
+use fs4::fs_std::FileExt;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DbLockError {
+    #[error("Database {0} is in use by a running server, stop it first")]
+    InUse(String),
+    #[error("Failed to lock {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+}
+
+/// Held for as long as the database is in use, released on drop
+#[derive(Debug)]
+pub struct DbLock {
+    _file: File,
+}
+
+impl DbLock {
+    pub fn acquire(db_path: &str) -> Result<Self, DbLockError> {
+        let path = PathBuf::from(format!("{db_path}.lock"));
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| DbLockError::Io(path.clone(), e))?;
+        match file.try_lock_exclusive() {
+            Ok(true) => Ok(Self { _file: file }),
+            Ok(false) => Err(DbLockError::InUse(db_path.to_string())),
+            Err(e) => Err(DbLockError::Io(path, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("prompts.db");
+        let db_path = db_path.to_str().unwrap();
+
+        let lock = DbLock::acquire(db_path).unwrap();
+        assert!(matches!(
+            DbLock::acquire(db_path),
+            Err(DbLockError::InUse(_))
+        ));
+
+        drop(lock);
+        assert!(DbLock::acquire(db_path).is_ok());
+    }
+}
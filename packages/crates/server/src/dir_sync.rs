@@ -0,0 +1,667 @@
+/*
+Two-way sync between the store and a directory of markdown prompt files.
+
+Every `.md` file below the directory, hidden directories aside, is a prompt: YAML front matter
+with its id, name, description, category and tags, then the content as is. A file without an id
+is a new lineage, it's created and the id is written back into the file. A file with an id
+follows the lineage of that version.
+
+Which side changed is decided against the last sync, recorded in `.sps-sync.json` at the top of
+the directory: the hash of each file as last written or read, and the hash of the lineage head it
+was synced with. A file edited since is pushed, as a new version when its content changed or as a
+metadata update of the head otherwise. A lineage that moved on in the store is pulled, the file
+is rewritten with its head. When both changed the file is left alone and reported as a conflict,
+unless both sides ended up the same. Files the state doesn't know yet, ie a fresh copy of the
+directory, are compared with the version their id names instead, so a metadata edit made in the
+store since that version is overwritten by the file's.
+
+Deleted files are dropped from the state, their lineages aren't archived. With `pull_new`,
+lineages no file follows are written to new files.
+*/
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::api_models::CreatePromptRequest;
+use crate::cache::{
+    now_timestamp, CacheError, CacheResult, DbPrompt, DbPromptMetadata, PromptQuery, MAX_PAGE_SIZE,
+};
+use crate::fingerprint::fnv1a;
+use crate::front_matter;
+use crate::store::PromptStore;
+
+pub const STATE_FILE: &str = ".sps-sync.json";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct FileFrontMatter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+}
+
+impl FileFrontMatter {
+    fn of(prompt: &DbPrompt) -> Self {
+        let metadata = prompt.metadata.as_ref();
+        Self {
+            id: Some(prompt.id.clone()),
+            name: metadata.and_then(|m| m.name.clone()),
+            description: metadata.and_then(|m| m.description.clone()),
+            category: metadata.and_then(|m| m.category.clone()),
+            tags: metadata.and_then(|m| m.tags.clone()),
+        }
+    }
+
+    fn with_id(&self, id: &str) -> Self {
+        Self {
+            id: Some(id.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// A new version carrying this metadata, `parent` unset for a new lineage
+    fn version(&self, content: &str, parent: Option<&DbPrompt>) -> DbPrompt {
+        CreatePromptRequest {
+            content: content.to_string(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            category: self.category.clone(),
+            tags: self.tags.clone(),
+            parent: parent.map(|head| head.parent.clone()),
+            branched: Some(false),
+        }
+        .into()
+    }
+
+    fn metadata(&self, id: &str) -> DbPromptMetadata {
+        DbPromptMetadata {
+            id: id.to_string(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            category: self.category.clone(),
+            tags: self.tags.clone(),
+            updated_at: now_timestamp(),
+        }
+    }
+}
+
+/// Whether a file holds the same content and metadata as a prompt, ids aside
+fn same(front_matter: &FileFrontMatter, body: &str, prompt: &DbPrompt) -> bool {
+    body == prompt.content && front_matter.with_id(&prompt.id) == FileFrontMatter::of(prompt)
+}
+
+fn render(prompt: &DbPrompt) -> String {
+    front_matter::join(&FileFrontMatter::of(prompt), &prompt.content)
+}
+
+fn hash(text: &str) -> String {
+    format!("{:016x}", fnv1a(text.as_bytes()))
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SyncState {
+    /// Keyed by path relative to the directory
+    files: BTreeMap<String, FileState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileState {
+    /// Root of the lineage the file follows
+    parent: String,
+    file_hash: String,
+    store_hash: String,
+}
+
+impl FileState {
+    fn synced(prompt: &DbPrompt, file: &str) -> Self {
+        Self {
+            parent: prompt.parent.clone(),
+            file_hash: hash(file),
+            store_hash: hash(&render(prompt)),
+        }
+    }
+}
+
+fn read_state(path: &Path) -> CacheResult<SyncState> {
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| {
+            CacheError::InvalidRequest(format!("{} is not a sync state: {}", path.display(), e))
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SyncState::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncOptions {
+    /// Only report what would change
+    pub dry_run: bool,
+    /// Write lineages no file follows to new files
+    pub pull_new: bool,
+    /// Limits `pull_new` to a category
+    pub category: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Unchanged,
+    /// A file without an id became a new lineage
+    Created {
+        id: String,
+    },
+    /// The file's content became a new version
+    Pushed {
+        id: String,
+    },
+    /// The file's metadata was applied to the head
+    UpdatedMetadata {
+        id: String,
+    },
+    /// The file was rewritten with a newer head
+    Pulled {
+        id: String,
+    },
+    /// A lineage no file followed was written to a new file
+    Added {
+        id: String,
+    },
+    /// Both sides changed, or the file can't be matched to a lineage. Nothing was written.
+    Conflict {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub files: Vec<(PathBuf, Action)>,
+    pub applied: bool,
+}
+
+impl SyncReport {
+    pub fn conflicts(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|(_, action)| matches!(action, Action::Conflict { .. }))
+            .count()
+    }
+}
+
+/// What syncing one file did, and what to remember about it
+struct Outcome {
+    action: Action,
+    /// Lineage the file follows, if any
+    parent: Option<String>,
+    state: Option<FileState>,
+}
+
+impl Outcome {
+    fn conflict(reason: String, parent: Option<String>, previous: Option<&FileState>) -> Self {
+        Self {
+            action: Action::Conflict { reason },
+            parent,
+            // Keeping the previous state keeps both sides changed until the conflict is resolved
+            state: previous.cloned(),
+        }
+    }
+
+    fn synced(action: Action, prompt: &DbPrompt, file: &str) -> Self {
+        Self {
+            action,
+            parent: Some(prompt.parent.clone()),
+            state: Some(FileState::synced(prompt, file)),
+        }
+    }
+}
+
+/// Syncs every markdown file below `dir` with the store
+pub fn sync(store: &dyn PromptStore, dir: &Path, options: &SyncOptions) -> CacheResult<SyncReport> {
+    let state_path = dir.join(STATE_FILE);
+    let previous = read_state(&state_path)?;
+    let mut state = SyncState::default();
+    let mut report = SyncReport {
+        applied: !options.dry_run,
+        ..SyncReport::default()
+    };
+
+    let mut followed = HashSet::new();
+    for path in markdown_files(dir)? {
+        let key = relative(dir, &path);
+        let text = fs::read_to_string(&path)?;
+        let outcome = sync_file(
+            store,
+            &path,
+            &text,
+            previous.files.get(&key),
+            options.dry_run,
+        )?;
+        followed.extend(outcome.parent);
+        if let Some(file_state) = outcome.state {
+            state.files.insert(key, file_state);
+        }
+        report.files.push((path, outcome.action));
+    }
+
+    if options.pull_new {
+        let mut cursor = None;
+        loop {
+            let page = store.get_prompt_heads(PromptQuery {
+                category: options.category.clone(),
+                cursor,
+                limit: MAX_PAGE_SIZE,
+                ..PromptQuery::default()
+            })?;
+            for head in page.items {
+                let prompt = head.prompt;
+                if !followed.insert(prompt.parent.clone()) {
+                    continue;
+                }
+                let path = new_file(dir, &prompt);
+                let text = render(&prompt);
+                if !options.dry_run {
+                    if let Some(directory) = path.parent() {
+                        fs::create_dir_all(directory)?;
+                    }
+                    fs::write(&path, &text)?;
+                }
+                state
+                    .files
+                    .insert(relative(dir, &path), FileState::synced(&prompt, &text));
+                report.files.push((path, Action::Added { id: prompt.id }));
+            }
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+    }
+
+    if !options.dry_run {
+        let state = serde_json::to_string_pretty(&state)
+            .map_err(|e| CacheError::UnhandledError(e.to_string()))?;
+        fs::write(&state_path, state + "\n")?;
+        info!(
+            "Synced {} files in {}, {} conflicts",
+            report.files.len(),
+            dir.display(),
+            report.conflicts()
+        );
+    }
+    Ok(report)
+}
+
+fn sync_file(
+    store: &dyn PromptStore,
+    path: &Path,
+    text: &str,
+    previous: Option<&FileState>,
+    dry_run: bool,
+) -> CacheResult<Outcome> {
+    let (front_matter, body) = front_matter::split::<FileFrontMatter>(text);
+    let front_matter = front_matter.unwrap_or_default();
+
+    let Some(id) = &front_matter.id else {
+        let prompt = front_matter.version(body, None);
+        let action = Action::Created {
+            id: prompt.id.clone(),
+        };
+        if dry_run {
+            return Ok(Outcome::synced(action, &prompt, text));
+        }
+        let prompt = store.insert_prompt(prompt)?;
+        let text = front_matter::join(&front_matter.with_id(&prompt.id), body);
+        fs::write(path, &text)?;
+        return Ok(Outcome::synced(action, &prompt, &text));
+    };
+
+    let Some(base) = store.get_prompt(id, Some(true))? else {
+        return Ok(Outcome::conflict(
+            format!("prompt {} is not in the store", id),
+            None,
+            previous,
+        ));
+    };
+    let parent = base.parent.clone();
+    let Some(head) = lineage_head(store, &parent)? else {
+        return Ok(Outcome::conflict(
+            format!("every version of lineage {} is archived", parent),
+            Some(parent),
+            previous,
+        ));
+    };
+
+    let (local_changed, remote_changed) = match previous.filter(|p| p.parent == parent) {
+        Some(previous) => (
+            previous.file_hash != hash(text),
+            previous.store_hash != hash(&render(&head)),
+        ),
+        None => (!same(&front_matter, body, &base), head.id != base.id),
+    };
+
+    match (local_changed, remote_changed) {
+        (false, false) => Ok(Outcome::synced(Action::Unchanged, &head, text)),
+        (true, false) => push(store, path, &front_matter, body, &head, dry_run),
+        (true, true) if !same(&front_matter, body, &head) => Ok(Outcome::conflict(
+            format!(
+                "changed both here and in the store, whose newest version is {}",
+                head.id
+            ),
+            Some(parent),
+            previous,
+        )),
+        // Both sides made the same change
+        (true, true) if *id == head.id => Ok(Outcome::synced(Action::Unchanged, &head, text)),
+        _ => {
+            let text = render(&head);
+            if !dry_run {
+                fs::write(path, &text)?;
+            }
+            Ok(Outcome::synced(
+                Action::Pulled {
+                    id: head.id.clone(),
+                },
+                &head,
+                &text,
+            ))
+        }
+    }
+}
+
+fn push(
+    store: &dyn PromptStore,
+    path: &Path,
+    front_matter: &FileFrontMatter,
+    body: &str,
+    head: &DbPrompt,
+    dry_run: bool,
+) -> CacheResult<Outcome> {
+    if same(front_matter, body, head) {
+        let text = front_matter::join(&front_matter.with_id(&head.id), body);
+        return Ok(Outcome::synced(Action::Unchanged, head, &text));
+    }
+
+    let (action, prompt) = if body == head.content {
+        let action = Action::UpdatedMetadata {
+            id: head.id.clone(),
+        };
+        if dry_run {
+            (action, head.clone())
+        } else {
+            match store.update_prompt_metadata(&head.id, front_matter.metadata(&head.id)) {
+                Ok(_) => (
+                    action,
+                    store
+                        .get_prompt(&head.id, Some(true))?
+                        .ok_or(CacheError::NotFound)?,
+                ),
+                // The head never had metadata, a new version carries it instead
+                Err(CacheError::NotFound) => new_version(store, front_matter, body, head)?,
+                Err(e) => return Err(e),
+            }
+        }
+    } else if dry_run {
+        let prompt = front_matter.version(body, Some(head));
+        (
+            Action::Pushed {
+                id: prompt.id.clone(),
+            },
+            prompt,
+        )
+    } else {
+        new_version(store, front_matter, body, head)?
+    };
+
+    let text = front_matter::join(&front_matter.with_id(&prompt.id), body);
+    if !dry_run {
+        fs::write(path, &text)?;
+    }
+    Ok(Outcome::synced(action, &prompt, &text))
+}
+
+fn new_version(
+    store: &dyn PromptStore,
+    front_matter: &FileFrontMatter,
+    body: &str,
+    head: &DbPrompt,
+) -> CacheResult<(Action, DbPrompt)> {
+    let prompt = store.insert_prompt(front_matter.version(body, Some(head)))?;
+    Ok((
+        Action::Pushed {
+            id: prompt.id.clone(),
+        },
+        prompt,
+    ))
+}
+
+/// Highest non-archived version of a lineage
+fn lineage_head(store: &dyn PromptStore, parent: &str) -> CacheResult<Option<DbPrompt>> {
+    let page = store.get_prompt_heads(PromptQuery {
+        parent: Some(parent.to_string()),
+        limit: 1,
+        ..PromptQuery::default()
+    })?;
+    Ok(page.items.into_iter().next().map(|head| head.prompt))
+}
+
+/// Every markdown file below `dir`, skipping hidden files and directories, in path order
+fn markdown_files(dir: &Path) -> CacheResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+            {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "md") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn relative(dir: &Path, path: &Path) -> String {
+    let path = path.strip_prefix(dir).unwrap_or(path);
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A free path for a lineage pulled for the first time, named after the prompt when it has a name
+fn new_file(dir: &Path, prompt: &DbPrompt) -> PathBuf {
+    let slug = prompt
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.name.as_deref())
+        .map(|name| {
+            name.to_lowercase()
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .filter(|slug| !slug.is_empty());
+    match slug {
+        Some(slug) if !dir.join(format!("{slug}.md")).exists() => dir.join(format!("{slug}.md")),
+        Some(slug) => dir.join(format!("{slug}-{}.md", prompt.parent)),
+        None => dir.join(format!("{}.md", prompt.parent)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+
+    fn actions(report: &SyncReport) -> Vec<(String, &Action)> {
+        report
+            .files
+            .iter()
+            .map(|(path, action)| {
+                (
+                    path.file_name().unwrap().to_string_lossy().to_string(),
+                    action,
+                )
+            })
+            .collect()
+    }
+
+    fn read(path: &Path) -> (FileFrontMatter, String) {
+        let text = fs::read_to_string(path).unwrap();
+        let (front_matter, body) = front_matter::split::<FileFrontMatter>(&text);
+        (front_matter.unwrap(), body.to_string())
+    }
+
+    #[test]
+    fn test_push_and_pull() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let store = MemoryStore::new();
+        let options = SyncOptions::default();
+        let file = dir.join("greeting.md");
+        fs::write(&file, "---\nname: Greeting\ntags:\n- a\n---\nHello\n").unwrap();
+        fs::create_dir(dir.join(".git")).unwrap();
+        fs::write(dir.join(".git").join("ignored.md"), "Ignored").unwrap();
+
+        // A new file becomes a lineage and gets its id
+        let report = sync(&store, dir, &options).unwrap();
+        let Action::Created { id: root } = report.files[0].1.clone() else {
+            panic!("expected a new lineage, got {:?}", report.files);
+        };
+        assert_eq!(report.files.len(), 1);
+        let (front_matter, body) = read(&file);
+        assert_eq!(front_matter.id.as_deref(), Some(root.as_str()));
+        assert_eq!(body, "Hello\n");
+        let stored = store.get_prompt(&root, Some(true)).unwrap().unwrap();
+        assert_eq!(stored.metadata.unwrap().tags, Some(vec!["a".to_string()]));
+
+        // Nothing changed
+        let report = sync(&store, dir, &options).unwrap();
+        assert_eq!(actions(&report)[0].1, &Action::Unchanged);
+
+        // An edited body becomes a new version of the lineage
+        let text = fs::read_to_string(&file).unwrap();
+        fs::write(&file, text.replace("Hello", "Hello there")).unwrap();
+        let report = sync(&store, dir, &options).unwrap();
+        let Action::Pushed { id: pushed } = report.files[0].1.clone() else {
+            panic!("expected a new version, got {:?}", report.files);
+        };
+        let version = store.get_prompt(&pushed, Some(true)).unwrap().unwrap();
+        assert_eq!(
+            (version.parent.as_str(), version.version),
+            (root.as_str(), 2)
+        );
+        assert_eq!(read(&file).0.id, Some(pushed.clone()));
+
+        // A metadata edit in the store is written back
+        store
+            .update_prompt_metadata(
+                &pushed,
+                FileFrontMatter {
+                    name: Some("Renamed".to_string()),
+                    ..FileFrontMatter::default()
+                }
+                .metadata(&pushed),
+            )
+            .unwrap();
+        let report = sync(&store, dir, &options).unwrap();
+        assert_eq!(
+            actions(&report)[0].1,
+            &Action::Pulled { id: pushed.clone() }
+        );
+        let (front_matter, body) = read(&file);
+        assert_eq!(front_matter.name.as_deref(), Some("Renamed"));
+        assert_eq!(body, "Hello there\n");
+
+        // Lineages no file follows are only written when asked for
+        let other = store
+            .insert_prompt(
+                FileFrontMatter {
+                    name: Some("Other prompt".to_string()),
+                    ..FileFrontMatter::default()
+                }
+                .version("Other", None),
+            )
+            .unwrap();
+        let pull_new = SyncOptions {
+            pull_new: true,
+            ..SyncOptions::default()
+        };
+        let report = sync(
+            &store,
+            dir,
+            &SyncOptions {
+                dry_run: true,
+                ..pull_new.clone()
+            },
+        )
+        .unwrap();
+        assert!(!report.applied);
+        assert!(!dir.join("other-prompt.md").exists());
+        let report = sync(&store, dir, &pull_new).unwrap();
+        assert_eq!(
+            actions(&report)[1],
+            (
+                "other-prompt.md".to_string(),
+                &Action::Added { id: other.id }
+            )
+        );
+        assert_eq!(read(&dir.join("other-prompt.md")).1, "Other");
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let store = MemoryStore::new();
+        let options = SyncOptions::default();
+        let file = dir.join("prompt.md");
+        fs::write(&file, "Original").unwrap();
+        sync(&store, dir, &options).unwrap();
+        let (front_matter, _) = read(&file);
+        let head = store
+            .get_prompt(front_matter.id.as_deref().unwrap(), None)
+            .unwrap()
+            .unwrap();
+
+        // Both sides changed differently, the file is left alone until it's resolved
+        store
+            .insert_prompt(front_matter.version("Remote edit", Some(&head)))
+            .unwrap();
+        let local = front_matter::join(&front_matter, "Local edit");
+        fs::write(&file, &local).unwrap();
+        for _ in 0..2 {
+            let report = sync(&store, dir, &options).unwrap();
+            assert_eq!(report.conflicts(), 1);
+            assert_eq!(fs::read_to_string(&file).unwrap(), local);
+        }
+
+        // Taking the store's version resolves it
+        fs::write(&file, front_matter::join(&front_matter, "Remote edit")).unwrap();
+        let report = sync(&store, dir, &options).unwrap();
+        assert!(matches!(report.files[0].1, Action::Pulled { .. }));
+        assert_eq!(read(&file).1, "Remote edit");
+
+        // An id the store doesn't know is a conflict as well
+        fs::write(dir.join("unknown.md"), "---\nid: missing\n---\nText").unwrap();
+        let report = sync(&store, dir, &options).unwrap();
+        assert_eq!(report.conflicts(), 1);
+    }
+}
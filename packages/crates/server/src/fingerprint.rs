@@ -0,0 +1,163 @@
+/*
+MinHash fingerprints for near-duplicate detection.
+https://en.wikipedia.org/wiki/MinHash
+
+Content is normalized and split into word shingles. Each signature slot keeps the minimum of one
+hash permutation over all shingles, so the share of equal slots between two signatures estimates
+the Jaccard similarity of their shingle sets. Signatures are split into bands for locality
+sensitive hashing: prompts sharing any band bucket are candidates worth comparing.
+
+Signatures are persisted, so hashing must stay stable across builds and Rust versions.
+*/
+
+use std::collections::{HashMap, HashSet};
+
+pub const SIGNATURE_LEN: usize = 64;
+pub const BANDS: usize = 16;
+const ROWS_PER_BAND: usize = SIGNATURE_LEN / BANDS;
+const SHINGLE_WORDS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinHash(Vec<u64>);
+
+/// FNV-1a, stable regardless of platform or compiler version
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// splitmix64 finalizer, used to derive independent permutations from one base hash
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn shingles(content: &str) -> HashSet<u64> {
+    let words: Vec<String> = content
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    match words.len() {
+        0 => HashSet::new(),
+        n if n < SHINGLE_WORDS => HashSet::from([fnv1a(words.join(" ").as_bytes())]),
+        _ => words
+            .windows(SHINGLE_WORDS)
+            .map(|window| fnv1a(window.join(" ").as_bytes()))
+            .collect(),
+    }
+}
+
+impl MinHash {
+    pub fn from_content(content: &str) -> Self {
+        let shingles = shingles(content);
+        Self(
+            (0..SIGNATURE_LEN as u64)
+                .map(|seed| {
+                    let seed = mix(seed.wrapping_add(0x9e3779b97f4a7c15));
+                    shingles
+                        .iter()
+                        .map(|shingle| mix(shingle ^ seed))
+                        .min()
+                        .unwrap_or(u64::MAX)
+                })
+                .collect(),
+        )
+    }
+
+    /// Estimated Jaccard similarity between the shingle sets, 0.0 to 1.0
+    pub fn similarity(&self, other: &MinHash) -> f64 {
+        let matching = self
+            .0
+            .iter()
+            .zip(&other.0)
+            .filter(|(a, b)| a == b && **a != u64::MAX)
+            .count();
+        matching as f64 / SIGNATURE_LEN as f64
+    }
+
+    /// One bucket per band. Prompts sharing a bucket in any band are similarity candidates.
+    pub fn band_buckets(&self) -> Vec<i64> {
+        self.0
+            .chunks(ROWS_PER_BAND)
+            .map(|band| {
+                let bytes: Vec<u8> = band.iter().flat_map(|hash| hash.to_le_bytes()).collect();
+                fnv1a(&bytes) as i64
+            })
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.iter().flat_map(|hash| hash.to_le_bytes()).collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == SIGNATURE_LEN * 8).then(|| {
+            Self(
+                bytes
+                    .chunks_exact(8)
+                    .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+                    .collect(),
+            )
+        })
+    }
+}
+
+/// Groups ids linked by candidate pairs that are at least `threshold` similar. Each member
+/// carries its highest similarity to another member of its group. Largest groups come first,
+/// they're the most worth consolidating.
+pub fn group_duplicates(
+    candidates: &[(String, String)],
+    signatures: &HashMap<String, MinHash>,
+    threshold: f64,
+) -> Vec<Vec<(String, f64)>> {
+    fn find(roots: &mut HashMap<String, String>, id: &str) -> String {
+        let parent = roots.get(id).cloned().unwrap_or_else(|| id.to_string());
+        match parent == id {
+            true => parent,
+            false => {
+                let root = find(roots, &parent);
+                roots.insert(id.to_string(), root.clone());
+                root
+            }
+        }
+    }
+
+    // Union-find over the pairs that clear the threshold
+    let mut roots: HashMap<String, String> = HashMap::new();
+    let mut best: HashMap<String, f64> = HashMap::new();
+    for (a, b) in candidates {
+        let similarity = match (signatures.get(a), signatures.get(b)) {
+            (Some(sig_a), Some(sig_b)) => sig_a.similarity(sig_b),
+            _ => continue,
+        };
+        if similarity < threshold {
+            continue;
+        }
+        for id in [a, b] {
+            let entry = best.entry(id.clone()).or_insert(0.0);
+            *entry = entry.max(similarity);
+        }
+        let (root_a, root_b) = (find(&mut roots, a), find(&mut roots, b));
+        roots.insert(root_a, root_b);
+    }
+
+    let mut groups: HashMap<String, Vec<(String, f64)>> = HashMap::new();
+    for (id, similarity) in best {
+        let root = find(&mut roots, &id);
+        groups.entry(root).or_default().push((id, similarity));
+    }
+
+    let mut groups: Vec<Vec<(String, f64)>> = groups
+        .into_values()
+        .map(|mut group| {
+            group.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            group
+        })
+        .collect();
+    groups.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[0].0.cmp(&b[0].0)));
+    groups
+}
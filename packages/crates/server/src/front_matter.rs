@@ -0,0 +1,27 @@
+/*
+YAML front matter: a `---` line, a YAML document, another `---` line, then the body as is.
+Used by the git mirror and directory sync, both write prompts as markdown files.
+*/
+
+use serde::{de::DeserializeOwned, Serialize};
+
+const DELIMITER: &str = "---\n";
+
+/// Splits a file into its front matter and body. Files without front matter, or with front
+/// matter that doesn't deserialize into `T`, are all body.
+pub fn split<T: DeserializeOwned>(text: &str) -> (Option<T>, &str) {
+    let front_matter = text.strip_prefix(DELIMITER).and_then(|rest| {
+        let end = rest.find(&format!("\n{DELIMITER}"))? + 1;
+        let front_matter = serde_yaml::from_str(&rest[..end]).ok()?;
+        Some((front_matter, &rest[end + DELIMITER.len()..]))
+    });
+    match front_matter {
+        Some((front_matter, body)) => (Some(front_matter), body),
+        None => (None, text),
+    }
+}
+
+pub fn join<T: Serialize>(front_matter: &T, body: &str) -> String {
+    let yaml = serde_yaml::to_string(front_matter).expect("Failed to serialize front matter");
+    format!("{DELIMITER}{yaml}{DELIMITER}{body}")
+}
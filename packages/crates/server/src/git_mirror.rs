@@ -0,0 +1,809 @@
+/*
+Mirror of every lineage into a bare git repository, so prompts can be browsed with `git log` and
+`git blame` and an offline audit copy is kept next to the database.
+
+Each lineage is a markdown file at the top of the repository named after its root id. Every
+version is a commit on the main branch replacing the file with the version's content, with its
+metadata as YAML front matter. Metadata edits of the newest version are commits as well. Commits
+are dated when the version was created. Versions imported from git keep the author and message of
+the commit they came from, anything else is committed by the configured author.
+
+The mirror catches up from the store instead of replaying events: the front matter of each file
+records the newest version mirrored, every version created after it is committed in order. That
+runs on startup and after each write, so writes the mirror missed are picked up later. Archiving
+and purging aren't mirrored, the history is kept.
+
+Importing walks the first-parent history of a repository from its oldest commit and turns every
+changed text file into a version, recording the commit's author and message. Files with front matter keep their ids and lineage, any other
+file becomes a lineage of its own keyed by path, its versions named after the commit and path so
+importing again skips them.
+*/
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use git2::{Commit, Delta, ErrorCode, FileMode, Oid, Repository, Signature, Sort, Time, Tree};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::cache::{
+    CacheError, CacheResult, DbCategoryCount, DbCommit, DbMetadataPatch, DbPrompt, DbPromptHead,
+    DbPromptMetadata, DbSimilarPrompt, PromptPage, PromptQuery,
+};
+use crate::front_matter;
+use crate::store::{all_versions, PromptStore};
+
+const BRANCH: &str = "refs/heads/main";
+
+impl From<git2::Error> for CacheError {
+    fn from(e: git2::Error) -> Self {
+        CacheError::UnhandledError(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct FrontMatter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    branched: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+}
+
+impl FrontMatter {
+    fn of(prompt: &DbPrompt) -> Self {
+        let metadata = prompt.metadata.clone();
+        let metadata = metadata.as_ref();
+        Self {
+            id: Some(prompt.id.clone()),
+            parent: Some(prompt.parent.clone()),
+            version: Some(prompt.version),
+            branched: prompt.branched.filter(|branched| *branched),
+            created_at: Some(prompt.created_at),
+            name: metadata.and_then(|m| m.name.clone()),
+            description: metadata.and_then(|m| m.description.clone()),
+            category: metadata.and_then(|m| m.category.clone()),
+            tags: metadata.and_then(|m| m.tags.clone()),
+        }
+    }
+
+    fn metadata(&self, id: &str, updated_at: i64) -> Option<DbPromptMetadata> {
+        if self.name.is_none()
+            && self.description.is_none()
+            && self.category.is_none()
+            && self.tags.is_none()
+        {
+            return None;
+        }
+        Some(DbPromptMetadata {
+            id: id.to_string(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            category: self.category.clone(),
+            tags: self.tags.clone(),
+            updated_at,
+        })
+    }
+}
+
+/// The lineage file for a version: front matter, then the content as is
+fn render(prompt: &DbPrompt) -> String {
+    front_matter::join(&FrontMatter::of(prompt), &prompt.content)
+}
+
+fn parse(text: &str) -> (Option<FrontMatter>, &str) {
+    front_matter::split(text)
+}
+
+fn file_name(parent: &str) -> String {
+    format!("{parent}.md")
+}
+
+fn head(repo: &Repository) -> CacheResult<Option<Commit<'_>>> {
+    match repo.find_reference(BRANCH) {
+        Ok(reference) => Ok(Some(reference.peel_to_commit()?)),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+enum Change {
+    Version(DbPrompt),
+    Metadata(DbPrompt),
+}
+
+impl Change {
+    fn prompt(&self) -> &DbPrompt {
+        match self {
+            Self::Version(prompt) | Self::Metadata(prompt) => prompt,
+        }
+    }
+
+    /// Unix timestamp the commit is dated at
+    fn time(&self) -> i64 {
+        match self {
+            Self::Version(prompt) => prompt.created_at,
+            Self::Metadata(prompt) => prompt
+                .metadata
+                .as_ref()
+                .map_or(prompt.created_at, |metadata| metadata.updated_at),
+        }
+    }
+
+    /// Commit order. Ids are random, so changes made within the same second are ordered by
+    /// version first.
+    fn order(&self) -> (i64, i32, &str) {
+        let prompt = self.prompt();
+        (self.time(), prompt.version, &prompt.id)
+    }
+
+    /// The author of the commit a version was imported from, or else `default`
+    fn author(&self, default: &Author) -> Author {
+        match self {
+            Self::Version(DbPrompt {
+                commit: Some(commit),
+                ..
+            }) => Author {
+                name: commit.author_name.clone(),
+                email: commit.author_email.clone(),
+            },
+            _ => default.clone(),
+        }
+    }
+
+    fn message(&self) -> String {
+        let prompt = self.prompt();
+        let name = prompt
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.name.as_deref())
+            .unwrap_or(&prompt.id);
+        match self {
+            Self::Version(DbPrompt {
+                commit: Some(commit),
+                ..
+            }) => commit.message.clone(),
+            Self::Version(_) => format!(
+                "Add version {} of {}\n\nId: {}",
+                prompt.version, name, prompt.id
+            ),
+            Self::Metadata(_) => format!("Update metadata of {}\n\nId: {}", name, prompt.id),
+        }
+    }
+}
+
+/// What a lineage file is missing: the versions created after the one it holds, or else an
+/// edit of that version's metadata
+fn lineage_changes(versions: &[DbPrompt], mirrored: Option<&str>) -> Vec<Change> {
+    let position = mirrored
+        .and_then(|text| parse(text).0)
+        .and_then(|front_matter| {
+            Some((
+                front_matter.created_at?,
+                front_matter.version?,
+                front_matter.id?,
+            ))
+        });
+    let after_position = |prompt: &&DbPrompt| {
+        position.as_ref().is_none_or(|(created_at, version, id)| {
+            (prompt.created_at, prompt.version, prompt.id.as_str()).cmp(&(
+                *created_at,
+                *version,
+                id.as_str(),
+            )) == Ordering::Greater
+        })
+    };
+    let pending: Vec<Change> = versions
+        .iter()
+        .filter(after_position)
+        .cloned()
+        .map(Change::Version)
+        .collect();
+    if !pending.is_empty() {
+        return pending;
+    }
+
+    position
+        .and_then(|(_, _, id)| versions.iter().find(|prompt| prompt.id == id))
+        .filter(|prompt| Some(render(prompt).as_str()) != mirrored)
+        .map(|prompt| Change::Metadata(prompt.clone()))
+        .into_iter()
+        .collect()
+}
+
+pub struct GitMirror {
+    repo: Mutex<Repository>,
+    author: Author,
+}
+
+impl GitMirror {
+    /// Opens the bare repository at `path`, creating it if it doesn't exist
+    pub fn open(path: &Path, author: Author) -> CacheResult<Self> {
+        let repo = if path.exists() {
+            Repository::open_bare(path)?
+        } else {
+            info!("Creating git mirror at {}", path.display());
+            Repository::init_bare(path)?
+        };
+        // Attaches HEAD to the mirror's branch even before its first commit
+        repo.set_head(BRANCH)?;
+        Ok(Self {
+            repo: Mutex::new(repo),
+            author,
+        })
+    }
+
+    fn repo(&self) -> CacheResult<MutexGuard<'_, Repository>> {
+        self.repo
+            .lock()
+            .map_err(|e| CacheError::UnhandledError(e.to_string()))
+    }
+
+    /// Commits every version and metadata edit the mirror is missing. Returns the number of
+    /// commits made.
+    pub fn sync(&self, store: &dyn PromptStore) -> CacheResult<usize> {
+        let repo = self.repo()?;
+        let mut lineages: BTreeMap<String, Vec<DbPrompt>> = BTreeMap::new();
+        for prompt in all_versions(store, None)? {
+            lineages
+                .entry(prompt.parent.clone())
+                .or_default()
+                .push(prompt);
+        }
+        self.commit_changes(&repo, &lineages)
+    }
+
+    /// `sync` for the lineage rooted at `parent` only
+    pub fn sync_lineage(&self, store: &dyn PromptStore, parent: &str) -> CacheResult<usize> {
+        let repo = self.repo()?;
+        let versions = all_versions(store, Some(parent))?;
+        self.commit_changes(&repo, &BTreeMap::from([(parent.to_string(), versions)]))
+    }
+
+    fn commit_changes(
+        &self,
+        repo: &Repository,
+        lineages: &BTreeMap<String, Vec<DbPrompt>>,
+    ) -> CacheResult<usize> {
+        let tree = head(repo)?.map(|commit| commit.tree()).transpose()?;
+        let mut changes = Vec::new();
+        for (parent, versions) in lineages {
+            let mirrored = tree
+                .as_ref()
+                .and_then(|tree| tree.get_name(&file_name(parent)))
+                .map(|entry| repo.find_blob(entry.id()))
+                .transpose()?
+                .map(|blob| String::from_utf8_lossy(blob.content()).into_owned());
+            changes.extend(lineage_changes(versions, mirrored.as_deref()));
+        }
+        changes.sort_by(|a, b| a.order().cmp(&b.order()));
+
+        for change in &changes {
+            let prompt = change.prompt();
+            self.commit_file(
+                repo,
+                &file_name(&prompt.parent),
+                &render(prompt),
+                &change.author(&self.author),
+                &change.message(),
+                change.time(),
+            )?;
+        }
+        if !changes.is_empty() {
+            debug!("Mirrored {} changes to git", changes.len());
+        }
+        Ok(changes.len())
+    }
+
+    /// Commits `contents` as the file `name` on top of the branch
+    fn commit_file(
+        &self,
+        repo: &Repository,
+        name: &str,
+        contents: &str,
+        author: &Author,
+        message: &str,
+        timestamp: i64,
+    ) -> CacheResult<Oid> {
+        let parent = head(repo)?;
+        let base: Option<Tree> = parent.as_ref().map(Commit::tree).transpose()?;
+        let mut builder = repo.treebuilder(base.as_ref())?;
+        builder.insert(name, repo.blob(contents.as_bytes())?, FileMode::Blob.into())?;
+        let tree = repo.find_tree(builder.write()?)?;
+
+        let signature = Signature::new(&author.name, &author.email, &Time::new(timestamp, 0))?;
+        let parents: Vec<&Commit> = parent.iter().collect();
+        Ok(repo.commit(
+            Some(BRANCH),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )?)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub commits: usize,
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+}
+
+struct ImportedLineage {
+    parent: String,
+    content: String,
+}
+
+/// Replays the first-parent history of `reference` in the repository at `path` into `store`.
+/// Versions already in the store are skipped, so importing twice is harmless.
+pub fn import(path: &Path, reference: &str, store: &dyn PromptStore) -> CacheResult<ImportSummary> {
+    let repo = Repository::open(path)?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(repo.revparse_single(reference)?.peel_to_commit()?.id())?;
+    revwalk.simplify_first_parent()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+    let mut summary = ImportSummary::default();
+    let mut lineages: HashMap<String, ImportedLineage> = HashMap::new();
+    let mut imported: HashSet<String> = HashSet::new();
+    for oid in revwalk {
+        let oid = oid?;
+        let commit = repo.find_commit(oid)?;
+        summary.commits += 1;
+        let time = commit.time().seconds();
+        let author = commit.author();
+        let origin = DbCommit {
+            author_name: String::from_utf8_lossy(author.name_bytes()).into_owned(),
+            author_email: String::from_utf8_lossy(author.email_bytes()).into_owned(),
+            message: String::from_utf8_lossy(commit.message_bytes()).into_owned(),
+        };
+        let previous = commit.parents().next().map(|p| p.tree()).transpose()?;
+        let diff = repo.diff_tree_to_tree(previous.as_ref(), Some(&commit.tree()?), None)?;
+
+        for delta in diff.deltas() {
+            let file = delta.new_file();
+            if !matches!(delta.status(), Delta::Added | Delta::Modified)
+                || file.mode() != FileMode::Blob
+            {
+                continue;
+            }
+            let Some(path) = file.path() else {
+                continue;
+            };
+            let blob = repo.find_blob(file.id())?;
+            let Ok(text) = std::str::from_utf8(blob.content()) else {
+                debug!("Skipping binary file {}", path.display());
+                summary.skipped += 1;
+                continue;
+            };
+
+            let (front_matter, content) = parse(text);
+            let key = front_matter
+                .as_ref()
+                .and_then(|front_matter| front_matter.parent.clone())
+                .unwrap_or_else(|| path.to_string_lossy().into_owned());
+            let front_matter = front_matter.unwrap_or_else(|| FrontMatter {
+                name: path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned()),
+                ..FrontMatter::default()
+            });
+            let lineage = lineages.get(&key);
+
+            if let Some(id) = &front_matter.id {
+                // A later commit of a version imported in this run edits its metadata
+                if imported.contains(id) {
+                    match front_matter
+                        .metadata(id, time)
+                        .map(|metadata| store.update_prompt_metadata(id, metadata))
+                    {
+                        Some(Ok(_)) => summary.updated += 1,
+                        Some(Err(CacheError::NotFound)) | None => summary.skipped += 1,
+                        Some(Err(e)) => return Err(e),
+                    }
+                    continue;
+                }
+            } else if lineage.is_some_and(|lineage| lineage.content == content) {
+                summary.skipped += 1;
+                continue;
+            }
+
+            // Without an id in the front matter the commit and path name the version, so
+            // importing again finds the versions of plain files too
+            let id = front_matter.id.clone().unwrap_or_else(|| {
+                let name = format!("{}:{}", oid, path.display());
+                Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes()).to_string()
+            });
+            if let Some(existing) = store.get_prompt(&id, None)? {
+                summary.skipped += 1;
+                lineages.insert(
+                    key,
+                    ImportedLineage {
+                        parent: existing.parent,
+                        content: existing.content,
+                    },
+                );
+                continue;
+            }
+            let parent = front_matter
+                .parent
+                .clone()
+                .or_else(|| lineage.map(|lineage| lineage.parent.clone()))
+                .unwrap_or_else(|| id.clone());
+            store.insert_prompt(DbPrompt {
+                id: id.clone(),
+                version: front_matter.version.unwrap_or(1),
+                content: content.to_string(),
+                parent: parent.clone(),
+                branched: front_matter.branched,
+                archived: Some(false),
+                created_at: front_matter.created_at.unwrap_or(time),
+                metadata: front_matter.metadata(&id, time),
+                commit: Some(origin.clone()),
+            })?;
+            summary.created += 1;
+            lineages.insert(
+                key,
+                ImportedLineage {
+                    parent,
+                    content: content.to_string(),
+                },
+            );
+            imported.insert(id);
+        }
+    }
+    info!(
+        "Imported {} versions from {} commits of {}",
+        summary.created,
+        summary.commits,
+        path.display()
+    );
+    Ok(summary)
+}
+
+/// Mirrors writes into git once they've reached the inner store. A failed mirror write is
+/// logged rather than failing the request, the next sync catches up.
+pub struct MirroredStore {
+    inner: Arc<dyn PromptStore>,
+    mirror: Arc<GitMirror>,
+}
+
+impl MirroredStore {
+    pub fn new(inner: Arc<dyn PromptStore>, mirror: Arc<GitMirror>) -> Self {
+        Self { inner, mirror }
+    }
+
+    fn mirror_lineage(&self, parent: &str) {
+        if let Err(e) = self.mirror.sync_lineage(self.inner.as_ref(), parent) {
+            error!("Failed to mirror lineage {} to git: {:?}", parent, e);
+        }
+    }
+}
+
+impl PromptStore for MirroredStore {
+    fn insert_prompt(&self, prompt: DbPrompt) -> CacheResult<DbPrompt> {
+        let prompt = self.inner.insert_prompt(prompt)?;
+        self.mirror_lineage(&prompt.parent);
+        Ok(prompt)
+    }
+
+    fn insert_prompts(&self, prompts: Vec<DbPrompt>) -> CacheResult<Vec<DbPrompt>> {
+        let prompts = self.inner.insert_prompts(prompts)?;
+        let parents: BTreeSet<&str> = prompts.iter().map(|p| p.parent.as_str()).collect();
+        for parent in parents {
+            self.mirror_lineage(parent);
+        }
+        Ok(prompts)
+    }
+
+    fn get_prompt(&self, id: &str, metadata: Option<bool>) -> CacheResult<Option<DbPrompt>> {
+        self.inner.get_prompt(id, metadata)
+    }
+
+    fn get_prompt_content(&self, id: &str) -> CacheResult<String> {
+        self.inner.get_prompt_content(id)
+    }
+
+    fn get_prompt_content_latest_version(&self, id: &str) -> CacheResult<String> {
+        self.inner.get_prompt_content_latest_version(id)
+    }
+
+    fn get_prompts(&self, query: PromptQuery) -> CacheResult<PromptPage> {
+        self.inner.get_prompts(query)
+    }
+
+    fn get_prompt_heads(&self, query: PromptQuery) -> CacheResult<PromptPage<DbPromptHead>> {
+        self.inner.get_prompt_heads(query)
+    }
+
+    fn update_prompt_metadata(&self, id: &str, metadata: DbPromptMetadata) -> CacheResult<String> {
+        let updated = self.inner.update_prompt_metadata(id, metadata)?;
+        if let Some(prompt) = self.inner.get_prompt(id, None)? {
+            self.mirror_lineage(&prompt.parent);
+        }
+        Ok(updated)
+    }
+
+    fn patch_prompt_metadata(
+        &self,
+        id: &str,
+        patch: DbMetadataPatch,
+    ) -> CacheResult<DbPromptMetadata> {
+        let patched = self.inner.patch_prompt_metadata(id, patch)?;
+        if let Some(prompt) = self.inner.get_prompt(id, None)? {
+            self.mirror_lineage(&prompt.parent);
+        }
+        Ok(patched)
+    }
+
+    fn delete_prompt(&self, id: &str) -> CacheResult<bool> {
+        self.inner.delete_prompt(id)
+    }
+
+    fn restore_prompt(&self, id: &str) -> CacheResult<()> {
+        self.inner.restore_prompt(id)
+    }
+
+    fn purge_prompt(&self, id: &str) -> CacheResult<bool> {
+        self.inner.purge_prompt(id)
+    }
+
+    fn purge_expired(&self, retention: Duration) -> CacheResult<usize> {
+        self.inner.purge_expired(retention)
+    }
+
+    fn get_prompt_categories(&self) -> CacheResult<Vec<String>> {
+        self.inner.get_prompt_categories()
+    }
+
+    fn get_category_counts(&self) -> CacheResult<Vec<DbCategoryCount>> {
+        self.inner.get_category_counts()
+    }
+
+    fn merge_categories(&self, sources: &[String], target: &str) -> CacheResult<usize> {
+        let updated = self.inner.merge_categories(sources, target)?;
+        // Any lineage may have moved, let a full sync find them
+        if updated > 0 {
+            if let Err(e) = self.mirror.sync(self.inner.as_ref()) {
+                error!("Failed to mirror category merge to git: {:?}", e);
+            }
+        }
+        Ok(updated)
+    }
+
+    fn get_similar_prompts(
+        &self,
+        id: &str,
+        threshold: f64,
+        limit: usize,
+        include_lineage: bool,
+    ) -> CacheResult<Vec<DbSimilarPrompt>> {
+        self.inner
+            .get_similar_prompts(id, threshold, limit, include_lineage)
+    }
+
+    fn get_duplicate_groups(&self, threshold: f64) -> CacheResult<Vec<Vec<DbSimilarPrompt>>> {
+        self.inner.get_duplicate_groups(threshold)
+    }
+
+    fn get_related_prompts(&self, id: &str, limit: usize) -> CacheResult<Vec<DbSimilarPrompt>> {
+        self.inner.get_related_prompts(id, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+
+    fn author() -> Author {
+        Author {
+            name: "Test".to_string(),
+            email: "test@example.com".to_string(),
+        }
+    }
+
+    fn prompt(id: &str, parent: &str, version: i32, name: &str) -> DbPrompt {
+        DbPrompt {
+            id: id.to_string(),
+            version,
+            content: format!("Content of {}\n---\nwith a rule", id),
+            parent: parent.to_string(),
+            branched: Some(false),
+            archived: Some(false),
+            created_at: 1_700_000_000 + i64::from(version),
+            metadata: Some(DbPromptMetadata {
+                id: id.to_string(),
+                name: Some(name.to_string()),
+                description: None,
+                category: Some("support".to_string()),
+                tags: Some(vec!["a".to_string(), "b".to_string()]),
+                updated_at: 1_700_000_000,
+            }),
+            commit: None,
+        }
+    }
+
+    fn commit_count(path: &Path) -> usize {
+        let repo = Repository::open_bare(path).unwrap();
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push_ref(BRANCH).unwrap();
+        revwalk.count()
+    }
+
+    #[test]
+    fn test_render_and_parse() {
+        let prompt = prompt("a", "a", 1, "Greeting");
+        let rendered = render(&prompt);
+        let (front_matter, content) = parse(&rendered);
+        assert_eq!(front_matter, Some(FrontMatter::of(&prompt)));
+        assert_eq!(content, prompt.content);
+
+        assert_eq!(parse("No front matter"), (None, "No front matter"));
+    }
+
+    #[test]
+    fn test_mirror_and_import() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("mirror.git");
+        let inner: Arc<dyn PromptStore> = Arc::new(MemoryStore::new());
+
+        // Versions written before the mirror existed are caught up by a sync
+        inner
+            .insert_prompt(prompt("a", "a", 1, "Greeting"))
+            .unwrap();
+        let mirror = Arc::new(GitMirror::open(&path, author()).unwrap());
+        assert_eq!(mirror.sync(inner.as_ref()).unwrap(), 1);
+
+        let store = MirroredStore::new(inner.clone(), mirror.clone());
+        store
+            .insert_prompt(prompt("a2", "a", 2, "Greeting"))
+            .unwrap();
+        store
+            .update_prompt_metadata(
+                "a2",
+                DbPromptMetadata {
+                    name: Some("Welcome".to_string()),
+                    ..prompt("a2", "a", 2, "").metadata.unwrap()
+                },
+            )
+            .unwrap();
+        // Metadata of an older version isn't in the file
+        store
+            .update_prompt_metadata("a", prompt("a", "a", 1, "Old").metadata.unwrap())
+            .unwrap();
+        assert_eq!(commit_count(&path), 3);
+        assert_eq!(mirror.sync(inner.as_ref()).unwrap(), 0);
+
+        // Importing the mirror into an empty store rebuilds the lineage
+        let imported = MemoryStore::new();
+        let summary = import(&path, "HEAD", &imported).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                commits: 3,
+                created: 2,
+                updated: 1,
+                skipped: 0
+            }
+        );
+        let a2 = imported.get_prompt("a2", Some(true)).unwrap().unwrap();
+        assert_eq!(a2.parent, "a");
+        assert_eq!(a2.content, prompt("a2", "a", 2, "").content);
+        assert_eq!(a2.metadata.unwrap().name.as_deref(), Some("Welcome"));
+
+        let summary = import(&path, "HEAD", &imported).unwrap();
+        assert_eq!(summary.created, 0);
+    }
+
+    #[test]
+    fn test_import_plain_history() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(temp_dir.path()).unwrap();
+        let signature = Signature::now("Ada", "ada@example.com").unwrap();
+        let mut parent: Option<Oid> = None;
+        let mut commit = |content: &str| {
+            let mut builder = repo.treebuilder(None).unwrap();
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            builder
+                .insert("greeting.txt", blob, FileMode::Blob.into())
+                .unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let parents: Vec<Commit> = parent
+                .map(|oid| repo.find_commit(oid).unwrap())
+                .into_iter()
+                .collect();
+            let parents: Vec<&Commit> = parents.iter().collect();
+            parent = Some(
+                repo.commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    content,
+                    &tree,
+                    &parents,
+                )
+                .unwrap(),
+            );
+        };
+        for content in ["Hello", "Hello there", "Hello there"] {
+            commit(content);
+        }
+
+        let store = MemoryStore::new();
+        let summary = import(temp_dir.path(), "HEAD", &store).unwrap();
+        assert_eq!((summary.commits, summary.created), (3, 2));
+
+        // Importing again finds every version, and links later commits to the same lineage
+        let summary = import(temp_dir.path(), "HEAD", &store).unwrap();
+        assert_eq!((summary.created, summary.skipped), (0, 2));
+        commit("Hello again");
+        let summary = import(temp_dir.path(), "HEAD", &store).unwrap();
+        assert_eq!((summary.created, summary.skipped), (1, 2));
+
+        // Commits made within the same second tie on created_at
+        let mut versions = all_versions(&store, None).unwrap();
+        versions.sort_by_key(|version| version.version);
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[1].parent, versions[0].id);
+        assert_eq!(versions[2].parent, versions[0].id);
+        assert_eq!(versions[2].version, 3);
+        assert_eq!(
+            versions[0].metadata.as_ref().unwrap().name.as_deref(),
+            Some("greeting")
+        );
+        assert_eq!(
+            store
+                .get_prompt_content_latest_version(&versions[0].id)
+                .unwrap(),
+            "Hello again"
+        );
+
+        // Versions keep the author and message of their commit, mirroring commits them the same
+        assert_eq!(
+            versions[1].commit,
+            Some(DbCommit {
+                author_name: "Ada".to_string(),
+                author_email: "ada@example.com".to_string(),
+                message: "Hello there".to_string(),
+            })
+        );
+        let path = temp_dir.path().join("mirror.git");
+        let mirror = GitMirror::open(&path, author()).unwrap();
+        assert_eq!(mirror.sync(&store).unwrap(), 3);
+        let mirrored = Repository::open_bare(&path).unwrap();
+        let head = mirrored
+            .find_reference(BRANCH)
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(head.author().name().unwrap(), "Ada");
+        assert_eq!(head.message().unwrap(), "Hello again");
+    }
+}
@@ -0,0 +1,125 @@
+/*
+Conditional GETs for prompt reads. Responses carry a strong ETag, a hash of the body, and a
+request whose `If-None-Match` lists it is answered with 304 Not Modified and no body.
+
+The content of a version never changes, so it's cacheable for good. Whatever can move, the latest
+version of a lineage or a prompt's metadata and archived flag, gets a short max-age and is
+revalidated with its ETag after that.
+*/
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+};
+
+use crate::fingerprint::fnv1a;
+
+/// How long resolved or mutable representations may be reused without revalidating
+pub const SHORT_MAX_AGE_SECS: u64 = 10;
+const IMMUTABLE_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// Never changes, ie the content of a pinned version
+    Immutable,
+    /// May change, ie the latest version of a lineage
+    Short,
+}
+
+impl Freshness {
+    fn cache_control(self) -> String {
+        match self {
+            Self::Immutable => format!("max-age={}, immutable", IMMUTABLE_MAX_AGE_SECS),
+            Self::Short => format!("max-age={}", SHORT_MAX_AGE_SECS),
+        }
+    }
+}
+
+pub fn etag(body: &[u8]) -> String {
+    format!("\"{:016x}\"", fnv1a(body))
+}
+
+/// Whether an `If-None-Match` value lists `etag`. The comparison is weak, as RFC 9110 asks for.
+fn matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// `body` with its ETag and caching headers, or 304 when the client already holds it
+pub fn conditional(
+    request: &HeaderMap,
+    content_type: &str,
+    body: Vec<u8>,
+    freshness: Freshness,
+) -> Response<Body> {
+    let etag = etag(&body);
+    let not_modified = request
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| matches(value, &etag));
+    // Representations are negotiated, so caches have to key on Accept too
+    let headers = [
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, freshness.cache_control()),
+        (header::VARY, "accept".to_string()),
+    ];
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    let mut response = (headers, body).into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(content_type).expect("Invalid content type"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conditional() {
+        let body = b"\"Hello\"".to_vec();
+        let tag = etag(&body);
+
+        let response = conditional(
+            &HeaderMap::new(),
+            "application/json",
+            body.clone(),
+            Freshness::Immutable,
+        );
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], tag.as_str());
+        assert!(response.headers()[header::CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .ends_with("immutable"));
+
+        for if_none_match in [
+            tag.clone(),
+            format!("\"other\", W/{}", tag),
+            "*".to_string(),
+        ] {
+            let mut request = HeaderMap::new();
+            request.insert(header::IF_NONE_MATCH, if_none_match.parse().unwrap());
+            let response =
+                conditional(&request, "application/json", body.clone(), Freshness::Short);
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(response.headers()[header::ETAG], tag.as_str());
+            assert_eq!(
+                response.headers()[header::CACHE_CONTROL],
+                format!("max-age={}", SHORT_MAX_AGE_SECS).as_str()
+            );
+        }
+
+        let mut request = HeaderMap::new();
+        request.insert(header::IF_NONE_MATCH, "\"other\"".parse().unwrap());
+        let response = conditional(&request, "application/json", body, Freshness::Short);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
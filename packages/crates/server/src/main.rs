@@ -1,19 +1,57 @@
 use axum::{
-    routing::{get, post, put},
+    extract::DefaultBodyLimit,
+    http::{header, HeaderValue},
+    middleware,
+    routing::{delete, get, patch, post, put},
     Router,
 };
+use backup::Backups;
 use cache::CacheConfig;
-use log::{debug, info};
-use std::env;
+use clap::Parser;
+use config::{
+    Cli, Command, Config, ConfigCommand, CorsConfig, LogConfig, LogFormat, MirrorCommand,
+    StorageBackend,
+};
+use db_lock::DbLock;
+use dir_sync::{Action, SyncOptions};
+use git_mirror::{GitMirror, MirroredStore};
+use log::{debug, error, info, warn};
+use memory_store::MemoryStore;
+use read_cache::CachedStore;
+use std::num::NonZeroUsize;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use store::PromptStore;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::EnvFilter;
+use transfer::{ConflictPolicy, ExportFormat, ImportOptions};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
+use validation::Limits;
 
+mod api_error;
 mod api_models;
+mod api_version;
+mod backup;
 mod cache;
+mod config;
+mod db_lock;
+mod dir_sync;
+mod fingerprint;
+mod front_matter;
+mod git_mirror;
+mod http_cache;
+mod memory_store;
+mod migrations;
+mod negotiation;
+mod read_cache;
 mod routes;
+mod store;
+mod tfidf;
+mod transfer;
+mod validation;
 
 #[derive(OpenApi)]
 #[openapi(
@@ -22,8 +60,26 @@ mod routes;
         routes::get_prompt_content,
         routes::get_prompts,
         routes::create_prompt,
+        routes::batch_get_prompts,
+        routes::batch_create_prompts,
         routes::update_prompt_metadata,
-        routes::delete_prompt
+        routes::patch_prompt_metadata,
+        routes::delete_prompt,
+        routes::restore_prompt,
+        routes::get_trash,
+        routes::purge_prompt,
+        routes::get_similar_prompts,
+        routes::get_related_prompts,
+        routes::get_prompt_categories,
+        routes::get_categories,
+        routes::rename_category,
+        routes::merge_categories,
+        routes::get_duplicate_prompts,
+        routes::get_cache_stats,
+        routes::create_backup,
+        routes::get_backups,
+        routes::export_prompts,
+        routes::import_prompts
     ),
     info(
         title = "Simple Prompt Storage API",
@@ -38,70 +94,512 @@ mod routes;
 )]
 struct ApiDoc;
 
-fn write_openapi_spec() -> std::io::Result<()> {
-    info!("Writing OpenAPI spec to file");
-    let spec = ApiDoc::openapi();
-    let yaml_spec = serde_yaml::to_string(&spec).expect("Failed to serialize OpenAPI spec to YAML");
-    std::fs::write("openapi.yaml", yaml_spec)
+fn init_tracing(log: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&log.filter))
+        .unwrap();
+    // stderr keeps stdout clean for commands that print results, ie `server export`
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match log.format {
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+/// CORS for the configured origins, None when there are none
+fn cors_layer(cors: &CorsConfig) -> Option<CorsLayer> {
+    if cors.allowed_origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if cors.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            cors.allowed_origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(Any)
+            .allow_headers(Any)
+            .expose_headers(
+                [api_error::REQUEST_ID_HEADER, header::ETAG, header::LOCATION]
+                    .into_iter()
+                    .chain(api_version::exposed_headers())
+                    .collect::<Vec<_>>(),
+            ),
+    )
+}
+
+/// One spec per API version, openapi.yaml being the unversioned routes older clients use
+fn write_openapi_spec(dir: &Path) -> std::io::Result<()> {
+    info!("Writing OpenAPI specs to {}", dir.display());
+    for (file, spec) in [
+        (
+            "openapi.v1.yaml",
+            api_version::openapi_v1(ApiDoc::openapi()),
+        ),
+        (
+            "openapi.yaml",
+            api_version::openapi_legacy(ApiDoc::openapi()),
+        ),
+    ] {
+        let yaml_spec =
+            serde_yaml::to_string(&spec).expect("Failed to serialize OpenAPI spec to YAML");
+        std::fs::write(dir.join(file), yaml_spec)?;
+    }
+    Ok(())
+}
+
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Exports and full batches are a lot larger than the default request body limit
+const BULK_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Hard-deletes archived prompts once they've outlived the retention period
+async fn purge_expired_trash(store: Arc<dyn PromptStore>, retention: Duration) {
+    let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let store = store.clone();
+        match tokio::task::spawn_blocking(move || store.purge_expired(retention)).await {
+            Ok(Err(e)) => error!("Failed to purge expired trash: {:?}", e),
+            Err(e) => error!("Trash purge task failed: {:?}", e),
+            Ok(Ok(_)) => {}
+        }
+    }
+}
+
+/// Takes a snapshot every `interval`, retention is applied after each one
+async fn run_scheduled_backups(backups: Arc<Backups>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes immediately, skip it so restarts don't snapshot every time
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let backups = backups.clone();
+        match tokio::task::spawn_blocking(move || backups.snapshot()).await {
+            Ok(Err(e)) => error!("Failed to take scheduled snapshot: {:?}", e),
+            Err(e) => error!("Scheduled snapshot task failed: {:?}", e),
+            Ok(Ok(_)) => {}
+        }
+    }
+}
+
+/// `server backup`: takes a snapshot of the database, even while the server is running
+fn run_backup(backups: &Backups) -> Result<(), Box<dyn std::error::Error>> {
+    let snapshot = backups.snapshot()?;
+    println!(
+        "Wrote snapshot {} ({} bytes)",
+        snapshot.path.display(),
+        snapshot.size_bytes
+    );
+    Ok(())
+}
+
+/// `server restore <snapshot>`: verifies a snapshot and copies it over the database. Refuses
+/// to run while a server uses the database, it doesn't pick up a restored database.
+fn run_restore(db_path: &str, snapshot: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let _lock = DbLock::acquire(db_path)?;
+    backup::restore(snapshot, db_path)?;
+    println!("Restored {} from {}", db_path, snapshot.display());
+    Ok(())
+}
+
+/// `server config check`: prints the effective settings, or why they're invalid
+fn run_config_check(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let config = cli.config()?;
+    if let Some(path) = &cli.config {
+        println!("# Loaded from {}", path.display());
+    }
+    print!("{}", config.to_toml());
+    Ok(())
+}
+
+/// `server mirror sync` and `server mirror import <repo>`, against the configured database while
+/// no server uses it
+fn run_mirror(config: &Config, command: &MirrorCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let _lock = DbLock::acquire(&config.database.path)?;
+    let store = CacheConfig::new(&config.database.path, config.database.connection_options())?;
+    match command {
+        MirrorCommand::Sync => {
+            let path = config
+                .git_mirror
+                .path
+                .as_ref()
+                .ok_or("git_mirror.path must be set to sync the mirror")?;
+            let commits = GitMirror::open(path, config.git_mirror.author())?.sync(&store)?;
+            println!("Made {} commits in {}", commits, path.display());
+        }
+        MirrorCommand::Import { repo, reference } => {
+            let summary = git_mirror::import(repo, reference, &store)?;
+            println!(
+                "Read {} commits: {} versions created, {} metadata updates, {} skipped",
+                summary.commits, summary.created, summary.updated, summary.skipped
+            );
+        }
+    }
+    Ok(())
+}
+
+/// `server export`: writes every prompt to `output`, or to stdout
+fn run_export(
+    config: &Config,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let store = CacheConfig::new(&config.database.path, config.database.connection_options())?;
+    let prompts = store::all_versions(&store, None)?;
+    let count = prompts.len();
+    let export = match format {
+        ExportFormat::Ndjson => transfer::to_ndjson(prompts),
+        ExportFormat::Yaml => transfer::to_yaml(prompts),
+    };
+    match output {
+        Some(output) => {
+            std::fs::write(output, export)?;
+            println!("Exported {} prompts to {}", count, output.display());
+        }
+        None => print!("{}", export),
+    }
+    Ok(())
+}
+
+/// `server import <file>`: replays an export into the database, yaml files by extension. Stop
+/// the server first, or use `POST /v1/import` against it.
+fn run_import(
+    config: &Config,
+    file: &Path,
+    options: ImportOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _lock = DbLock::acquire(&config.database.path)?;
+    let format = match file.extension().and_then(|extension| extension.to_str()) {
+        Some("yaml" | "yml") => ExportFormat::Yaml,
+        _ => ExportFormat::Ndjson,
+    };
+    let store = CacheConfig::new(&config.database.path, config.database.connection_options())?;
+    let records = transfer::parse(&std::fs::read_to_string(file)?, format)?;
+    let plan = transfer::import(&store, records, options)?;
+
+    for conflict in &plan.conflicts {
+        println!("Conflict: {}: {}", conflict.id, conflict.reason);
+    }
+    let verb = if plan.applied {
+        "Created"
+    } else {
+        "Would create"
+    };
+    println!(
+        "{} {} prompts, {} unchanged, {} conflicts",
+        verb,
+        plan.prompts.len(),
+        plan.unchanged,
+        plan.conflicts.len()
+    );
+    if !options.dry_run && !plan.conflicts.is_empty() && options.on_conflict == ConflictPolicy::Fail
+    {
+        return Err(
+            "Conflicts found, nothing was imported. Use --on-conflict skip or --ids remap".into(),
+        );
+    }
+    Ok(())
+}
+
+/// `server sync <dir>`: syncs a directory of prompt files, failing if any file conflicts. It
+/// writes the database directly, so the server has to be stopped.
+fn run_sync(
+    config: &Config,
+    dir: &Path,
+    options: &SyncOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _lock = DbLock::acquire(&config.database.path)?;
+    let store = CacheConfig::new(&config.database.path, config.database.connection_options())?;
+    let report = dir_sync::sync(&store, dir, options)?;
+
+    let mut changed = 0;
+    for (path, action) in &report.files {
+        let path = path.strip_prefix(dir).unwrap_or(path).display();
+        let line = match action {
+            Action::Unchanged => continue,
+            Action::Created { id } => format!("created {id}"),
+            Action::Pushed { id } => format!("pushed as {id}"),
+            Action::UpdatedMetadata { id } => format!("updated metadata of {id}"),
+            Action::Pulled { id } => format!("pulled {id}"),
+            Action::Added { id } => format!("added {id}"),
+            Action::Conflict { reason } => format!("conflict, {reason}"),
+        };
+        changed += 1;
+        println!("{path}: {line}");
+    }
+    let verb = if report.applied {
+        "Synced"
+    } else {
+        "Would sync"
+    };
+    println!(
+        "{} {} files, {} changed, {} conflicts",
+        verb,
+        report.files.len(),
+        changed - report.conflicts(),
+        report.conflicts()
+    );
+    if report.conflicts() > 0 {
+        return Err("Conflicting files were left untouched, resolve them and sync again".into());
+    }
+    Ok(())
+}
+
+/// `server migrate [--dry-run]`: applies pending schema migrations, or only reports them
+fn run_migrations(db_path: &str, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let _lock = DbLock::acquire(db_path)?;
+    let mut conn = rusqlite::Connection::open(db_path)?;
+    let plan = migrations::plan(&conn)?;
+    println!(
+        "Database {} is at schema version {}, latest is {}",
+        db_path, plan.current_version, plan.target_version
+    );
+    if plan.pending.is_empty() {
+        println!("No pending migrations");
+        return Ok(());
+    }
+
+    for migration in &plan.pending {
+        println!("  {}: {}", migration.version, migration.description);
+    }
+    if dry_run {
+        println!("Dry run, {} migrations not applied", plan.pending.len());
+        return Ok(());
+    }
+
+    let applied = migrations::migrate(&mut conn)?;
+    println!("Applied {} migrations", applied.len());
+    Ok(())
 }
 
 #[derive(Clone)]
 struct AppState {
-    cache: Arc<CacheConfig>,
+    store: Arc<dyn PromptStore>,
+    /// Snapshots of the SQLite database, None with in-memory storage
+    backups: Option<Arc<Backups>>,
+    limits: Limits,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env()
-                .or_else(|_| EnvFilter::try_new("debug,axum::rejection=trace,tower_http=warn"))
-                .unwrap(),
-        )
-        .init();
-
-    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    let addr = format!("0.0.0.0:{}", port);
-
-    write_openapi_spec().expect("Failed to write OpenAPI spec to file");
+    let cli = Cli::parse();
+    // Printed before logging starts so the output is only the settings
+    if let Some(Command::Config(ConfigCommand::Check)) = cli.command {
+        return run_config_check(&cli);
+    }
+    let config = cli.config()?;
+    init_tracing(&config.log);
+    debug!("Config: {:?}", config);
+    if cli.overrides.uses_legacy_stage() {
+        warn!(
+            "STAGE and DATA_DIR are deprecated, set DATABASE_PATH={} instead",
+            config.database.path
+        );
+    }
 
     // TODO: Add SQL database as fallback
     // TODO: Object storage for long term storage
 
-    let stage = env::var("STAGE").unwrap_or_else(|_| "dev".to_string());
-    debug!("Stage: {}", stage);
-    let db_path = match stage.as_str() {
-        "prod" => {
-            let data_dir = env::var("DATA_DIR").expect("DATA_DIR must be set");
-            format!("{}/prompts-prod.db", data_dir)
+    let db_path = config.database.path.clone();
+    let backups = Backups::new(&db_path, &config.backups.dir, config.backups.retention());
+
+    match &cli.command {
+        Some(Command::Migrate { dry_run }) => return run_migrations(&db_path, *dry_run),
+        Some(Command::Backup) => return run_backup(&backups),
+        Some(Command::Restore { snapshot }) => return run_restore(&db_path, snapshot),
+        Some(Command::Mirror(command)) => return run_mirror(&config, command),
+        Some(Command::Export { format, output }) => {
+            return run_export(&config, *format, output.as_deref())
+        }
+        Some(Command::Import {
+            file,
+            dry_run,
+            ids,
+            on_conflict,
+        }) => {
+            return run_import(
+                &config,
+                file,
+                ImportOptions {
+                    ids: *ids,
+                    on_conflict: *on_conflict,
+                    dry_run: *dry_run,
+                },
+            )
+        }
+        Some(Command::Sync {
+            dir,
+            dry_run,
+            pull_new,
+            category,
+        }) => {
+            return run_sync(
+                &config,
+                dir,
+                &SyncOptions {
+                    dry_run: *dry_run,
+                    pull_new: *pull_new,
+                    category: category.clone(),
+                },
+            )
         }
-        "dev" => "prompts-dev.db".to_string(),
-        _ => panic!("Invalid stage: {}", stage),
+        Some(Command::Serve | Command::Config(_)) | None => {}
+    }
+
+    if config.features.write_openapi {
+        write_openapi_spec(&config.features.openapi_dir)
+            .expect("Failed to write OpenAPI spec to file");
+    }
+
+    // Held until the server exits so offline commands can't write behind its back
+    let _db_lock = match config.database.storage {
+        StorageBackend::Sqlite => Some(DbLock::acquire(&db_path)?),
+        StorageBackend::Memory => None,
+    };
+    let (store, backups): (Arc<dyn PromptStore>, _) = match config.database.storage {
+        StorageBackend::Sqlite => (
+            Arc::new(CacheConfig::new(
+                &db_path,
+                config.database.connection_options(),
+            )?),
+            Some(Arc::new(backups)),
+        ),
+        StorageBackend::Memory => (Arc::new(MemoryStore::new()), None),
     };
 
-    let cache = CacheConfig::new(&db_path)?;
+    let store: Arc<dyn PromptStore> = match &config.git_mirror.path {
+        Some(path) => {
+            let mirror = Arc::new(GitMirror::open(path, config.git_mirror.author())?);
+            let (catch_up, inner) = (mirror.clone(), store.clone());
+            let commits =
+                tokio::task::spawn_blocking(move || catch_up.sync(inner.as_ref())).await??;
+            info!(
+                "Mirroring to git at {}, caught up with {} commits",
+                path.display(),
+                commits
+            );
+            Arc::new(MirroredStore::new(store, mirror))
+        }
+        None => store,
+    };
+
+    // A capacity of 0 disables the cache
+    let store: Arc<dyn PromptStore> = match NonZeroUsize::new(config.read_cache.capacity) {
+        Some(capacity) => Arc::new(CachedStore::new(store, capacity, config.read_cache_ttl())),
+        None => store,
+    };
     let state = AppState {
-        cache: Arc::new(cache),
+        store,
+        backups,
+        limits: config.validation.limits(),
     };
 
-    let app = Router::new()
+    match config.trash_retention() {
+        Some(retention) => {
+            tokio::spawn(purge_expired_trash(state.store.clone(), retention));
+        }
+        None => info!("Trash retention is 0, archived prompts are kept until purged"),
+    }
+
+    let backup_interval_mins = config.backups.interval_mins;
+    if let Some(backups) = state.backups.clone().filter(|_| backup_interval_mins > 0) {
+        info!(
+            "Backing up to {} every {} minutes",
+            config.backups.dir.display(),
+            backup_interval_mins
+        );
+        tokio::spawn(run_scheduled_backups(
+            backups,
+            Duration::from_secs(backup_interval_mins * 60),
+        ));
+    }
+
+    // `/prompt/categories` is still served for existing clients
+    #[allow(deprecated)]
+    let api = Router::new()
         .route("/prompt", post(routes::create_prompt))
         .route(
             "/prompt/{id}",
             get(routes::get_prompt).delete(routes::delete_prompt),
         )
         .route("/prompt/{id}/content", get(routes::get_prompt_content))
+        .route(
+            "/prompt/{id}/metadata",
+            patch(routes::patch_prompt_metadata),
+        )
+        .route("/prompt/{id}/restore", post(routes::restore_prompt))
+        .route("/prompt/{id}/similar", get(routes::get_similar_prompts))
+        .route("/prompt/{id}/related", get(routes::get_related_prompts))
         .route("/prompt/metadata", put(routes::update_prompt_metadata))
         .route("/prompts", get(routes::get_prompts))
+        .route("/prompts:batchGet", post(routes::batch_get_prompts))
+        .route(
+            "/prompts:batchCreate",
+            post(routes::batch_create_prompts).layer(DefaultBodyLimit::max(BULK_BODY_LIMIT)),
+        )
+        .route("/prompts/duplicates", get(routes::get_duplicate_prompts))
         .route("/prompt/categories", get(routes::get_prompt_categories))
-        .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
+        .route("/categories", get(routes::get_categories))
+        .route("/categories/rename", post(routes::rename_category))
+        .route("/categories/merge", post(routes::merge_categories))
+        .route("/cache/stats", get(routes::get_cache_stats))
+        .route(
+            "/admin/backups",
+            get(routes::get_backups).post(routes::create_backup),
+        )
+        .route("/export", get(routes::export_prompts))
+        .route(
+            "/import",
+            post(routes::import_prompts).layer(DefaultBodyLimit::max(BULK_BODY_LIMIT)),
+        )
+        .route("/trash", get(routes::get_trash))
+        .route("/trash/{id}", delete(routes::purge_prompt))
+        .method_not_allowed_fallback(api_error::method_not_allowed);
+    let mut app = Router::new().nest(api_version::V1_PREFIX, api.clone());
+    if config.legacy_api.enabled {
+        app = app.merge(api.layer(middleware::from_fn_with_state(
+            api_version::Deprecation::from(&config.legacy_api),
+            api_version::deprecated,
+        )));
+    }
+    if config.features.swagger_ui {
+        app = app.merge(
+            SwaggerUi::new("/swagger-ui")
+                .url(
+                    "/api-docs/v1/openapi.json",
+                    api_version::openapi_v1(ApiDoc::openapi()),
+                )
+                .url(
+                    "/api-docs/openapi.json",
+                    api_version::openapi_legacy(ApiDoc::openapi()),
+                ),
+        );
+    }
+    if let Some(cors) = cors_layer(&config.cors) {
+        app = app.layer(cors);
+    }
+    let app = app
+        .fallback(api_error::not_found)
+        .layer(middleware::from_fn(api_error::request_id))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
+    let addr = config.bind_addr()?;
     info!("Server running on http://{}", addr);
-    info!("Swagger UI available at http://{}/swagger-ui/", addr);
+    if config.features.swagger_ui {
+        info!("Swagger UI available at http://{}/swagger-ui/", addr);
+    }
 
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await?;
     Ok(())
 }
@@ -0,0 +1,825 @@
+/*
+In-memory `PromptStore`, for tests and throwaway instances. Nothing is persisted.
+
+Behaviour mirrors the SQLite store: listings use the same filters and keyset cursors, and
+similarity and related prompts are computed from the same MinHash and TF-IDF code. Signatures and
+term weights are computed on the fly instead of being indexed, which is fine at the sizes this
+store is meant for.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::cache::{
+    category_counts, merge_order, now_timestamp, CacheError, CacheResult, DbCategoryCount,
+    DbMetadataPatch, DbPrompt, DbPromptHead, DbPromptMetadata, DbSimilarPrompt, PromptCursor,
+    PromptPage, PromptQuery,
+};
+use crate::fingerprint::{self, MinHash};
+use crate::store::{latest_version, PromptStore};
+use crate::tfidf;
+use rusqlite::types::Value;
+
+#[derive(Default)]
+struct MemoryState {
+    prompts: HashMap<String, DbPrompt>,
+    /// Archived prompt ids and when they were archived
+    trash: HashMap<String, i64>,
+}
+
+impl MemoryState {
+    /// Archived prompts go straight to the trash
+    fn insert(&mut self, prompt: DbPrompt) {
+        if is_archived(&prompt) {
+            self.trash
+                .entry(prompt.id.clone())
+                .or_insert(now_timestamp());
+        }
+        self.prompts.insert(prompt.id.clone(), prompt);
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    state: RwLock<MemoryState>,
+}
+
+fn is_archived(prompt: &DbPrompt) -> bool {
+    prompt.archived.unwrap_or(false)
+}
+
+fn has_other_versions(state: &MemoryState, root: &str) -> bool {
+    state
+        .prompts
+        .values()
+        .any(|p| p.parent == root && p.id != root)
+}
+
+/// Highest version of each lineage along with the lineage size, keyed by parent
+fn lineage_heads<'a>(
+    prompts: impl Iterator<Item = &'a DbPrompt>,
+) -> HashMap<&'a str, (&'a DbPrompt, i64)> {
+    let mut heads: HashMap<&str, (&DbPrompt, i64)> = HashMap::new();
+    for prompt in prompts {
+        heads
+            .entry(prompt.parent.as_str())
+            .and_modify(|(head, count)| {
+                *count += 1;
+                let rank = |p: &DbPrompt| (p.version, p.created_at, p.id.clone());
+                if rank(prompt) > rank(head) {
+                    *head = prompt;
+                }
+            })
+            .or_insert((prompt, 1));
+    }
+    heads
+}
+
+fn shares_bucket(a: &[i64], b: &[i64]) -> bool {
+    a.iter().zip(b).any(|(a, b)| a == b)
+}
+
+fn similar_prompt(prompt: &DbPrompt, similarity: f64) -> DbSimilarPrompt {
+    DbSimilarPrompt {
+        id: prompt.id.clone(),
+        parent: prompt.parent.clone(),
+        name: prompt.metadata.as_ref().and_then(|m| m.name.clone()),
+        similarity,
+    }
+}
+
+fn term_weights(prompt: &DbPrompt) -> HashMap<String, f64> {
+    let metadata = prompt.metadata.as_ref();
+    tfidf::term_weights(&tfidf::Document {
+        content: &prompt.content,
+        name: metadata.and_then(|m| m.name.as_deref()),
+        description: metadata.and_then(|m| m.description.as_deref()),
+        tags: metadata.and_then(|m| m.tags.as_deref()).unwrap_or_default(),
+    })
+}
+
+/// Sorts and keyset-paginates rows the same way `CacheConfig::query_page` does
+fn paginate<T>(
+    query: &PromptQuery,
+    mut rows: Vec<(Value, String, T)>,
+) -> CacheResult<PromptPage<T>> {
+    query.check_limit()?;
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|cursor| PromptCursor::decode(cursor, query.sort, query.order))
+        .transpose()?;
+
+    let total = rows.len() as i64;
+    rows.sort_by(|a, b| query.order.compare((&a.0, &a.1), (&b.0, &b.1)));
+    if let Some(cursor) = cursor {
+        rows.retain(|(sort_key, id, _)| {
+            query
+                .order
+                .compare((sort_key, id), (&cursor.sort_key, &cursor.id))
+                .is_gt()
+        });
+    }
+
+    let limit = query.limit as usize;
+    let next_cursor = (rows.len() > limit).then(|| {
+        let (sort_key, id, _) = &rows[limit - 1];
+        PromptCursor {
+            sort_key: sort_key.clone(),
+            id: id.clone(),
+        }
+        .encode(query.sort, query.order)
+    });
+    rows.truncate(limit);
+
+    Ok(PromptPage {
+        items: rows.into_iter().map(|(_, _, item)| item).collect(),
+        next_cursor,
+        total,
+    })
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> CacheResult<RwLockReadGuard<'_, MemoryState>> {
+        self.state
+            .read()
+            .map_err(|e| CacheError::UnhandledError(e.to_string()))
+    }
+
+    fn write(&self) -> CacheResult<RwLockWriteGuard<'_, MemoryState>> {
+        self.state
+            .write()
+            .map_err(|e| CacheError::UnhandledError(e.to_string()))
+    }
+}
+
+impl PromptStore for MemoryStore {
+    fn insert_prompt(&self, prompt: DbPrompt) -> CacheResult<DbPrompt> {
+        info!("Inserting: {}", prompt.id);
+        let mut prompts = self.insert_prompts(vec![prompt])?;
+        Ok(prompts.remove(0))
+    }
+
+    fn insert_prompts(&self, mut prompts: Vec<DbPrompt>) -> CacheResult<Vec<DbPrompt>> {
+        info!("Inserting {} prompts", prompts.len());
+        let mut state = self.write()?;
+        let mut ids = HashSet::new();
+        if let Some(prompt) = prompts
+            .iter()
+            .find(|prompt| state.prompts.contains_key(&prompt.id) || !ids.insert(&prompt.id))
+        {
+            return Err(CacheError::UnhandledError(format!(
+                "prompt {} already exists",
+                prompt.id
+            )));
+        }
+
+        // Parents resolve to their lineage root, which can be earlier in the same batch, and so
+        // can the newest version of the lineage
+        let mut roots: HashMap<String, String> = HashMap::new();
+        let mut newest: HashMap<String, i32> = HashMap::new();
+        for prompt in &mut prompts {
+            if prompt.parent != prompt.id {
+                prompt.parent = roots
+                    .get(&prompt.parent)
+                    .or_else(|| state.prompts.get(&prompt.parent).map(|p| &p.parent))
+                    .cloned()
+                    .ok_or_else(|| CacheError::UnknownParent(prompt.parent.clone()))?;
+            }
+            let stored = state
+                .prompts
+                .values()
+                .filter(|p| p.parent == prompt.parent)
+                .map(|p| p.version)
+                .max();
+            let latest = newest.get(&prompt.parent).copied().max(stored).unwrap_or(0);
+            prompt.version = prompt.version.max(latest + 1);
+            roots.insert(prompt.id.clone(), prompt.parent.clone());
+            newest.insert(prompt.parent.clone(), prompt.version);
+        }
+        for prompt in &prompts {
+            state.insert(prompt.clone());
+        }
+        Ok(prompts)
+    }
+
+    fn get_prompt(&self, id: &str, metadata: Option<bool>) -> CacheResult<Option<DbPrompt>> {
+        debug!(
+            "Getting prompt with id: {} and metadata: {:?}",
+            id, metadata
+        );
+        Ok(self.read()?.prompts.get(id).map(|prompt| DbPrompt {
+            metadata: prompt
+                .metadata
+                .clone()
+                .filter(|_| metadata.is_some_and(|m| m)),
+            ..prompt.clone()
+        }))
+    }
+
+    fn get_prompt_content(&self, id: &str) -> CacheResult<String> {
+        match self.read()?.prompts.get(id) {
+            None => Err(CacheError::NotFound),
+            Some(prompt) if is_archived(prompt) => Err(CacheError::Archived),
+            Some(prompt) => Ok(prompt.content.clone()),
+        }
+    }
+
+    fn get_prompt_content_latest_version(&self, id: &str) -> CacheResult<String> {
+        latest_version(self, id, false).map(|prompt| prompt.content)
+    }
+
+    fn get_prompts(&self, query: PromptQuery) -> CacheResult<PromptPage> {
+        debug!("Getting prompts with query: {:?}", query);
+        let rows = self
+            .read()?
+            .prompts
+            .values()
+            .filter(|prompt| query.matches(prompt))
+            .map(|prompt| {
+                (
+                    query.sort.sort_key(prompt),
+                    prompt.id.clone(),
+                    prompt.clone(),
+                )
+            })
+            .collect();
+        paginate(&query, rows)
+    }
+
+    fn get_prompt_heads(&self, query: PromptQuery) -> CacheResult<PromptPage<DbPromptHead>> {
+        debug!("Getting prompt heads with query: {:?}", query);
+        // Archived versions don't count towards a lineage unless they were asked for
+        let skip_archived = query.archived.is_none() && !query.include_archived;
+        let state = self.read()?;
+        let rows = lineage_heads(
+            state
+                .prompts
+                .values()
+                .filter(|prompt| !(skip_archived && is_archived(prompt))),
+        )
+        .into_values()
+        .filter(|(head, _)| query.matches(head))
+        .map(|(head, version_count)| {
+            (
+                query.sort.sort_key(head),
+                head.id.clone(),
+                DbPromptHead {
+                    prompt: head.clone(),
+                    version_count,
+                },
+            )
+        })
+        .collect();
+        paginate(&query, rows)
+    }
+
+    fn update_prompt_metadata(&self, id: &str, metadata: DbPromptMetadata) -> CacheResult<String> {
+        let mut state = self.write()?;
+        let existing = state
+            .prompts
+            .get_mut(id)
+            .and_then(|prompt| prompt.metadata.as_mut())
+            .ok_or(CacheError::NotFound)?;
+        *existing = DbPromptMetadata {
+            id: existing.id.clone(),
+            updated_at: now_timestamp(),
+            ..metadata
+        };
+        Ok(id.to_string())
+    }
+
+    fn patch_prompt_metadata(
+        &self,
+        id: &str,
+        patch: DbMetadataPatch,
+    ) -> CacheResult<DbPromptMetadata> {
+        let mut state = self.write()?;
+        let prompt = state.prompts.get_mut(id).ok_or(CacheError::NotFound)?;
+        let metadata = patch.apply(id, prompt.metadata.take());
+        prompt.metadata = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    fn delete_prompt(&self, id: &str) -> CacheResult<bool> {
+        let mut state = self.write()?;
+        let Some(prompt) = state.prompts.get_mut(id) else {
+            return Ok(false);
+        };
+        prompt.archived = Some(true);
+        state.trash.entry(id.to_string()).or_insert(now_timestamp());
+        Ok(true)
+    }
+
+    fn restore_prompt(&self, id: &str) -> CacheResult<()> {
+        let mut state = self.write()?;
+        let prompt = state.prompts.get_mut(id).ok_or(CacheError::NotFound)?;
+        prompt.archived = Some(false);
+        state.trash.remove(id);
+        Ok(())
+    }
+
+    fn purge_prompt(&self, id: &str) -> CacheResult<bool> {
+        let mut state = self.write()?;
+        let trashed =
+            state.trash.contains_key(id) && state.prompts.get(id).is_some_and(is_archived);
+        if trashed && has_other_versions(&state, id) {
+            return Err(CacheError::LineageNotEmpty(id.to_string()));
+        }
+        if trashed {
+            state.prompts.remove(id);
+            state.trash.remove(id);
+        }
+        Ok(trashed)
+    }
+
+    fn purge_expired(&self, retention: Duration) -> CacheResult<usize> {
+        let cutoff = now_timestamp() - retention.as_secs() as i64;
+        let mut state = self.write()?;
+        let expired: HashSet<String> = state
+            .trash
+            .iter()
+            .filter(|(id, archived_at)| {
+                **archived_at <= cutoff && state.prompts.get(*id).is_some_and(is_archived)
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        // A root goes only if every other version of its lineage goes with it
+        let purged: Vec<String> = expired
+            .iter()
+            .filter(|id| {
+                state
+                    .prompts
+                    .values()
+                    .all(|p| p.parent != **id || p.id == **id || expired.contains(&p.id))
+            })
+            .cloned()
+            .collect();
+        for id in &purged {
+            state.prompts.remove(id);
+            state.trash.remove(id);
+        }
+        if !purged.is_empty() {
+            info!("Purged {} expired prompts from the trash", purged.len());
+        }
+        Ok(purged.len())
+    }
+
+    fn get_prompt_categories(&self) -> CacheResult<Vec<String>> {
+        let mut categories: Vec<String> = self
+            .read()?
+            .prompts
+            .values()
+            .filter_map(|prompt| prompt.metadata.as_ref()?.category.clone())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        categories.sort();
+        Ok(categories)
+    }
+
+    fn get_category_counts(&self) -> CacheResult<Vec<DbCategoryCount>> {
+        let mut direct: HashMap<String, i64> = HashMap::new();
+        self.read()?
+            .prompts
+            .values()
+            .filter(|prompt| !is_archived(prompt))
+            .filter_map(|prompt| prompt.metadata.as_ref()?.category.clone())
+            .for_each(|category| *direct.entry(category).or_insert(0) += 1);
+        Ok(category_counts(direct))
+    }
+
+    fn merge_categories(&self, sources: &[String], target: &str) -> CacheResult<usize> {
+        info!("Merging categories {:?} into {}", sources, target);
+        if target.is_empty() || sources.is_empty() || sources.iter().any(|s| s.is_empty()) {
+            return Err(CacheError::InvalidRequest(
+                "Category names must not be empty".to_string(),
+            ));
+        }
+
+        let sources = merge_order(sources);
+        let mut state = self.write()?;
+        let now = now_timestamp();
+        let mut updated = 0;
+        for metadata in state
+            .prompts
+            .values_mut()
+            .filter_map(|prompt| prompt.metadata.as_mut())
+        {
+            let moved = metadata.category.as_deref().and_then(|category| {
+                sources.iter().find_map(|source| {
+                    let rest = category.strip_prefix(source)?;
+                    (rest.is_empty() || rest.starts_with('/')).then(|| format!("{target}{rest}"))
+                })
+            });
+            if let Some(category) = moved {
+                metadata.category = Some(category);
+                metadata.updated_at = now;
+                updated += 1;
+            }
+        }
+
+        match updated {
+            0 => Err(CacheError::NotFound),
+            _ => Ok(updated),
+        }
+    }
+
+    fn get_similar_prompts(
+        &self,
+        id: &str,
+        threshold: f64,
+        limit: usize,
+        include_lineage: bool,
+    ) -> CacheResult<Vec<DbSimilarPrompt>> {
+        debug!(
+            "Getting prompts similar to {} with threshold {}",
+            id, threshold
+        );
+        let state = self.read()?;
+        let target = state.prompts.get(id).ok_or(CacheError::NotFound)?;
+        let target_minhash = MinHash::from_content(&target.content);
+        let target_buckets = target_minhash.band_buckets();
+
+        let mut similar: Vec<DbSimilarPrompt> = state
+            .prompts
+            .values()
+            .filter(|prompt| prompt.id != id && !is_archived(prompt))
+            .filter(|prompt| include_lineage || prompt.parent != target.parent)
+            .filter_map(|prompt| {
+                let minhash = MinHash::from_content(&prompt.content);
+                shares_bucket(&target_buckets, &minhash.band_buckets())
+                    .then(|| similar_prompt(prompt, target_minhash.similarity(&minhash)))
+            })
+            .filter(|prompt| prompt.similarity >= threshold)
+            .collect();
+
+        similar.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        similar.truncate(limit);
+        Ok(similar)
+    }
+
+    fn get_duplicate_groups(&self, threshold: f64) -> CacheResult<Vec<Vec<DbSimilarPrompt>>> {
+        debug!("Getting duplicate groups with threshold {}", threshold);
+        let state = self.read()?;
+        let heads: HashMap<String, &DbPrompt> =
+            lineage_heads(state.prompts.values().filter(|prompt| !is_archived(prompt)))
+                .into_values()
+                .map(|(head, _)| (head.id.clone(), head))
+                .collect();
+
+        let signatures: HashMap<String, MinHash> = heads
+            .iter()
+            .map(|(id, head)| (id.clone(), MinHash::from_content(&head.content)))
+            .collect();
+        let buckets: HashMap<&String, Vec<i64>> = signatures
+            .iter()
+            .map(|(id, minhash)| (id, minhash.band_buckets()))
+            .collect();
+
+        let candidates: Vec<(String, String)> = buckets
+            .iter()
+            .flat_map(|(a, a_buckets)| {
+                buckets
+                    .iter()
+                    .filter(move |(b, b_buckets)| {
+                        a.as_str() < b.as_str() && shares_bucket(a_buckets, b_buckets)
+                    })
+                    .map(move |(b, _)| ((*a).clone(), (*b).clone()))
+            })
+            .collect();
+
+        Ok(
+            fingerprint::group_duplicates(&candidates, &signatures, threshold)
+                .into_iter()
+                .map(|group| {
+                    group
+                        .into_iter()
+                        .map(|(id, similarity)| similar_prompt(heads[&id], similarity))
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    fn get_related_prompts(&self, id: &str, limit: usize) -> CacheResult<Vec<DbSimilarPrompt>> {
+        debug!("Getting prompts related to {}", id);
+        let state = self.read()?;
+        let target = state.prompts.get(id).ok_or(CacheError::NotFound)?;
+
+        // Every stored prompt counts towards document frequencies, as in the SQLite index
+        let weights: HashMap<&str, HashMap<String, f64>> = state
+            .prompts
+            .values()
+            .map(|prompt| (prompt.id.as_str(), term_weights(prompt)))
+            .filter(|(_, weights)| !weights.is_empty())
+            .collect();
+        let mut documents_with_term: HashMap<&str, i64> = HashMap::new();
+        weights
+            .values()
+            .flat_map(|weights| weights.keys())
+            .for_each(|term| *documents_with_term.entry(term).or_insert(0) += 1);
+
+        let documents = weights.len() as i64;
+        let tfidf_vector = |weights: &HashMap<String, f64>| -> HashMap<String, f64> {
+            weights
+                .iter()
+                .map(|(term, weight)| {
+                    let idf = tfidf::idf(documents, documents_with_term[term.as_str()]);
+                    (term.clone(), weight * idf)
+                })
+                .collect()
+        };
+        let target_vector = weights.get(id).map(&tfidf_vector).unwrap_or_default();
+
+        let mut related: Vec<DbSimilarPrompt> =
+            lineage_heads(state.prompts.values().filter(|prompt| !is_archived(prompt)))
+                .into_values()
+                .map(|(head, _)| head)
+                .filter(|head| head.parent != target.parent)
+                .filter_map(|head| {
+                    let vector = tfidf_vector(weights.get(head.id.as_str())?);
+                    Some(similar_prompt(head, tfidf::cosine(&target_vector, &vector)))
+                })
+                .filter(|prompt| prompt.similarity > 0.0)
+                .collect();
+
+        related.sort_by(|a, b| {
+            b.similarity
+                .total_cmp(&a.similarity)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        related.truncate(limit);
+        Ok(related)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{PromptSort, SortOrder};
+    use crate::store::latest_version;
+
+    fn prompt(id: &str, parent: &str, version: i32, category: Option<&str>) -> DbPrompt {
+        DbPrompt {
+            id: id.to_string(),
+            version,
+            content: format!("Content of {}", id),
+            parent: parent.to_string(),
+            branched: Some(false),
+            archived: Some(false),
+            created_at: 1_000 + i64::from(version),
+            metadata: Some(DbPromptMetadata {
+                id: id.to_string(),
+                name: Some(id.to_string()),
+                description: None,
+                category: category.map(str::to_string),
+                tags: None,
+                updated_at: 1_000,
+            }),
+            commit: None,
+        }
+    }
+
+    #[test]
+    fn test_prompt_lifecycle() {
+        let store = MemoryStore::new();
+        store.insert_prompt(prompt("a", "a", 1, None)).unwrap();
+        store.insert_prompt(prompt("a2", "a", 2, None)).unwrap();
+        assert!(store.insert_prompt(prompt("a", "a", 1, None)).is_err());
+
+        let without_metadata = store.get_prompt("a", None).unwrap().unwrap();
+        assert!(without_metadata.metadata.is_none());
+        assert!(store
+            .get_prompt("a", Some(true))
+            .unwrap()
+            .unwrap()
+            .metadata
+            .is_some());
+        assert!(store.get_prompt("missing", None).unwrap().is_none());
+
+        assert_eq!(
+            store.get_prompt_content_latest_version("a").unwrap(),
+            "Content of a2"
+        );
+        // Parents resolve to the lineage root and versions are numbered after the lineage's
+        // newest, within a batch too
+        let inserted = store
+            .insert_prompts(vec![prompt("b", "b", 1, None), prompt("b2", "b", 1, None)])
+            .unwrap();
+        assert_eq!((inserted[1].parent.as_str(), inserted[1].version), ("b", 2));
+        let inserted = store.insert_prompt(prompt("b3", "b2", 1, None)).unwrap();
+        assert_eq!((inserted.parent.as_str(), inserted.version), ("b", 3));
+        assert!(matches!(
+            store.insert_prompt(prompt("c", "missing", 1, None)),
+            Err(CacheError::UnknownParent(_))
+        ));
+
+        // Any version resolves to the same head `latest_version` does
+        assert_eq!(
+            store.get_prompt_content_latest_version("a2").unwrap(),
+            latest_version(&store, "a", false).unwrap().content
+        );
+        assert!(store.delete_prompt("a2").unwrap());
+        assert!(!store.delete_prompt("missing").unwrap());
+        assert_eq!(
+            store.get_prompt_content_latest_version("a").unwrap(),
+            "Content of a"
+        );
+        assert!(matches!(
+            store.get_prompt_content("a2"),
+            Err(CacheError::Archived)
+        ));
+        assert_eq!(store.get_trash(PromptQuery::default()).unwrap().total, 1);
+
+        store.restore_prompt("a2").unwrap();
+        assert!(store
+            .get_trash(PromptQuery::default())
+            .unwrap()
+            .items
+            .is_empty());
+
+        // A root can't be purged while its lineage has other versions
+        store.delete_prompt("a").unwrap();
+        assert!(matches!(
+            store.purge_prompt("a"),
+            Err(CacheError::LineageNotEmpty(_))
+        ));
+        assert_eq!(store.purge_expired(Duration::ZERO).unwrap(), 0);
+        store.restore_prompt("a").unwrap();
+
+        store.delete_prompt("a2").unwrap();
+        assert!(store.purge_prompt("a2").unwrap());
+        assert!(!store.purge_prompt("a").unwrap());
+        assert!(store.get_prompt("a2", None).unwrap().is_none());
+
+        // Expired lineages are purged whole
+        store.delete_prompt("b").unwrap();
+        store.delete_prompt("b2").unwrap();
+        store.delete_prompt("b3").unwrap();
+        assert_eq!(store.purge_expired(Duration::ZERO).unwrap(), 3);
+
+        assert!(matches!(
+            store.update_prompt_metadata(
+                "missing",
+                DbPromptMetadata {
+                    id: "missing".to_string(),
+                    name: None,
+                    description: None,
+                    category: None,
+                    tags: None,
+                    updated_at: 0,
+                }
+            ),
+            Err(CacheError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_pagination_and_heads() {
+        let store = MemoryStore::new();
+        for (id, parent, version) in [("a", "a", 1), ("a2", "a", 2), ("b", "b", 1), ("c", "c", 3)] {
+            store
+                .insert_prompt(prompt(id, parent, version, None))
+                .unwrap();
+        }
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = store
+                .get_prompts(PromptQuery {
+                    sort: PromptSort::Name,
+                    order: SortOrder::Asc,
+                    limit: 3,
+                    cursor,
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(page.total, 4);
+            seen.extend(page.items.into_iter().map(|p| p.id));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen, vec!["a", "a2", "b", "c"]);
+
+        let heads = store
+            .get_prompt_heads(PromptQuery {
+                sort: PromptSort::Name,
+                order: SortOrder::Asc,
+                ..Default::default()
+            })
+            .unwrap();
+        let heads: Vec<(String, i64)> = heads
+            .items
+            .into_iter()
+            .map(|head| (head.prompt.id, head.version_count))
+            .collect();
+        assert_eq!(
+            heads,
+            vec![
+                ("a2".to_string(), 2),
+                ("b".to_string(), 1),
+                ("c".to_string(), 1)
+            ]
+        );
+
+        // Any version resolves to the head of its lineage
+        assert_eq!(latest_version(&store, "a", false).unwrap().id, "a2");
+        assert_eq!(latest_version(&store, "a2", false).unwrap().id, "a2");
+        store.delete_prompt("c").unwrap();
+        assert!(matches!(
+            latest_version(&store, "c", false),
+            Err(CacheError::Archived)
+        ));
+        assert_eq!(latest_version(&store, "c", true).unwrap().id, "c");
+        assert!(matches!(
+            latest_version(&store, "missing", false),
+            Err(CacheError::NotFound)
+        ));
+
+        let invalid = store.get_prompts(PromptQuery {
+            limit: 0,
+            ..Default::default()
+        });
+        assert!(matches!(invalid, Err(CacheError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_categories_and_similarity() {
+        let store = MemoryStore::new();
+        store
+            .insert_prompt(prompt("a", "a", 1, Some("frontend/react")))
+            .unwrap();
+        store
+            .insert_prompt(prompt("b", "b", 1, Some("frontend")))
+            .unwrap();
+        store
+            .insert_prompt(prompt("c", "c", 1, Some("backend")))
+            .unwrap();
+
+        let page = store
+            .get_prompts(PromptQuery {
+                category: Some("frontend".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 2);
+
+        assert_eq!(store.rename_category("frontend", "web").unwrap(), 2);
+        assert_eq!(
+            store.get_prompt_categories().unwrap(),
+            vec!["backend", "web", "web/react"]
+        );
+        let web = store
+            .get_category_counts()
+            .unwrap()
+            .into_iter()
+            .find(|count| count.name == "web")
+            .unwrap();
+        assert_eq!((web.count, web.total), (1, 2));
+        assert!(matches!(
+            store.merge_categories(&["missing".to_string()], "web"),
+            Err(CacheError::NotFound)
+        ));
+        assert_eq!(
+            store
+                .merge_categories(&["backend".to_string(), "web".to_string()], "web/all")
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            store.get_prompt_categories().unwrap(),
+            vec!["web/all", "web/all/react"]
+        );
+
+        let shared = "You are a helpful assistant that reviews pull requests for style issues";
+        for id in ["d", "e"] {
+            store
+                .insert_prompt(DbPrompt {
+                    content: shared.to_string(),
+                    ..prompt(id, id, 1, None)
+                })
+                .unwrap();
+        }
+        let similar = store.get_similar_prompts("d", 0.8, 10, false).unwrap();
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].id, "e");
+
+        let groups = store.get_duplicate_groups(0.8).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 2);
+
+        let related = store.get_related_prompts("d", 10).unwrap();
+        assert_eq!(related[0].id, "e");
+    }
+}
@@ -0,0 +1,153 @@
+/*
+Ordered schema migrations tracked with `PRAGMA user_version`.
+https://www.sqlite.org/pragma.html#pragma_user_version
+
+Migrations are append-only: never edit one that has shipped, add a new one instead. Each runs in
+its own transaction together with the user_version bump, so a failed migration leaves the
+database at the previous version.
+
+Databases created before migrations existed are at version 0 but may already have some of the
+early tables, so the early migrations use IF NOT EXISTS.
+*/
+
+use rusqlite::Connection;
+
+use crate::cache::CacheError;
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create prompts and metadata tables",
+        sql: "CREATE TABLE IF NOT EXISTS prompts (
+                  id TEXT PRIMARY KEY,
+                  version INTEGER NOT NULL,
+                  content TEXT NOT NULL,
+                  parent TEXT,
+                  branched BOOLEAN,
+                  archived BOOLEAN,
+                  created_at INTEGER NOT NULL
+              );
+              CREATE TABLE IF NOT EXISTS metadata (
+                  id TEXT PRIMARY KEY,
+                  name TEXT,
+                  description TEXT,
+                  category TEXT,
+                  tags TEXT,
+                  updated_at INTEGER NOT NULL
+              );",
+    },
+    Migration {
+        version: 2,
+        description: "Add indexes for keyset pagination",
+        sql: "CREATE INDEX IF NOT EXISTS idx_prompts_created_at ON prompts (created_at, id);
+              CREATE INDEX IF NOT EXISTS idx_prompts_parent ON prompts (parent);
+              CREATE INDEX IF NOT EXISTS idx_metadata_category ON metadata (category);",
+    },
+    Migration {
+        version: 3,
+        description: "Create trash table for archived prompt retention",
+        // Prompts archived before the trash existed start their retention period now
+        sql: "CREATE TABLE IF NOT EXISTS trash (
+                  id TEXT PRIMARY KEY,
+                  archived_at INTEGER NOT NULL
+              );
+              INSERT OR IGNORE INTO trash (id, archived_at)
+              SELECT id, CAST(strftime('%s', 'now') AS INTEGER) FROM prompts
+              WHERE COALESCE(archived, false) = true;",
+    },
+    Migration {
+        version: 4,
+        description: "Create MinHash fingerprint tables for near-duplicate detection",
+        sql: "CREATE TABLE IF NOT EXISTS fingerprints (
+                  id TEXT PRIMARY KEY,
+                  minhash BLOB NOT NULL
+              );
+              CREATE TABLE IF NOT EXISTS fingerprint_bands (
+                  band INTEGER NOT NULL,
+                  bucket INTEGER NOT NULL,
+                  id TEXT NOT NULL,
+                  PRIMARY KEY (band, bucket, id)
+              );
+              CREATE INDEX IF NOT EXISTS idx_fingerprint_bands_id ON fingerprint_bands (id);",
+    },
+    Migration {
+        version: 5,
+        description: "Create TF-IDF index tables for related prompts",
+        sql: "CREATE TABLE IF NOT EXISTS index_terms (
+                  id TEXT NOT NULL,
+                  term TEXT NOT NULL,
+                  weight REAL NOT NULL,
+                  PRIMARY KEY (id, term)
+              );
+              CREATE INDEX IF NOT EXISTS idx_index_terms_term ON index_terms (term);
+              CREATE TABLE IF NOT EXISTS term_documents (
+                  term TEXT PRIMARY KEY,
+                  documents INTEGER NOT NULL
+              );",
+    },
+    Migration {
+        version: 6,
+        description: "Add the git commit author and message of imported versions",
+        sql: "ALTER TABLE prompts ADD COLUMN author_name TEXT;
+              ALTER TABLE prompts ADD COLUMN author_email TEXT;
+              ALTER TABLE prompts ADD COLUMN commit_message TEXT;",
+    },
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+pub struct MigrationPlan {
+    pub current_version: i64,
+    pub target_version: i64,
+    pub pending: Vec<&'static Migration>,
+}
+
+/// Migrations that have not been applied yet. Fails if the database was migrated by a newer
+/// server, since this one doesn't know what changed.
+pub fn plan(conn: &Connection) -> Result<MigrationPlan, CacheError> {
+    let current_version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let target_version = latest_version();
+    if current_version > target_version {
+        return Err(CacheError::UnsupportedSchema {
+            found: current_version,
+            supported: target_version,
+        });
+    }
+
+    Ok(MigrationPlan {
+        current_version,
+        target_version,
+        pending: MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version > current_version)
+            .collect(),
+    })
+}
+
+/// Applies pending migrations in order and returns the versions applied
+pub fn migrate(conn: &mut Connection) -> Result<Vec<i64>, CacheError> {
+    plan(conn)?
+        .pending
+        .into_iter()
+        .map(|migration| {
+            log::info!(
+                "Applying migration {}: {}",
+                migration.version,
+                migration.description
+            );
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration.sql)?;
+            tx.pragma_update(None, "user_version", migration.version)?;
+            tx.commit()?;
+            Ok(migration.version)
+        })
+        .collect()
+}
@@ -0,0 +1,250 @@
+/*
+Content negotiation for read endpoints. JSON stays the default and is what a request without
+`Accept` gets. Prompts and listings can also be had as YAML or MessagePack, and the content of a
+prompt as raw text, so shell scripts don't have to decode a JSON string.
+
+`Accept` is ranked by q-value, the most specific media range deciding the q-value of a format. Ties
+go to the format the endpoint offers first. A request accepting none of them is answered with 406.
+*/
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use log::error;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::api_error::{ApiError, ErrorCode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    MsgPack,
+    /// Raw text, only for strings
+    PlainText,
+    /// Raw text, only for strings
+    Markdown,
+}
+
+/// What endpoints returning prompts offer
+pub const STRUCTURED: &[Format] = &[Format::Json, Format::Yaml, Format::MsgPack];
+
+/// What the content endpoint offers
+pub const CONTENT: &[Format] = &[
+    Format::Json,
+    Format::PlainText,
+    Format::Markdown,
+    Format::Yaml,
+    Format::MsgPack,
+];
+
+#[derive(Error, Debug)]
+pub enum EncodeError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    MsgPack(#[from] rmp_serde::encode::Error),
+    #[error("Only strings can be sent as text")]
+    NotText,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Yaml => "application/yaml",
+            Self::MsgPack => "application/msgpack",
+            Self::PlainText => "text/plain; charset=utf-8",
+            Self::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    /// Media types the format is known by, the first one being its own
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Self::Json => &["application/json"],
+            Self::Yaml => &["application/yaml", "application/x-yaml", "text/yaml"],
+            Self::MsgPack => &[
+                "application/msgpack",
+                "application/x-msgpack",
+                "application/vnd.msgpack",
+            ],
+            Self::PlainText => &["text/plain"],
+            Self::Markdown => &["text/markdown"],
+        }
+    }
+
+    /// How specifically `range` names this format, or None if it doesn't match it
+    fn specificity(self, range: &str) -> Option<u8> {
+        if self.media_types().contains(&range) {
+            return Some(2);
+        }
+        match range.split_once('/') {
+            Some(("*", "*")) => Some(0),
+            Some((kind, "*")) => self
+                .media_types()
+                .iter()
+                .any(|media| media.split('/').next() == Some(kind))
+                .then_some(1),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, EncodeError> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            Self::Yaml => Ok(serde_yaml::to_string(value)?.into_bytes()),
+            Self::MsgPack => Ok(rmp_serde::to_vec_named(value)?),
+            Self::PlainText | Self::Markdown => match serde_json::to_value(value)? {
+                serde_json::Value::String(text) => Ok(text.into_bytes()),
+                _ => Err(EncodeError::NotText),
+            },
+        }
+    }
+}
+
+/// Media ranges of `Accept` with their q-values, ie `text/*;q=0.5` is ("text/*", 0.5)
+fn media_ranges(headers: &HeaderMap) -> Vec<(String, f32)> {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let range = parts.next().filter(|range| !range.is_empty())?;
+            let q = parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((range.to_ascii_lowercase(), q))
+        })
+        .collect()
+}
+
+/// The offered format `Accept` ranks highest
+pub fn negotiate(headers: &HeaderMap, offered: &[Format]) -> Result<Format, ApiError> {
+    let ranges = media_ranges(headers);
+    if ranges.is_empty() {
+        return Ok(offered[0]);
+    }
+    let mut best: Option<(Format, f32)> = None;
+    for &format in offered {
+        let q = ranges
+            .iter()
+            .filter_map(|(range, q)| {
+                format
+                    .specificity(range)
+                    .map(|specificity| (specificity, *q))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, q)| q)
+            .unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((format, q));
+        }
+    }
+    best.map(|(format, _)| format).ok_or_else(|| {
+        let acceptable = offered
+            .iter()
+            .map(|format| format.media_types()[0])
+            .collect::<Vec<_>>()
+            .join(", ");
+        ApiError::new(
+            ErrorCode::NotAcceptable,
+            format!("Acceptable representations are {}", acceptable),
+        )
+    })
+}
+
+/// `value` in `format`, marked as depending on `Accept`
+pub fn respond<T: Serialize>(format: Format, value: &T) -> Result<Response, ApiError> {
+    let body = encode(format, value)?;
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(format.content_type()),
+            ),
+            (header::VARY, HeaderValue::from_static("accept")),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// `format.encode`, with failures logged and reported as internal errors
+pub fn encode<T: Serialize>(format: Format, value: &T) -> Result<Vec<u8>, ApiError> {
+    format.encode(value).map_err(|e| {
+        error!("Failed to encode response as {:?}: {:?}", format, e);
+        ApiError::internal()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let negotiated = |value: &str, offered| negotiate(&accept(value), offered).ok();
+        assert_eq!(
+            negotiate(&HeaderMap::new(), CONTENT).ok(),
+            Some(Format::Json)
+        );
+        assert_eq!(negotiated("*/*", CONTENT), Some(Format::Json));
+        assert_eq!(negotiated("text/plain", CONTENT), Some(Format::PlainText));
+        assert_eq!(negotiated("text/*", CONTENT), Some(Format::PlainText));
+        assert_eq!(negotiated("Text/Markdown", CONTENT), Some(Format::Markdown));
+        assert_eq!(
+            negotiated("application/json;q=0.5, application/x-yaml", STRUCTURED),
+            Some(Format::Yaml)
+        );
+        // The specific range wins over the wildcard
+        assert_eq!(
+            negotiated("application/*, application/json;q=0", STRUCTURED),
+            Some(Format::Yaml)
+        );
+        assert_eq!(negotiated("text/plain", STRUCTURED), None);
+        assert_eq!(negotiated("text/plain;q=0", CONTENT), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let content = "Line one\nLine \"two\"".to_string();
+        assert_eq!(
+            Format::PlainText.encode(&content).unwrap(),
+            content.as_bytes()
+        );
+        assert_eq!(
+            Format::Json.encode(&content).unwrap(),
+            br#""Line one\nLine \"two\"""#
+        );
+        assert!(matches!(
+            Format::Markdown.encode(&vec![1]),
+            Err(EncodeError::NotText)
+        ));
+
+        let value = serde_json::json!({ "id": "a", "tags": ["x"] });
+        let yaml = Format::Yaml.encode(&value).unwrap();
+        assert_eq!(
+            serde_yaml::from_slice::<serde_json::Value>(&yaml).unwrap(),
+            value
+        );
+        let msgpack = Format::MsgPack.encode(&value).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<serde_json::Value>(&msgpack).unwrap(),
+            value
+        );
+    }
+}
@@ -0,0 +1,368 @@
+/*
+Read-through cache for prompt content, in front of any `PromptStore`.
+
+SDKs resolve the latest content of a prompt on every LLM call, so both content lookups are served
+from a bounded LRU. Writes going through this process invalidate the affected entries. The TTL
+bounds staleness for writes that don't, ie the migrate CLI or another process sharing the database.
+
+A read that misses records the cache generation before going to the store, and only fills the
+cache if no write invalidated anything in the meantime. Otherwise a slow read could put back a
+value a concurrent write just replaced.
+*/
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use lru::LruCache;
+
+use crate::cache::{
+    CacheError, CacheResult, DbCategoryCount, DbMetadataPatch, DbPrompt, DbPromptHead,
+    DbPromptMetadata, DbSimilarPrompt, PromptPage, PromptQuery,
+};
+use crate::store::PromptStore;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ContentKey {
+    /// Content of the prompt with this id
+    Prompt(String),
+    /// Content of the latest version in the lineage rooted at this id. Lookups through any
+    /// version share it, so keys are always lineage roots
+    Latest(String),
+}
+
+struct Entries {
+    lru: LruCache<ContentKey, (String, Instant)>,
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+pub struct CachedStore {
+    inner: Arc<dyn PromptStore>,
+    entries: Mutex<Entries>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CachedStore {
+    pub fn new(inner: Arc<dyn PromptStore>, capacity: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            inner,
+            entries: Mutex::new(Entries {
+                lru: LruCache::new(capacity),
+                generation: 0,
+            }),
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn entries(&self) -> CacheResult<MutexGuard<'_, Entries>> {
+        self.entries
+            .lock()
+            .map_err(|e| CacheError::UnhandledError(e.to_string()))
+    }
+
+    fn read_through(
+        &self,
+        key: ContentKey,
+        load: impl FnOnce() -> CacheResult<String>,
+    ) -> CacheResult<String> {
+        let generation = {
+            let mut entries = self.entries()?;
+            match entries.lru.get(&key) {
+                Some((content, cached_at)) if cached_at.elapsed() < self.ttl => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(content.clone());
+                }
+                Some(_) => {
+                    entries.lru.pop(&key);
+                }
+                None => {}
+            }
+            entries.generation
+        };
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let content = load()?;
+
+        let mut entries = self.entries()?;
+        if entries.generation == generation {
+            entries.lru.put(key, (content.clone(), Instant::now()));
+        }
+        Ok(content)
+    }
+
+    fn invalidate(&self, keys: impl IntoIterator<Item = ContentKey>) -> CacheResult<()> {
+        let mut entries = self.entries()?;
+        entries.generation += 1;
+        for key in keys {
+            entries.lru.pop(&key);
+        }
+        Ok(())
+    }
+
+    /// Drops the prompt's own content and the latest content of its lineage
+    fn invalidate_prompt(&self, id: &str, parent: Option<&str>) -> CacheResult<()> {
+        debug!("Invalidating cached content for {}", id);
+        let mut keys = vec![
+            ContentKey::Prompt(id.to_string()),
+            ContentKey::Latest(id.to_string()),
+        ];
+        keys.extend(parent.map(|parent| ContentKey::Latest(parent.to_string())));
+        self.invalidate(keys)
+    }
+
+    fn parent_of(&self, id: &str) -> CacheResult<Option<String>> {
+        Ok(self.inner.get_prompt(id, None)?.map(|prompt| prompt.parent))
+    }
+}
+
+impl PromptStore for CachedStore {
+    fn insert_prompt(&self, prompt: DbPrompt) -> CacheResult<DbPrompt> {
+        let prompt = self.inner.insert_prompt(prompt)?;
+        self.invalidate_prompt(&prompt.id, Some(&prompt.parent))?;
+        Ok(prompt)
+    }
+
+    fn insert_prompts(&self, prompts: Vec<DbPrompt>) -> CacheResult<Vec<DbPrompt>> {
+        let prompts = self.inner.insert_prompts(prompts)?;
+        for prompt in &prompts {
+            self.invalidate_prompt(&prompt.id, Some(&prompt.parent))?;
+        }
+        Ok(prompts)
+    }
+
+    fn get_prompt(&self, id: &str, metadata: Option<bool>) -> CacheResult<Option<DbPrompt>> {
+        self.inner.get_prompt(id, metadata)
+    }
+
+    fn get_prompt_content(&self, id: &str) -> CacheResult<String> {
+        self.read_through(ContentKey::Prompt(id.to_string()), || {
+            self.inner.get_prompt_content(id)
+        })
+    }
+
+    fn get_prompt_content_latest_version(&self, id: &str) -> CacheResult<String> {
+        let Some(root) = self.parent_of(id)? else {
+            return self.inner.get_prompt_content_latest_version(id);
+        };
+        self.read_through(ContentKey::Latest(root), || {
+            self.inner.get_prompt_content_latest_version(id)
+        })
+    }
+
+    fn get_prompts(&self, query: PromptQuery) -> CacheResult<PromptPage> {
+        self.inner.get_prompts(query)
+    }
+
+    fn get_prompt_heads(&self, query: PromptQuery) -> CacheResult<PromptPage<DbPromptHead>> {
+        self.inner.get_prompt_heads(query)
+    }
+
+    fn update_prompt_metadata(&self, id: &str, metadata: DbPromptMetadata) -> CacheResult<String> {
+        let updated = self.inner.update_prompt_metadata(id, metadata)?;
+        self.invalidate_prompt(id, None)?;
+        Ok(updated)
+    }
+
+    fn patch_prompt_metadata(
+        &self,
+        id: &str,
+        patch: DbMetadataPatch,
+    ) -> CacheResult<DbPromptMetadata> {
+        let patched = self.inner.patch_prompt_metadata(id, patch)?;
+        self.invalidate_prompt(id, None)?;
+        Ok(patched)
+    }
+
+    fn delete_prompt(&self, id: &str) -> CacheResult<bool> {
+        let deleted = self.inner.delete_prompt(id)?;
+        self.invalidate_prompt(id, self.parent_of(id)?.as_deref())?;
+        Ok(deleted)
+    }
+
+    fn restore_prompt(&self, id: &str) -> CacheResult<()> {
+        self.inner.restore_prompt(id)?;
+        self.invalidate_prompt(id, self.parent_of(id)?.as_deref())
+    }
+
+    fn purge_prompt(&self, id: &str) -> CacheResult<bool> {
+        let parent = self.parent_of(id)?;
+        let purged = self.inner.purge_prompt(id)?;
+        self.invalidate_prompt(id, parent.as_deref())?;
+        Ok(purged)
+    }
+
+    fn purge_expired(&self, retention: Duration) -> CacheResult<usize> {
+        let purged = self.inner.purge_expired(retention)?;
+        if purged > 0 {
+            // Purged ids aren't known here, start over
+            let mut entries = self.entries()?;
+            entries.generation += 1;
+            entries.lru.clear();
+        }
+        Ok(purged)
+    }
+
+    fn get_prompt_categories(&self) -> CacheResult<Vec<String>> {
+        self.inner.get_prompt_categories()
+    }
+
+    fn get_category_counts(&self) -> CacheResult<Vec<DbCategoryCount>> {
+        self.inner.get_category_counts()
+    }
+
+    fn merge_categories(&self, sources: &[String], target: &str) -> CacheResult<usize> {
+        self.inner.merge_categories(sources, target)
+    }
+
+    fn get_similar_prompts(
+        &self,
+        id: &str,
+        threshold: f64,
+        limit: usize,
+        include_lineage: bool,
+    ) -> CacheResult<Vec<DbSimilarPrompt>> {
+        self.inner
+            .get_similar_prompts(id, threshold, limit, include_lineage)
+    }
+
+    fn get_duplicate_groups(&self, threshold: f64) -> CacheResult<Vec<Vec<DbSimilarPrompt>>> {
+        self.inner.get_duplicate_groups(threshold)
+    }
+
+    fn get_related_prompts(&self, id: &str, limit: usize) -> CacheResult<Vec<DbSimilarPrompt>> {
+        self.inner.get_related_prompts(id, limit)
+    }
+
+    fn read_cache_stats(&self) -> Option<ReadCacheStats> {
+        let entries = self.entries().ok()?;
+        Some(ReadCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.lru.len(),
+            capacity: entries.lru.cap().get(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+
+    fn prompt(id: &str, parent: &str, version: i32) -> DbPrompt {
+        DbPrompt {
+            id: id.to_string(),
+            version,
+            content: format!("Content of {}", id),
+            parent: parent.to_string(),
+            branched: Some(false),
+            archived: Some(false),
+            created_at: 1_000 + i64::from(version),
+            metadata: None,
+            commit: None,
+        }
+    }
+
+    fn cached_store(capacity: usize, ttl: Duration) -> CachedStore {
+        CachedStore::new(
+            Arc::new(MemoryStore::new()),
+            NonZeroUsize::new(capacity).unwrap(),
+            ttl,
+        )
+    }
+
+    #[test]
+    fn test_read_through_and_invalidation() {
+        let store = cached_store(10, Duration::from_secs(60));
+        store.insert_prompt(prompt("a", "a", 1)).unwrap();
+
+        assert_eq!(
+            store.get_prompt_content_latest_version("a").unwrap(),
+            "Content of a"
+        );
+        assert_eq!(
+            store.get_prompt_content_latest_version("a").unwrap(),
+            "Content of a"
+        );
+        let stats = store.read_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+        // A new version replaces the cached latest content
+        store.insert_prompt(prompt("a2", "a", 2)).unwrap();
+        assert_eq!(
+            store.get_prompt_content_latest_version("a").unwrap(),
+            "Content of a2"
+        );
+
+        // Archiving it falls back to the previous version
+        store.get_prompt_content("a2").unwrap();
+        store.delete_prompt("a2").unwrap();
+        assert!(matches!(
+            store.get_prompt_content("a2"),
+            Err(CacheError::Archived)
+        ));
+        assert_eq!(
+            store.get_prompt_content_latest_version("a").unwrap(),
+            "Content of a"
+        );
+
+        // Lookups through a version share the lineage's entry, so they see new versions too
+        store.restore_prompt("a2").unwrap();
+        assert_eq!(
+            store.get_prompt_content_latest_version("a2").unwrap(),
+            "Content of a2"
+        );
+        store.insert_prompt(prompt("a3", "a2", 3)).unwrap();
+        assert_eq!(
+            store.get_prompt_content_latest_version("a2").unwrap(),
+            "Content of a3"
+        );
+        assert_eq!(
+            store.get_prompt_content_latest_version("a").unwrap(),
+            "Content of a3"
+        );
+        let hits = store.read_cache_stats().unwrap().hits;
+
+        // Errors aren't cached
+        assert!(store.get_prompt_content("missing").is_err());
+        assert!(store.get_prompt_content("missing").is_err());
+        assert!(store.get_prompt_content_latest_version("missing").is_err());
+        assert_eq!(store.read_cache_stats().unwrap().hits, hits);
+    }
+
+    #[test]
+    fn test_eviction_and_expiry() {
+        let store = cached_store(2, Duration::from_secs(60));
+        for id in ["a", "b", "c"] {
+            store.insert_prompt(prompt(id, id, 1)).unwrap();
+            store.get_prompt_content(id).unwrap();
+        }
+        let stats = store.read_cache_stats().unwrap();
+        assert_eq!((stats.entries, stats.capacity), (2, 2));
+
+        // The least recently used entry was evicted
+        store.get_prompt_content("a").unwrap();
+        assert_eq!(store.read_cache_stats().unwrap().hits, 0);
+
+        let store = cached_store(2, Duration::ZERO);
+        store.insert_prompt(prompt("a", "a", 1)).unwrap();
+        store.get_prompt_content("a").unwrap();
+        store.get_prompt_content("a").unwrap();
+        let stats = store.read_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (0, 2));
+    }
+}
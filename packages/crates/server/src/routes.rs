@@ -1,15 +1,64 @@
+use crate::api_error::{
+    ApiError, ApiJson, ApiPath, ApiQuery, ApiText, ErrorCode, ProblemDetails, ServiceUnavailable,
+};
 use crate::api_models::{
-    CreatePromptError, CreatePromptRequest, DeletePromptError, GetPromptContentRequest,
-    GetPromptError, GetPromptRequest, GetPromptsError, GetPromptsRequest, Prompt,
-    UpdateMetadataError, UpdateMetadataRequest,
+    BackupSnapshot, BatchCreatePromptsRequest, BatchCreatePromptsResponse, BatchCreateResult,
+    BatchGetPromptsRequest, BatchGetPromptsResponse, BatchGetResult, CacheStats, CategoryCount,
+    CreatePromptRequest, DuplicateGroup, ExportBundle, ExportRequest, ExportedPrompt,
+    GetDuplicatesRequest, GetPromptContentRequest, GetPromptRequest, GetPromptsRequest,
+    GetRelatedPromptsRequest, GetSimilarPromptsRequest, GetTrashRequest, ImportReport,
+    ImportRequest, MergeCategoriesRequest, PatchMetadataRequest, Prompt, PromptList,
+    PromptMetadata, PromptView, RenameCategoryRequest, SimilarPrompt, UpdateCategoriesResponse,
+    UpdateMetadataRequest, DEFAULT_SIMILARITY_THRESHOLD,
 };
 use axum::{
-    extract::{Path, Query, State},
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    api_version,
+    cache::{now_timestamp, CacheError, CacheResult, PromptPage, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+    http_cache::{conditional, Freshness},
+    negotiation::{self, Format},
+    store::{all_versions, latest_version, versions_page, PromptStore},
+    transfer::{self, ConflictPolicy, ExportFormat, IdMode, ImportOptions},
+    validation, AppState,
+};
 
-use crate::{cache::CacheError, AppState};
+/// RFC 7240 headers, which `http` has no constants for
+const PREFER: HeaderName = HeaderName::from_static("prefer");
+const PREFERENCE_APPLIED: HeaderName = HeaderName::from_static("preference-applied");
+
+/// Pages of an NDJSON export serialized ahead of a slow client
+const EXPORT_BUFFERED_PAGES: usize = 4;
+
+/// Runs a store call on the blocking thread pool so slow queries and lock waits don't stall the
+/// async runtime. Running out of database connections comes back as `ServiceUnavailable`, the
+/// caller maps every other error.
+async fn with_store<T, F>(state: &AppState, f: F) -> Result<CacheResult<T>, ServiceUnavailable>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn PromptStore) -> CacheResult<T> + Send + 'static,
+{
+    let store = state.store.clone();
+    let result = tokio::task::spawn_blocking(move || f(store.as_ref()))
+        .await
+        .unwrap_or_else(|e| Err(CacheError::UnhandledError(e.to_string())));
+    match result {
+        Err(CacheError::PoolError(e)) => {
+            warn!("No database connection available: {}", e);
+            Err(ServiceUnavailable)
+        }
+        result => Ok(result),
+    }
+}
 
 /// Get entire prompt with option to include metadata
 #[utoipa::path(
@@ -17,34 +66,59 @@ use crate::{cache::CacheError, AppState};
     path = "/prompt/{id}",
     params(
         ("id" = String, Path, description = "Prompt identifier"),
-        ("metadata" = Option<bool>, Query, description = "Whether to include metadata in the response")
+        ("metadata" = Option<bool>, Query, description = "Whether to include metadata in the response"),
+        ("include_archived" = Option<bool>, Query, description = "Whether to return the prompt even if it is archived"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response, answered with 304 if it still matches")
     ),
     responses(
-        (status = StatusCode::OK, description = "Successly retrieved prompt", body = Prompt),
-        (status = StatusCode::NOT_FOUND, description = "Prompt not found"),
-        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error")
+        (status = StatusCode::OK, description = "Successly retrieved prompt, as JSON unless Accept asks for YAML or MessagePack. Metadata and the archived flag can change so it is cached briefly.", content(
+            (Prompt = "application/json"),
+            (Prompt = "application/yaml"),
+            (Prompt = "application/msgpack")
+        ), headers(
+            ("ETag" = String, description = "Strong validator, a hash of the body"),
+            ("Cache-Control" = String, description = "Short max-age")
+        )),
+        (status = StatusCode::NOT_MODIFIED, description = "The ETag in If-None-Match still matches"),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::NOT_FOUND, description = "Prompt not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::GONE, description = "Prompt is archived", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed query string", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
     )
 )]
 #[axum_macros::debug_handler]
 pub async fn get_prompt(
     State(state): State<AppState>,
-    Path(id): Path<String>,
-    Query(params): Query<GetPromptRequest>,
-) -> Result<Json<Prompt>, GetPromptError> {
+    ApiPath(id): ApiPath<String>,
+    ApiQuery(params): ApiQuery<GetPromptRequest>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
     info!("Requested prompt with id: {}", id);
+    let format = negotiation::negotiate(&headers, negotiation::STRUCTURED)?;
 
-    let db_prompt = state
-        .cache
-        .get_prompt(&id, params.metadata)
+    let metadata = params.metadata;
+    let prompt_id = id.clone();
+    let db_prompt = with_store(&state, move |store| store.get_prompt(&prompt_id, metadata))
+        .await?
         .map_err(|e| {
             error!("Failed to get prompt for id {}: {:?}", id, e);
-            GetPromptError::InternalServerError
+            ApiError::internal()
         })?
-        .ok_or(GetPromptError::NotFound)?;
+        .ok_or_else(|| ApiError::prompt_not_found(&id))?;
 
-    let prompt = Prompt::from(db_prompt);
+    if db_prompt.archived == Some(true) && !params.include_archived.unwrap_or(false) {
+        return Err(ApiError::prompt_archived(&id));
+    }
 
-    Ok(Json(prompt))
+    let body = negotiation::encode(format, &Prompt::from(db_prompt))?;
+    Ok(conditional(
+        &headers,
+        format.content_type(),
+        body,
+        Freshness::Short,
+    ))
 }
 
 /// Get prompt content
@@ -53,111 +127,569 @@ pub async fn get_prompt(
     path = "/prompt/{id}/content",
     params(
         ("id" = String, Path, description = "Prompt identifier"),
-        ("latest" = Option<bool>, Query, description = "Latest version of the prompt")
+        ("latest" = Option<bool>, Query, description = "Content of the highest non-archived version in the lineage of id, which can be any of its versions"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a previous response, answered with 304 if it still matches")
     ),
     responses(
-        (status = StatusCode::OK, description = "Successly retrieved prompt content", body = String),
-        (status = StatusCode::NOT_FOUND, description = "Prompt not found"),
-        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error")
+        (status = StatusCode::OK, description = "Successly retrieved prompt content, as a JSON string unless Accept asks for raw text (text/plain, text/markdown), YAML or MessagePack. Content of a version never changes and is cacheable for good, the latest version is cached briefly.", content(
+            (String = "application/json"),
+            (String = "text/plain"),
+            (String = "text/markdown"),
+            (String = "application/yaml"),
+            (String = "application/msgpack")
+        ), headers(
+            ("ETag" = String, description = "Strong validator, a hash of the content"),
+            ("Cache-Control" = String, description = "immutable for a version id, a short max-age with latest")
+        )),
+        (status = StatusCode::NOT_MODIFIED, description = "The ETag in If-None-Match still matches"),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::NOT_FOUND, description = "Prompt not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::GONE, description = "Prompt is archived, or with latest every version of its lineage is", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed query string", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
     )
 )]
 #[axum_macros::debug_handler]
 pub async fn get_prompt_content(
     State(state): State<AppState>,
-    Path(id): Path<String>,
-    Query(params): Query<GetPromptContentRequest>,
-) -> Result<Json<String>, GetPromptError> {
+    ApiPath(id): ApiPath<String>,
+    ApiQuery(params): ApiQuery<GetPromptContentRequest>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
     info!("Requested prompt with id: {}", id);
+    let format = negotiation::negotiate(&headers, negotiation::CONTENT)?;
 
-    let content = match params.latest {
-        Some(true) => state.cache.get_prompt_content_latest_version(&id),
-        _ => state.cache.get_prompt_content(&id),
-    }
+    // A version's content never changes, the latest version of a lineage does
+    let freshness = match params.latest {
+        Some(true) => Freshness::Short,
+        _ => Freshness::Immutable,
+    };
+    let prompt_id = id.clone();
+    let content = with_store(&state, move |store| match params.latest {
+        Some(true) => store.get_prompt_content_latest_version(&prompt_id),
+        _ => store.get_prompt_content(&prompt_id),
+    })
+    .await?
     .map_err(|e| {
         error!("Failed to get prompt content for id {}: {:?}", id, e);
         match e {
-            CacheError::NotFound => GetPromptError::NotFound,
-            _ => GetPromptError::InternalServerError,
+            CacheError::NotFound => ApiError::prompt_not_found(&id),
+            CacheError::Archived => ApiError::prompt_archived(&id),
+            _ => ApiError::internal(),
         }
     })?;
-
-    Ok(Json(content))
+    let body = negotiation::encode(format, &content)?;
+    Ok(conditional(
+        &headers,
+        format.content_type(),
+        body,
+        freshness,
+    ))
 }
 
+/// Get category names
+///
+/// Superseded by `/categories`, which counts the prompts in each category as well
+#[utoipa::path(
+    get,
+    path = "/prompt/categories",
+    responses(
+        (status = StatusCode::OK, description = "Successly retrieved category names", body = Vec<String>),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[deprecated = "use get_categories, served at /categories"]
 #[axum_macros::debug_handler]
 pub async fn get_prompt_categories(
     State(state): State<AppState>,
-) -> Result<Json<Vec<String>>, GetPromptError> {
-    let categories = state.cache.get_prompt_categories().map_err(|e| {
-        error!("Failed to get prompt categories: {:?}", e);
-        GetPromptError::InternalServerError
-    })?;
+) -> Result<Json<Vec<String>>, ApiError> {
+    let categories = with_store(&state, |store| store.get_prompt_categories())
+        .await?
+        .map_err(|e| {
+            error!("Failed to get prompt categories: {:?}", e);
+            ApiError::internal()
+        })?;
     Ok(Json(categories))
 }
 
-/// Get list of prompts with pagination
+/// Get categories with their prompt counts
+#[utoipa::path(
+    get,
+    path = "/categories",
+    responses(
+        (status = StatusCode::OK, description = "Successly retrieved categories, as JSON unless Accept asks for YAML or MessagePack", content(
+            (Vec<CategoryCount> = "application/json"),
+            (Vec<CategoryCount> = "application/yaml"),
+            (Vec<CategoryCount> = "application/msgpack")
+        )),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn get_categories(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let format = negotiation::negotiate(&headers, negotiation::STRUCTURED)?;
+    let categories = with_store(&state, |store| store.get_category_counts())
+        .await?
+        .map_err(|e| {
+            error!("Failed to get category counts: {:?}", e);
+            ApiError::internal()
+        })?;
+    let categories: Vec<CategoryCount> = categories.into_iter().map(CategoryCount::from).collect();
+    negotiation::respond(format, &categories)
+}
+
+/// Get hit and miss counters of the prompt content cache
+#[utoipa::path(
+    get,
+    path = "/cache/stats",
+    responses(
+        (status = StatusCode::OK, description = "Successfully retrieved cache stats", body = CacheStats),
+        (status = StatusCode::NOT_FOUND, description = "Content cache is disabled", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn get_cache_stats(State(state): State<AppState>) -> Result<Json<CacheStats>, ApiError> {
+    state
+        .store
+        .read_cache_stats()
+        .map(|stats| Json(CacheStats::from(stats)))
+        .ok_or_else(|| ApiError::new(ErrorCode::CacheDisabled, "The content cache is disabled"))
+}
+
+/// Take a snapshot of the database while it keeps serving traffic
+#[utoipa::path(
+    post,
+    path = "/admin/backups",
+    responses(
+        (status = StatusCode::CREATED, description = "Successfully took snapshot", body = BackupSnapshot),
+        (status = StatusCode::NOT_FOUND, description = "Backups are not enabled", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn create_backup(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<BackupSnapshot>), ApiError> {
+    let backups = state.backups.ok_or_else(backups_disabled)?;
+    let snapshot = tokio::task::spawn_blocking(move || backups.snapshot())
+        .await
+        .unwrap_or_else(|e| Err(CacheError::UnhandledError(e.to_string())))
+        .map_err(|e| {
+            error!("Failed to take snapshot: {:?}", e);
+            ApiError::internal()
+        })?;
+    Ok((StatusCode::CREATED, Json(BackupSnapshot::from(snapshot))))
+}
+
+/// List database snapshots, newest first
+#[utoipa::path(
+    get,
+    path = "/admin/backups",
+    responses(
+        (status = StatusCode::OK, description = "Successfully retrieved snapshots", body = Vec<BackupSnapshot>),
+        (status = StatusCode::NOT_FOUND, description = "Backups are not enabled", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn get_backups(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<BackupSnapshot>>, ApiError> {
+    let backups = state.backups.ok_or_else(backups_disabled)?;
+    let snapshots = tokio::task::spawn_blocking(move || backups.list())
+        .await
+        .unwrap_or_else(|e| Err(CacheError::UnhandledError(e.to_string())))
+        .map_err(|e| {
+            error!("Failed to list snapshots: {:?}", e);
+            ApiError::internal()
+        })?;
+    Ok(Json(
+        snapshots.into_iter().map(BackupSnapshot::from).collect(),
+    ))
+}
+
+fn backups_disabled() -> ApiError {
+    ApiError::new(ErrorCode::BackupsDisabled, "Backups are not enabled")
+}
+
+fn map_update_categories_error(e: CacheError) -> ApiError {
+    error!("Failed to update categories: {:?}", e);
+    match e {
+        CacheError::InvalidRequest(message) => ApiError::new(ErrorCode::InvalidRequest, message),
+        CacheError::NotFound => {
+            ApiError::new(ErrorCode::CategoryNotFound, "No prompt is in the category")
+        }
+        _ => ApiError::internal(),
+    }
+}
+
+/// Rename a category along with its nested categories
+#[utoipa::path(
+    post,
+    path = "/categories/rename",
+    request_body = RenameCategoryRequest,
+    responses(
+        (status = StatusCode::OK, description = "Successfully renamed category", body = UpdateCategoriesResponse),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid request body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::NOT_FOUND, description = "Category not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn rename_category(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<RenameCategoryRequest>,
+) -> Result<Json<UpdateCategoriesResponse>, ApiError> {
+    info!("Renaming category: {:?}", request);
+    with_store(&state, move |store| {
+        store.rename_category(&request.from, &request.to)
+    })
+    .await?
+    .map(|updated| Json(UpdateCategoriesResponse { updated }))
+    .map_err(map_update_categories_error)
+}
+
+/// Merge categories into a single category
+#[utoipa::path(
+    post,
+    path = "/categories/merge",
+    request_body = MergeCategoriesRequest,
+    responses(
+        (status = StatusCode::OK, description = "Successfully merged categories", body = UpdateCategoriesResponse),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid request body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::NOT_FOUND, description = "None of the categories exist", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn merge_categories(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<MergeCategoriesRequest>,
+) -> Result<Json<UpdateCategoriesResponse>, ApiError> {
+    info!("Merging categories: {:?}", request);
+    with_store(&state, move |store| {
+        store.merge_categories(&request.sources, &request.target)
+    })
+    .await?
+    .map(|updated| Json(UpdateCategoriesResponse { updated }))
+    .map_err(map_update_categories_error)
+}
+
+/// Get list of prompts with filtering, sorting and cursor pagination
 #[utoipa::path(
     get,
     path = "/prompts",
     params(
-        ("category" = Option<String>, Query, description = "The category of the prompts to return"),
-        ("offset" = Option<u32>, Query, description = "The pagination offset to start from (0-based). Default is 0."),
-        ("limit" = Option<u32>, Query, description = "The number of prompts to return. Default is 10.")
+        ("view" = Option<String>, Query, description = "versions lists every version, heads lists one entry per lineage with its head version and version_count. Default is versions."),
+        ("category" = Option<String>, Query, description = "The category of the prompts to return, including nested categories"),
+        ("tag" = Option<String>, Query, description = "Only return prompts with this tag"),
+        ("created_after" = Option<i64>, Query, description = "Only return prompts created at or after this unix timestamp"),
+        ("created_before" = Option<i64>, Query, description = "Only return prompts created before this unix timestamp"),
+        ("branched" = Option<bool>, Query, description = "Filter on whether the prompt is branched"),
+        ("archived" = Option<bool>, Query, description = "Filter on whether the prompt is archived"),
+        ("include_archived" = Option<bool>, Query, description = "Whether to include archived prompts. Default is false."),
+        ("parent" = Option<String>, Query, description = "Only return prompts with this parent"),
+        ("sort" = Option<String>, Query, description = "Sort field: created_at, updated_at or name. Default is created_at."),
+        ("order" = Option<String>, Query, description = "Sort direction: asc or desc. Default is desc."),
+        ("cursor" = Option<String>, Query, description = "Cursor returned as next_cursor by the previous page"),
+        ("limit" = Option<u32>, Query, description = "The number of prompts to return (1-100). Default is 10.")
     ),
     responses(
-        (status = StatusCode::OK, description = "Successly retrieved all prompts", body = Vec<Prompt>),
-        (status = StatusCode::BAD_REQUEST, description = "Invalid request body")
+        (status = StatusCode::OK, description = "Successly retrieved prompts, as JSON unless Accept asks for YAML or MessagePack", content(
+            (PromptList = "application/json"),
+            (PromptList = "application/yaml"),
+            (PromptList = "application/msgpack")
+        )),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid limit or cursor", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
     )
 )]
 #[axum_macros::debug_handler]
 pub async fn get_prompts(
     State(state): State<AppState>,
-    Query(params): Query<GetPromptsRequest>,
-) -> Result<Json<Vec<Prompt>>, GetPromptsError> {
+    ApiQuery(params): ApiQuery<GetPromptsRequest>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
     info!("Requested prompts with params: {:?}", params);
-    let prompts = state
-        .cache
-        .get_prompts(
-            params.category,
-            params.offset.unwrap_or(0) as u32,
-            params.limit.unwrap_or(10) as u32,
-        )
-        .map_err(|e| {
-            error!("Failed to get prompts: {:?}", e);
-            match e {
-                CacheError::InvalidRequest(_) => GetPromptsError::InvalidRequest,
-                _ => GetPromptsError::InternalServerError,
+    let format = negotiation::negotiate(&headers, negotiation::STRUCTURED)?;
+    validate_limit(params.limit)?;
+    let view = params.view.unwrap_or_default();
+    let query = params.into();
+    with_store(&state, move |store| match view {
+        PromptView::Versions => store.get_prompts(query).map(PromptList::from),
+        PromptView::Heads => store.get_prompt_heads(query).map(PromptList::from),
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to get prompts: {:?}", e);
+        match e {
+            CacheError::InvalidRequest(message) => {
+                ApiError::new(ErrorCode::InvalidRequest, message)
             }
-        })?;
-
-    let prompts = prompts.into_iter().map(Prompt::from).collect();
+            _ => ApiError::internal(),
+        }
+    })
+    .and_then(|list| negotiation::respond(format, &list))
+}
 
-    Ok(Json(prompts))
+/// Page sizes are checked upfront so a bad limit is reported against its field
+fn validate_limit(limit: Option<u32>) -> Result<(), ApiError> {
+    match limit {
+        Some(limit) if limit == 0 || limit > MAX_PAGE_SIZE => Err(ApiError::invalid_field(
+            "limit",
+            format!("must be between 1 and {}", MAX_PAGE_SIZE),
+        )),
+        _ => Ok(()),
+    }
 }
 
 /// Create prompt or update it by passing the parent id
+///
+/// Answers with the created prompt. Callers that only want the id, as older clients did, send
+/// `Prefer: return=minimal` or `Accept: text/plain`.
 #[utoipa::path(
     post,
     path = "/prompt",
+    params(
+        ("Prefer" = Option<String>, Header, description = "`return=minimal` answers with the bare id instead of the prompt"),
+    ),
     request_body = CreatePromptRequest,
     responses(
-        (status = StatusCode::CREATED, description = "Successfully created prompt", body = String),
-        (status = StatusCode::BAD_REQUEST, description = "Invalid request body")
+        (status = StatusCode::CREATED, description = "Successfully created prompt. The full prompt by default, the bare id with `Prefer: return=minimal` or `Accept: text/plain`.", content(
+            (Prompt = "application/json"),
+            (String = "text/plain")
+        ), headers(
+            ("Location" = String, description = "Path of the created prompt, ie /v1/prompt/{id}")
+        )),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed JSON body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNSUPPORTED_MEDIA_TYPE, description = "Body is not JSON", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNPROCESSABLE_ENTITY, description = "Invalid fields, including a parent that does not exist", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
     )
 )]
 #[axum_macros::debug_handler]
 pub async fn create_prompt(
     State(state): State<AppState>,
-    Json(prompt): Json<CreatePromptRequest>,
-) -> Result<String, CreatePromptError> {
-    state
-        .cache
-        .insert_prompt(prompt.into())
-        .map_err(|e| {
-            debug!("Database error: {:?}", e);
-            CreatePromptError::InternalServerError
+    headers: HeaderMap,
+    ApiJson(prompt): ApiJson<CreatePromptRequest>,
+) -> Result<Response, ApiError> {
+    let limits = state.limits;
+    let created = with_store(&state, move |store| {
+        let mut errors = validation::create_request(&prompt, &limits);
+        errors.extend(validation::parent(store, prompt.parent.as_deref())?);
+        if !errors.is_empty() {
+            return Ok(Err(errors));
+        }
+        store.insert_prompt(prompt.into()).map(Ok)
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to create prompt: {:?}", e);
+        match e {
+            CacheError::InvalidRequest(message) => {
+                ApiError::new(ErrorCode::InvalidRequest, message)
+            }
+            CacheError::UnknownParent(parent) => {
+                ApiError::validation(vec![validation::unknown_parent(&parent)])
+            }
+            _ => ApiError::internal(),
+        }
+    })?
+    .map_err(ApiError::validation)?;
+
+    let location = [(header::LOCATION, api_version::prompt_location(&created.id))];
+    if prefers_minimal(&headers) {
+        return Ok((
+            StatusCode::CREATED,
+            location,
+            [(PREFERENCE_APPLIED, "return=minimal")],
+            created.id,
+        )
+            .into_response());
+    }
+    if accepts_plain_text(&headers) {
+        return Ok((StatusCode::CREATED, location, created.id).into_response());
+    }
+    Ok((StatusCode::CREATED, location, Json(Prompt::from(created))).into_response())
+}
+
+/// Whether the client asked for the bare id of a created prompt with `Prefer: return=minimal`
+fn prefers_minimal(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(PREFER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| {
+            preference.split(';').next().unwrap_or_default().trim() == "return=minimal"
+        })
+}
+
+/// Whether `Accept` prefers plain text to JSON, as clients expecting the bare id do
+fn accepts_plain_text(headers: &HeaderMap) -> bool {
+    negotiation::negotiate(headers, &[Format::Json, Format::PlainText])
+        .is_ok_and(|format| format == Format::PlainText)
+}
+
+/// Get many prompts at once
+#[utoipa::path(
+    post,
+    path = "/prompts:batchGet",
+    request_body = BatchGetPromptsRequest,
+    responses(
+        (status = StatusCode::OK, description = "One result per requested id, in order, with the prompt or why it is missing. JSON unless Accept asks for YAML or MessagePack.", content(
+            (BatchGetPromptsResponse = "application/json"),
+            (BatchGetPromptsResponse = "application/yaml"),
+            (BatchGetPromptsResponse = "application/msgpack")
+        )),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed JSON body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNSUPPORTED_MEDIA_TYPE, description = "Body is not JSON", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNPROCESSABLE_ENTITY, description = "Too many ids", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn batch_get_prompts(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(request): ApiJson<BatchGetPromptsRequest>,
+) -> Result<Response, ApiError> {
+    info!("Batch get of {} prompts", request.ids.len());
+    let format = negotiation::negotiate(&headers, negotiation::STRUCTURED)?;
+    let errors = validation::batch_size("ids", request.ids.len(), &state.limits);
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+    let results = with_store(&state, move |store| {
+        request
+            .ids
+            .iter()
+            .map(|id| batch_get_one(store, id, &request))
+            .collect::<CacheResult<Vec<_>>>()
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to batch get prompts: {:?}", e);
+        ApiError::internal()
+    })?;
+    negotiation::respond(format, &BatchGetPromptsResponse { results })
+}
+
+fn batch_get_one(
+    store: &dyn PromptStore,
+    id: &str,
+    request: &BatchGetPromptsRequest,
+) -> CacheResult<BatchGetResult> {
+    let include_archived = request.include_archived.unwrap_or(false);
+    let found = match request.latest {
+        Some(true) => latest_version(store, id, include_archived).map(|mut prompt| {
+            if request.metadata != Some(true) {
+                prompt.metadata = None;
+            }
+            prompt
+        }),
+        _ => match store.get_prompt(id, request.metadata)? {
+            Some(prompt) if prompt.archived == Some(true) && !include_archived => {
+                Err(CacheError::Archived)
+            }
+            Some(prompt) => Ok(prompt),
+            None => Err(CacheError::NotFound),
+        },
+    };
+    let (prompt, error) = match found {
+        Ok(prompt) => (Some(Prompt::from(prompt)), None),
+        Err(CacheError::NotFound) => (None, Some(ErrorCode::PromptNotFound)),
+        Err(CacheError::Archived) => (None, Some(ErrorCode::PromptArchived)),
+        Err(e) => return Err(e),
+    };
+    Ok(BatchGetResult {
+        id: id.to_string(),
+        prompt,
+        error,
+    })
+}
+
+/// Create many prompts at once
+///
+/// Every valid prompt is inserted in one transaction, invalid ones are skipped and reported with
+/// their field errors.
+#[utoipa::path(
+    post,
+    path = "/prompts:batchCreate",
+    request_body = BatchCreatePromptsRequest,
+    responses(
+        (status = StatusCode::OK, description = "One result per prompt, in order, with the created prompt or its field errors", body = BatchCreatePromptsResponse),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed JSON body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNSUPPORTED_MEDIA_TYPE, description = "Body is not JSON", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNPROCESSABLE_ENTITY, description = "Too many prompts", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error, nothing was created", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn batch_create_prompts(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<BatchCreatePromptsRequest>,
+) -> Result<Json<BatchCreatePromptsResponse>, ApiError> {
+    info!("Batch create of {} prompts", request.prompts.len());
+    let limits = state.limits;
+    let errors = validation::batch_size("prompts", request.prompts.len(), &limits);
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+    with_store(&state, move |store| {
+        let mut results = Vec::new();
+        let mut valid = Vec::new();
+        for prompt in request.prompts {
+            let mut errors = validation::create_request(&prompt, &limits);
+            errors.extend(validation::parent(store, prompt.parent.as_deref())?);
+            if errors.is_empty() {
+                valid.push(prompt.into());
+            }
+            results.push(BatchCreateResult {
+                prompt: None,
+                errors,
+            });
+        }
+
+        let mut created = store.insert_prompts(valid)?.into_iter().map(Prompt::from);
+        for result in results.iter_mut().filter(|result| result.errors.is_empty()) {
+            result.prompt = created.next();
+        }
+        Ok(BatchCreatePromptsResponse {
+            created: results
+                .iter()
+                .filter(|result| result.prompt.is_some())
+                .count(),
+            results,
         })
-        .map(|prompt| prompt.id)
+    })
+    .await?
+    .map(Json)
+    .map_err(|e| {
+        error!("Failed to batch create prompts: {:?}", e);
+        match e {
+            CacheError::InvalidRequest(message) => {
+                ApiError::new(ErrorCode::InvalidRequest, message)
+            }
+            CacheError::UnknownParent(parent) => {
+                ApiError::validation(vec![validation::unknown_parent(&parent)])
+            }
+            _ => ApiError::internal(),
+        }
+    })
 }
 
 /// Update prompt metadata
@@ -167,52 +699,712 @@ pub async fn create_prompt(
     request_body = UpdateMetadataRequest,
     responses(
         (status = StatusCode::OK, description = "Successly updated prompt metadata", body = String),
-        (status = StatusCode::NOT_FOUND, description = "Prompt not found"),
-        (status = StatusCode::BAD_REQUEST, description = "Invalid request body")
+        (status = StatusCode::NOT_FOUND, description = "Prompt not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed JSON body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNSUPPORTED_MEDIA_TYPE, description = "Body is not JSON", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNPROCESSABLE_ENTITY, description = "Invalid fields", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
     )
 )]
 #[axum_macros::debug_handler]
 pub async fn update_prompt_metadata(
     State(state): State<AppState>,
-    Json(prompt): Json<UpdateMetadataRequest>,
-) -> Result<String, UpdateMetadataError> {
+    ApiJson(prompt): ApiJson<UpdateMetadataRequest>,
+) -> Result<String, ApiError> {
     let id = prompt.id.clone();
     info!("Updating metadata for prompt: {:?}", id);
-    state
-        .cache
-        .update_prompt_metadata(&id, prompt.into())
-        .map_err(|e| {
-            error!("Database error: {:?}", e);
-            match e {
-                CacheError::NotFound => UpdateMetadataError::NotFound,
-                _ => UpdateMetadataError::InternalServerError,
-            }
-        })?;
+    let errors = validation::metadata_request(&prompt, &state.limits);
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+    let prompt_id = id.clone();
+    with_store(&state, move |store| {
+        store.update_prompt_metadata(&prompt_id, prompt.into())
+    })
+    .await?
+    .map_err(|e| {
+        error!("Database error: {:?}", e);
+        match e {
+            CacheError::NotFound => ApiError::prompt_not_found(&id),
+            _ => ApiError::internal(),
+        }
+    })?;
 
     Ok(id)
 }
 
-/// Delete prompt
+/// Patch prompt metadata
+///
+/// Applies a JSON Merge Patch: omitted fields are left as they are and null clears a field. A
+/// prompt without metadata gets it.
+#[utoipa::path(
+    patch,
+    path = "/prompt/{id}/metadata",
+    params(
+        ("id" = String, Path, description = "Prompt identifier"),
+    ),
+    request_body(content = PatchMetadataRequest, content_type = "application/merge-patch+json"),
+    responses(
+        (status = StatusCode::OK, description = "Metadata after the patch", body = PromptMetadata),
+        (status = StatusCode::NOT_FOUND, description = "Prompt not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed JSON body", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNSUPPORTED_MEDIA_TYPE, description = "Body is not JSON", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::UNPROCESSABLE_ENTITY, description = "Invalid or unknown fields", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn patch_prompt_metadata(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<String>,
+    ApiJson(patch): ApiJson<PatchMetadataRequest>,
+) -> Result<Json<PromptMetadata>, ApiError> {
+    info!("Patching metadata for prompt: {:?}", id);
+    let errors = validation::metadata_patch(&patch, &state.limits);
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+    let prompt_id = id.clone();
+    let metadata = with_store(&state, move |store| {
+        store.patch_prompt_metadata(&prompt_id, patch.into())
+    })
+    .await?
+    .map_err(|e| {
+        error!("Database error: {:?}", e);
+        match e {
+            CacheError::NotFound => ApiError::prompt_not_found(&id),
+            _ => ApiError::internal(),
+        }
+    })?;
+
+    Ok(Json(metadata.into()))
+}
+
+/// Delete prompt by moving it to the trash
 #[utoipa::path(
     delete,
     path = "/prompt/{id}",
     responses(
         (status = StatusCode::OK, description = "Successly deleted prompt"),
-        (status = StatusCode::NOT_FOUND, description = "Prompt does not exist"),
-        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error")
+        (status = StatusCode::NOT_FOUND, description = "Prompt does not exist", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
     )
 )]
 #[axum_macros::debug_handler]
 pub async fn delete_prompt(
     State(state): State<AppState>,
-    Path(id): Path<String>,
-) -> Result<(), DeletePromptError> {
-    state
-        .cache
-        .delete_prompt(&id)
-        .map_err(|_| DeletePromptError::InternalServerError)?
+    ApiPath(id): ApiPath<String>,
+) -> Result<(), ApiError> {
+    let prompt_id = id.clone();
+    with_store(&state, move |store| store.delete_prompt(&prompt_id))
+        .await?
+        .map_err(|e| {
+            error!("Failed to delete prompt {}: {:?}", id, e);
+            ApiError::internal()
+        })?
         .then_some(())
-        .ok_or(DeletePromptError::NotFoundError)?;
+        .ok_or_else(|| ApiError::prompt_not_found(&id))?;
 
     Ok(())
 }
+
+/// Restore an archived prompt from the trash
+#[utoipa::path(
+    post,
+    path = "/prompt/{id}/restore",
+    params(
+        ("id" = String, Path, description = "Prompt identifier")
+    ),
+    responses(
+        (status = StatusCode::OK, description = "Successfully restored prompt"),
+        (status = StatusCode::NOT_FOUND, description = "Prompt does not exist", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn restore_prompt(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<String>,
+) -> Result<(), ApiError> {
+    info!("Restoring prompt: {}", id);
+    let prompt_id = id.clone();
+    with_store(&state, move |store| store.restore_prompt(&prompt_id))
+        .await?
+        .map_err(|e| {
+            error!("Failed to restore prompt {}: {:?}", id, e);
+            match e {
+                CacheError::NotFound => ApiError::prompt_not_found(&id),
+                _ => ApiError::internal(),
+            }
+        })
+}
+
+/// List archived prompts
+#[utoipa::path(
+    get,
+    path = "/trash",
+    params(
+        ("category" = Option<String>, Query, description = "The category of the archived prompts to return"),
+        ("cursor" = Option<String>, Query, description = "Cursor returned as next_cursor by the previous page"),
+        ("limit" = Option<u32>, Query, description = "The number of prompts to return (1-100). Default is 10.")
+    ),
+    responses(
+        (status = StatusCode::OK, description = "Successly retrieved archived prompts, as JSON unless Accept asks for YAML or MessagePack", content(
+            (PromptList = "application/json"),
+            (PromptList = "application/yaml"),
+            (PromptList = "application/msgpack")
+        )),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid limit or cursor", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn get_trash(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<GetTrashRequest>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    info!("Requested trash with params: {:?}", params);
+    let format = negotiation::negotiate(&headers, negotiation::STRUCTURED)?;
+    validate_limit(params.limit)?;
+    with_store(&state, move |store| store.get_trash(params.into()))
+        .await?
+        .map_err(|e| {
+            error!("Failed to get trash: {:?}", e);
+            match e {
+                CacheError::InvalidRequest(message) => {
+                    ApiError::new(ErrorCode::InvalidRequest, message)
+                }
+                _ => ApiError::internal(),
+            }
+        })
+        .and_then(|page| negotiation::respond(format, &PromptList::from(page)))
+}
+
+/// Permanently delete an archived prompt
+#[utoipa::path(
+    delete,
+    path = "/trash/{id}",
+    params(
+        ("id" = String, Path, description = "Prompt identifier")
+    ),
+    responses(
+        (status = StatusCode::OK, description = "Successfully purged prompt"),
+        (status = StatusCode::NOT_FOUND, description = "Prompt is not in the trash", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::CONFLICT, description = "Prompt is the root of a lineage that still has other versions", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn purge_prompt(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<String>,
+) -> Result<(), ApiError> {
+    info!("Purging prompt: {}", id);
+    let prompt_id = id.clone();
+    with_store(&state, move |store| store.purge_prompt(&prompt_id))
+        .await?
+        .map_err(|e| match e {
+            CacheError::LineageNotEmpty(_) => ApiError::new(
+                ErrorCode::LineageNotEmpty,
+                format!("Prompt {} is the root of a lineage that still has other versions, purge them first", id),
+            ),
+            _ => {
+                error!("Failed to purge prompt {}: {:?}", id, e);
+                ApiError::internal()
+            }
+        })?
+        .then_some(())
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::PromptNotFound,
+                format!("Prompt {} is not in the trash", id),
+            )
+        })
+}
+
+fn validate_threshold(threshold: Option<f64>) -> Result<f64, ApiError> {
+    let threshold = threshold.unwrap_or(DEFAULT_SIMILARITY_THRESHOLD);
+    match (0.0..=1.0).contains(&threshold) {
+        true => Ok(threshold),
+        false => Err(ApiError::invalid_field(
+            "threshold",
+            "must be between 0.0 and 1.0",
+        )),
+    }
+}
+
+/// Get prompts whose content is a near-duplicate of the given prompt
+#[utoipa::path(
+    get,
+    path = "/prompt/{id}/similar",
+    params(
+        ("id" = String, Path, description = "Prompt identifier"),
+        ("threshold" = Option<f64>, Query, description = "Minimum estimated similarity from 0.0 to 1.0. Default is 0.8."),
+        ("limit" = Option<u32>, Query, description = "The number of prompts to return (1-100). Default is 10."),
+        ("include_lineage" = Option<bool>, Query, description = "Whether to include other versions of the same lineage. Default is false.")
+    ),
+    responses(
+        (status = StatusCode::OK, description = "Successly retrieved similar prompts, as JSON unless Accept asks for YAML or MessagePack", content(
+            (Vec<SimilarPrompt> = "application/json"),
+            (Vec<SimilarPrompt> = "application/yaml"),
+            (Vec<SimilarPrompt> = "application/msgpack")
+        )),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid threshold or limit", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::NOT_FOUND, description = "Prompt not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn get_similar_prompts(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<String>,
+    ApiQuery(params): ApiQuery<GetSimilarPromptsRequest>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    info!("Requested prompts similar to {}: {:?}", id, params);
+    let format = negotiation::negotiate(&headers, negotiation::STRUCTURED)?;
+    let threshold = validate_threshold(params.threshold)?;
+    validate_limit(params.limit)?;
+
+    let prompt_id = id.clone();
+    let similar = with_store(&state, move |store| {
+        store.get_similar_prompts(
+            &prompt_id,
+            threshold,
+            params.limit.unwrap_or(DEFAULT_PAGE_SIZE) as usize,
+            params.include_lineage.unwrap_or(false),
+        )
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to get prompts similar to {}: {:?}", id, e);
+        match e {
+            CacheError::NotFound => ApiError::prompt_not_found(&id),
+            _ => ApiError::internal(),
+        }
+    })?;
+
+    let similar: Vec<SimilarPrompt> = similar.into_iter().map(SimilarPrompt::from).collect();
+    negotiation::respond(format, &similar)
+}
+
+/// Get prompts on related topics, ranked by TF-IDF similarity of content, name, description and tags
+#[utoipa::path(
+    get,
+    path = "/prompt/{id}/related",
+    params(
+        ("id" = String, Path, description = "Prompt identifier"),
+        ("limit" = Option<u32>, Query, description = "The number of prompts to return (1-100). Default is 10.")
+    ),
+    responses(
+        (status = StatusCode::OK, description = "Successly retrieved related prompts, as JSON unless Accept asks for YAML or MessagePack", content(
+            (Vec<SimilarPrompt> = "application/json"),
+            (Vec<SimilarPrompt> = "application/yaml"),
+            (Vec<SimilarPrompt> = "application/msgpack")
+        )),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed query string or invalid limit", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::NOT_FOUND, description = "Prompt not found", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn get_related_prompts(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<String>,
+    ApiQuery(params): ApiQuery<GetRelatedPromptsRequest>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    info!("Requested prompts related to {}: {:?}", id, params);
+    let format = negotiation::negotiate(&headers, negotiation::STRUCTURED)?;
+    validate_limit(params.limit)?;
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE) as usize;
+    let prompt_id = id.clone();
+    let related = with_store(&state, move |store| {
+        store.get_related_prompts(&prompt_id, limit)
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to get prompts related to {}: {:?}", id, e);
+        match e {
+            CacheError::NotFound => ApiError::prompt_not_found(&id),
+            _ => ApiError::internal(),
+        }
+    })?;
+
+    let related: Vec<SimilarPrompt> = related.into_iter().map(SimilarPrompt::from).collect();
+    negotiation::respond(format, &related)
+}
+
+/// Report groups of lineages whose latest versions are near-duplicates
+#[utoipa::path(
+    get,
+    path = "/prompts/duplicates",
+    params(
+        ("threshold" = Option<f64>, Query, description = "Minimum estimated similarity from 0.0 to 1.0. Default is 0.8.")
+    ),
+    responses(
+        (status = StatusCode::OK, description = "Successly retrieved duplicate groups, as JSON unless Accept asks for YAML or MessagePack", content(
+            (Vec<DuplicateGroup> = "application/json"),
+            (Vec<DuplicateGroup> = "application/yaml"),
+            (Vec<DuplicateGroup> = "application/msgpack")
+        )),
+        (status = StatusCode::NOT_ACCEPTABLE, description = "None of the representations in Accept is offered", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid threshold", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn get_duplicate_prompts(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<GetDuplicatesRequest>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    info!("Requested duplicate prompts: {:?}", params);
+    let format = negotiation::negotiate(&headers, negotiation::STRUCTURED)?;
+    let threshold = validate_threshold(params.threshold)?;
+
+    let groups = with_store(&state, move |store| store.get_duplicate_groups(threshold))
+        .await?
+        .map_err(|e| {
+            error!("Failed to get duplicate prompts: {:?}", e);
+            ApiError::internal()
+        })?;
+
+    let groups: Vec<DuplicateGroup> = groups
+        .into_iter()
+        .map(|group| DuplicateGroup {
+            prompts: group.into_iter().map(SimilarPrompt::from).collect(),
+        })
+        .collect();
+    negotiation::respond(format, &groups)
+}
+
+/// Export every prompt, archived ones included, with its metadata and lineage link
+#[utoipa::path(
+    get,
+    path = "/export",
+    params(
+        ("format" = Option<ExportFormat>, Query, description = "ndjson (default) streams one version per line, yaml returns a single bundle")
+    ),
+    responses(
+        (status = StatusCode::OK, description = "Successfully exported prompts", content(
+            (ExportedPrompt = "application/x-ndjson"),
+            (ExportBundle = "application/yaml")
+        )),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::BAD_REQUEST, description = "Malformed query string", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn export_prompts(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<ExportRequest>,
+) -> Result<Response, ApiError> {
+    let format = params.format.unwrap_or_default();
+    info!("Exporting prompts as {:?}", format);
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"prompts-{}.{}\"",
+                now_timestamp(),
+                format.extension()
+            ),
+        ),
+    ];
+    let internal_error = |e: CacheError| {
+        error!("Failed to export prompts: {:?}", e);
+        ApiError::internal()
+    };
+
+    match format {
+        ExportFormat::Yaml => {
+            let prompts = with_store(&state, |store| all_versions(store, None))
+                .await?
+                .map_err(internal_error)?;
+            Ok((headers, transfer::to_yaml(prompts)).into_response())
+        }
+        ExportFormat::Ndjson => {
+            // The first page is read upfront so an exhausted pool is still answered with a 503
+            let first = with_store(&state, |store| versions_page(store, None, None))
+                .await?
+                .map_err(internal_error)?;
+            let (tx, rx) = mpsc::channel(EXPORT_BUFFERED_PAGES);
+            let store = state.store.clone();
+            tokio::task::spawn_blocking(move || stream_export(store.as_ref(), first, tx));
+            Ok((headers, Body::from_stream(ReceiverStream::new(rx))).into_response())
+        }
+    }
+}
+
+/// Sends a chunk of NDJSON per page until the last page or until the client goes away. A page
+/// that fails to load ends the body with an error, so the client sees a truncated export rather
+/// than one that looks complete.
+fn stream_export(
+    store: &dyn PromptStore,
+    mut page: PromptPage,
+    tx: mpsc::Sender<std::io::Result<String>>,
+) {
+    loop {
+        let cursor = page.next_cursor.take();
+        if tx
+            .blocking_send(Ok(transfer::to_ndjson(page.items)))
+            .is_err()
+        {
+            debug!("Export cancelled by the client");
+            return;
+        }
+        let Some(cursor) = cursor else {
+            return;
+        };
+        match versions_page(store, None, Some(cursor)) {
+            Ok(next) => page = next,
+            Err(e) => {
+                error!("Failed to export prompts: {:?}", e);
+                let _ = tx.blocking_send(Err(std::io::Error::other(e.to_string())));
+                return;
+            }
+        }
+    }
+}
+
+/// Replay an export into this instance
+#[utoipa::path(
+    post,
+    path = "/import",
+    params(
+        ("dry_run" = Option<bool>, Query, description = "Only plan the import, nothing is written"),
+        ("ids" = Option<IdMode>, Query, description = "keep (default) the exported ids or remap them to new ones"),
+        ("on_conflict" = Option<ConflictPolicy>, Query, description = "fail (default) the whole import on a conflict or skip conflicting versions")
+    ),
+    request_body(content(
+        (ExportedPrompt = "application/x-ndjson"),
+        (ExportBundle = "application/yaml")
+    )),
+    responses(
+        (status = StatusCode::OK, description = "Successfully imported prompts, or planned the import on a dry run", body = ImportReport),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid export, a body that isn't UTF-8 or a lineage link to an unknown prompt", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::CONFLICT, description = "Ids are taken by different prompts, nothing was written", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::PAYLOAD_TOO_LARGE, description = "Body is larger than the import limit", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "No database connection available, retry after the Retry-After delay", body = ProblemDetails, content_type = "application/problem+json")
+    )
+)]
+#[axum_macros::debug_handler]
+pub async fn import_prompts(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<ImportRequest>,
+    headers: HeaderMap,
+    ApiText(body): ApiText,
+) -> Result<Json<ImportReport>, ApiError> {
+    let format = match headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) if content_type.contains("yaml") => ExportFormat::Yaml,
+        _ => ExportFormat::Ndjson,
+    };
+    let options = ImportOptions {
+        ids: params.ids.unwrap_or_default(),
+        on_conflict: params.on_conflict.unwrap_or_default(),
+        dry_run: params.dry_run.unwrap_or(false),
+    };
+    info!("Importing prompts from {:?} with {:?}", format, options);
+
+    let plan = with_store(&state, move |store| {
+        transfer::import(store, transfer::parse(&body, format)?, options)
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to import prompts: {:?}", e);
+        match e {
+            CacheError::InvalidRequest(message) => {
+                ApiError::new(ErrorCode::InvalidRequest, message)
+            }
+            CacheError::UnknownParent(_) => ApiError::new(ErrorCode::InvalidRequest, e.to_string()),
+            _ => ApiError::internal(),
+        }
+    })?;
+
+    let blocked = !options.dry_run
+        && options.on_conflict == ConflictPolicy::Fail
+        && !plan.conflicts.is_empty();
+    let report = ImportReport::from(plan);
+    match blocked {
+        true => Err(ApiError::import_conflict(report)),
+        false => Ok(Json(report)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ValidationConfig;
+    use crate::memory_store::MemoryStore;
+    use axum::{body::to_bytes, extract::Request, routing::post, Router};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        let state = AppState {
+            store: Arc::new(MemoryStore::new()),
+            backups: None,
+            limits: ValidationConfig::default().limits(),
+        };
+        Router::new()
+            .route("/prompt", post(create_prompt))
+            .route("/prompts:batchCreate", post(batch_create_prompts))
+            .with_state(state)
+    }
+
+    fn post_json(uri: &str, body: Value, headers: &[(&str, &str)]) -> Request {
+        headers
+            .iter()
+            .fold(Request::post(uri), |request, (name, value)| {
+                request.header(*name, *value)
+            })
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn send(app: &Router, request: Request) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_create_prompt() {
+        let app = app();
+
+        // The created prompt, with where to find it
+        let request = post_json(
+            "/prompt",
+            json!({"content": "Hello", "name": "Greeting", "tags": ["a"]}),
+            &[],
+        );
+        let (status, headers, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        let created: Value = serde_json::from_slice(&body).unwrap();
+        let id = created["id"].as_str().unwrap().to_string();
+        assert_eq!(headers[header::LOCATION], format!("/v1/prompt/{id}"));
+        assert_eq!(
+            created,
+            json!({
+                "id": id,
+                "content": "Hello",
+                "version": 1,
+                "parent": id,
+                "branched": null,
+                "archived": false,
+                "created_at": created["created_at"],
+                "metadata": {
+                    "name": "Greeting",
+                    "description": null,
+                    "category": null,
+                    "tags": ["a"],
+                },
+            })
+        );
+        assert!(created["created_at"].as_i64().unwrap() > 0);
+
+        // A new version is numbered after the lineage's newest
+        let request = post_json("/prompt", json!({"content": "Hi", "parent": id}), &[]);
+        let (status, _, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let version: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            (&version["version"], &version["parent"]),
+            (&json!(2), &json!(id))
+        );
+
+        // The bare id on request
+        let request = post_json(
+            "/prompt",
+            json!({"content": "Hey"}),
+            &[("prefer", "return=minimal")],
+        );
+        let (status, headers, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(headers[PREFERENCE_APPLIED], "return=minimal");
+        let id = String::from_utf8(body).unwrap();
+        assert_eq!(headers[header::LOCATION], format!("/v1/prompt/{id}"));
+
+        let request = post_json(
+            "/prompt",
+            json!({"content": "Hey"}),
+            &[("accept", "text/plain")],
+        );
+        let (status, headers, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(!headers.contains_key(PREFERENCE_APPLIED));
+        assert!(headers[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let id = String::from_utf8(body).unwrap();
+        assert_eq!(headers[header::LOCATION], format!("/v1/prompt/{id}"));
+    }
+    #[tokio::test]
+    async fn test_batch_create_prompts() {
+        let app = app();
+        let request = post_json("/prompt", json!({"content": "Hello"}), &[]);
+        let (_, _, body) = send(&app, request).await;
+        let root: Value = serde_json::from_slice(&body).unwrap();
+
+        // Every item gets a result in request order, invalid ones don't stop the rest
+        let request = post_json(
+            "/prompts:batchCreate",
+            json!({"prompts": [
+                {"content": "New lineage"},
+                {"content": " "},
+                {"content": "Orphan", "parent": "missing"},
+                {"content": "Hello again", "parent": root["id"]},
+            ]}),
+            &[],
+        );
+        let (status, _, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["created"], 2);
+        let results = response["results"].as_array().unwrap();
+        assert_eq!(results.len(), 4);
+
+        let created = &results[0]["prompt"];
+        assert_eq!(created["content"], "New lineage");
+        assert_eq!(
+            (&created["version"], &created["parent"]),
+            (&json!(1), &created["id"])
+        );
+        assert!(results[0].get("errors").is_none());
+
+        assert!(results[1].get("prompt").is_none());
+        assert_eq!(results[1]["errors"][0]["field"], "content");
+        assert!(results[2].get("prompt").is_none());
+        assert_eq!(results[2]["errors"][0]["field"], "parent");
+
+        let version = &results[3]["prompt"];
+        assert_eq!(version["content"], "Hello again");
+        assert_eq!(
+            (&version["version"], &version["parent"]),
+            (&json!(2), &root["id"])
+        );
+    }
+}
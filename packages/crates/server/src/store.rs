@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use crate::cache::{
+    CacheError, CacheResult, DbCategoryCount, DbMetadataPatch, DbPrompt, DbPromptHead,
+    DbPromptMetadata, DbSimilarPrompt, PromptPage, PromptQuery, PromptSort, SortOrder,
+    MAX_PAGE_SIZE,
+};
+use crate::read_cache::ReadCacheStats;
+
+/// Storage backend for prompts. `CacheConfig` (SQLite) is the default, `MemoryStore` keeps
+/// everything in process for tests and throwaway instances.
+pub trait PromptStore: Send + Sync {
+    /// Inserts a prompt, linked to the root of its parent's lineage and numbered after the
+    /// lineage's newest version, unless its own version is already higher. Returns the prompt as
+    /// stored, `UnknownParent` if the parent doesn't exist.
+    fn insert_prompt(&self, prompt: DbPrompt) -> CacheResult<DbPrompt>;
+
+    /// Inserts every prompt or, if any of them fails, none of them
+    fn insert_prompts(&self, prompts: Vec<DbPrompt>) -> CacheResult<Vec<DbPrompt>>;
+
+    fn get_prompt(&self, id: &str, metadata: Option<bool>) -> CacheResult<Option<DbPrompt>>;
+
+    fn get_prompt_content(&self, id: &str) -> CacheResult<String>;
+
+    /// Content of the `latest_version` of `id`, skipping archived versions
+    fn get_prompt_content_latest_version(&self, id: &str) -> CacheResult<String>;
+
+    fn get_prompts(&self, query: PromptQuery) -> CacheResult<PromptPage>;
+
+    /// Lists one entry per lineage: the highest version sharing a parent, with the lineage size.
+    /// Filters and sorting apply to the head version.
+    fn get_prompt_heads(&self, query: PromptQuery) -> CacheResult<PromptPage<DbPromptHead>>;
+
+    fn update_prompt_metadata(&self, id: &str, metadata: DbPromptMetadata) -> CacheResult<String>;
+
+    /// Merges `patch` into a prompt's metadata, creating the metadata when the prompt has none.
+    /// Returns the metadata as stored.
+    fn patch_prompt_metadata(
+        &self,
+        id: &str,
+        patch: DbMetadataPatch,
+    ) -> CacheResult<DbPromptMetadata>;
+
+    /// Archives a prompt, moving it to the trash
+    fn delete_prompt(&self, id: &str) -> CacheResult<bool>;
+
+    /// Takes a prompt out of the trash. Restoring a prompt that isn't archived is a no-op.
+    fn restore_prompt(&self, id: &str) -> CacheResult<()>;
+
+    /// Lists archived prompts
+    fn get_trash(&self, query: PromptQuery) -> CacheResult<PromptPage> {
+        self.get_prompts(PromptQuery {
+            archived: Some(true),
+            ..query
+        })
+    }
+
+    /// Permanently deletes an archived prompt and its metadata. Returns false if the prompt
+    /// isn't in the trash, and `LineageNotEmpty` if it's a root other versions still point to.
+    fn purge_prompt(&self, id: &str) -> CacheResult<bool>;
+
+    /// Permanently deletes prompts that have been in the trash for longer than `retention`. A
+    /// root is kept until every other version of its lineage is purged.
+    fn purge_expired(&self, retention: Duration) -> CacheResult<usize>;
+
+    fn get_prompt_categories(&self) -> CacheResult<Vec<String>>;
+
+    /// Number of non-archived prompts per category. Ancestors of nested categories are listed
+    /// too, with `total` including every prompt below them.
+    fn get_category_counts(&self) -> CacheResult<Vec<DbCategoryCount>>;
+
+    /// Renames a category and its nested categories, ie renaming frontend to web also moves
+    /// frontend/react to web/react. Returns the number of prompts updated.
+    fn rename_category(&self, from: &str, to: &str) -> CacheResult<usize> {
+        self.merge_categories(&[from.to_string()], to)
+    }
+
+    /// Moves every prompt in the source categories, nested categories included, into `target`
+    /// atomically. Returns the number of prompts updated.
+    fn merge_categories(&self, sources: &[String], target: &str) -> CacheResult<usize>;
+
+    /// Non-archived prompts whose content is estimated to be at least `threshold` similar to
+    /// the prompt `id`, most similar first. Versions of the same lineage are skipped unless
+    /// `include_lineage` is set.
+    fn get_similar_prompts(
+        &self,
+        id: &str,
+        threshold: f64,
+        limit: usize,
+        include_lineage: bool,
+    ) -> CacheResult<Vec<DbSimilarPrompt>>;
+
+    /// Groups lineages whose head versions are near-duplicates of each other. Each member's
+    /// similarity is its highest similarity to another member of the group.
+    fn get_duplicate_groups(&self, threshold: f64) -> CacheResult<Vec<Vec<DbSimilarPrompt>>>;
+
+    /// Lineage heads ranked by TF-IDF cosine similarity to the prompt `id`, excluding its own
+    /// lineage and archived prompts
+    fn get_related_prompts(&self, id: &str, limit: usize) -> CacheResult<Vec<DbSimilarPrompt>>;
+
+    /// Hit and miss counters of the read-through cache, if this store has one
+    fn read_cache_stats(&self) -> Option<ReadCacheStats> {
+        None
+    }
+}
+
+/// A page of every version, or every version of one lineage, archived ones included and oldest
+/// first. Versions written while paging land after the last page.
+pub fn versions_page(
+    store: &dyn PromptStore,
+    parent: Option<&str>,
+    cursor: Option<String>,
+) -> CacheResult<PromptPage> {
+    store.get_prompts(PromptQuery {
+        parent: parent.map(str::to_string),
+        include_archived: true,
+        sort: PromptSort::CreatedAt,
+        order: SortOrder::Asc,
+        cursor,
+        limit: MAX_PAGE_SIZE,
+        ..PromptQuery::default()
+    })
+}
+
+/// The highest version in the lineage of `id`, which can be any of its versions. Archived
+/// versions are skipped unless `include_archived` is set, `Archived` means every version is.
+pub fn latest_version(
+    store: &dyn PromptStore,
+    id: &str,
+    include_archived: bool,
+) -> CacheResult<DbPrompt> {
+    let prompt = store.get_prompt(id, None)?.ok_or(CacheError::NotFound)?;
+    store
+        .get_prompt_heads(PromptQuery {
+            parent: Some(prompt.parent),
+            include_archived,
+            limit: 1,
+            ..PromptQuery::default()
+        })?
+        .items
+        .into_iter()
+        .next()
+        .map(|head| head.prompt)
+        .ok_or(CacheError::Archived)
+}
+
+/// Every page of `versions_page`
+pub fn all_versions(store: &dyn PromptStore, parent: Option<&str>) -> CacheResult<Vec<DbPrompt>> {
+    let mut versions = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = versions_page(store, parent, cursor)?;
+        versions.extend(page.items);
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return Ok(versions),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+/*
+TF-IDF term weighting for related prompt recommendations.
+https://en.wikipedia.org/wiki/Tf%E2%80%93idf
+
+Each prompt is a document made of its content, name, description and tags. Term frequencies are
+stored per document and document frequencies per term, so both can be updated one prompt at a
+time. IDF depends on the whole corpus and is applied at query time.
+*/
+
+use std::collections::HashMap;
+
+// Short fields describe the prompt more directly than its content does
+const NAME_BOOST: f64 = 3.0;
+const TAG_BOOST: f64 = 3.0;
+const DESCRIPTION_BOOST: f64 = 2.0;
+const CONTENT_BOOST: f64 = 1.0;
+
+const STOPWORDS: &[&str] = &[
+    "a", "about", "all", "also", "an", "and", "any", "are", "as", "at", "be", "been", "but", "by",
+    "can", "do", "does", "each", "for", "from", "has", "have", "how", "if", "in", "into", "is",
+    "it", "its", "may", "more", "most", "must", "no", "not", "of", "on", "only", "or", "other",
+    "our", "over", "should", "so", "such", "than", "that", "the", "their", "them", "then", "there",
+    "these", "they", "this", "those", "to", "use", "using", "was", "we", "were", "what", "when",
+    "where", "which", "while", "will", "with", "would", "you", "your",
+];
+
+pub struct Document<'a> {
+    pub content: &'a str,
+    pub name: Option<&'a str>,
+    pub description: Option<&'a str>,
+    pub tags: &'a [String],
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .filter(|term| term.chars().count() > 1)
+        .filter(|term| !term.chars().all(|c| c.is_ascii_digit()))
+        .filter(|term| !STOPWORDS.contains(&term.as_str()))
+}
+
+/// Boosted term frequencies of a document
+pub fn term_weights(document: &Document) -> HashMap<String, f64> {
+    let fields = [
+        (Some(document.content), CONTENT_BOOST),
+        (document.name, NAME_BOOST),
+        (document.description, DESCRIPTION_BOOST),
+    ];
+
+    let mut weights = HashMap::new();
+    fields
+        .into_iter()
+        .filter_map(|(text, boost)| text.map(|text| (text, boost)))
+        .chain(document.tags.iter().map(|tag| (tag.as_str(), TAG_BOOST)))
+        .for_each(|(text, boost)| {
+            tokenize(text).for_each(|term| *weights.entry(term).or_insert(0.0) += boost)
+        });
+    weights
+}
+
+/// Smoothed inverse document frequency
+pub fn idf(documents: i64, documents_with_term: i64) -> f64 {
+    ((1.0 + documents as f64) / (1.0 + documents_with_term as f64)).ln() + 1.0
+}
+
+/// Cosine similarity of two sparse TF-IDF vectors
+pub fn cosine(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let dot: f64 = a
+        .iter()
+        .filter_map(|(term, weight)| b.get(term).map(|other| weight * other))
+        .sum();
+    let norm = |vector: &HashMap<String, f64>| vector.values().map(|w| w * w).sum::<f64>().sqrt();
+
+    match norm(a) * norm(b) {
+        0.0 => 0.0,
+        norms => dot / norms,
+    }
+}
@@ -0,0 +1,436 @@
+/*
+Export and import of every prompt, to move prompts between instances.
+
+An export lists every version, archived ones included and oldest first, each with its metadata
+and its lineage link (`parent`, the root of its lineage). NDJSON has one version per line and is
+streamed a page at a time. The YAML bundle holds the same records in a single document.
+
+Importing plans before it writes. When ids are kept, an id that already exists with the same
+content and lineage is unchanged, anything else under that id is a conflict. When ids are
+remapped every version gets a new id and lineage links follow it, so nothing conflicts. A lineage
+link must point into the export or, when keeping ids, at a prompt already in the store. The
+planned versions are written with a single `insert_prompts`, all or nothing.
+*/
+
+use std::collections::{BTreeMap, HashSet};
+
+use clap::ValueEnum;
+use log::info;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api_models::{ExportBundle, ExportedPrompt};
+use crate::cache::{now_timestamp, CacheError, CacheResult, DbPrompt, DbPromptMetadata};
+use crate::store::PromptStore;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema, ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON record per line
+    #[default]
+    Ndjson,
+    /// A single YAML document
+    Yaml,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Yaml => "application/yaml",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Ndjson => "ndjson",
+            Self::Yaml => "yaml",
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema, ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum IdMode {
+    /// Versions keep their exported ids
+    #[default]
+    Keep,
+    /// Every version gets a new id, lineage links are rewritten to match
+    Remap,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema, ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Nothing is written if any version conflicts
+    #[default]
+    Fail,
+    /// Conflicting versions are left out, the rest is written
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportOptions {
+    pub ids: IdMode,
+    pub on_conflict: ConflictPolicy,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub id: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportPlan {
+    /// Versions to create, roots before the rest of their lineage
+    pub prompts: Vec<DbPrompt>,
+    pub unchanged: usize,
+    pub conflicts: Vec<Conflict>,
+    /// Exported id to new id, empty unless ids are remapped
+    pub id_map: BTreeMap<String, String>,
+    pub applied: bool,
+}
+
+/// One line per version
+pub fn to_ndjson(prompts: Vec<DbPrompt>) -> String {
+    prompts
+        .into_iter()
+        .map(|prompt| {
+            let line = serde_json::to_string(&ExportedPrompt::from(prompt))
+                .expect("Failed to serialize prompt to JSON");
+            line + "\n"
+        })
+        .collect()
+}
+
+pub fn to_yaml(prompts: Vec<DbPrompt>) -> String {
+    serde_yaml::to_string(&ExportBundle {
+        exported_at: now_timestamp(),
+        prompts: prompts.into_iter().map(ExportedPrompt::from).collect(),
+    })
+    .expect("Failed to serialize export to YAML")
+}
+
+/// Reads an export back into versions
+pub fn parse(text: &str, format: ExportFormat) -> CacheResult<Vec<DbPrompt>> {
+    let invalid = |e: &dyn std::fmt::Display| CacheError::InvalidRequest(e.to_string());
+    let records = match format {
+        ExportFormat::Ndjson => text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| {
+                serde_json::from_str::<ExportedPrompt>(line)
+                    .map_err(|e| invalid(&format!("line {}: {}", number + 1, e)))
+            })
+            .collect::<CacheResult<Vec<ExportedPrompt>>>()?,
+        ExportFormat::Yaml => {
+            serde_yaml::from_str::<ExportBundle>(text)
+                .map_err(|e| invalid(&e))?
+                .prompts
+        }
+    };
+    Ok(records.into_iter().map(DbPrompt::from).collect())
+}
+
+/// Works out what importing `records` would do without writing anything
+pub fn plan_import(
+    store: &dyn PromptStore,
+    records: Vec<DbPrompt>,
+    ids: IdMode,
+) -> CacheResult<ImportPlan> {
+    let mut exported = HashSet::new();
+    if let Some(duplicate) = records
+        .iter()
+        .find(|record| !exported.insert(record.id.clone()))
+    {
+        return Err(CacheError::InvalidRequest(format!(
+            "Prompt {} is exported more than once",
+            duplicate.id
+        )));
+    }
+
+    let mut plan = ImportPlan::default();
+    match ids {
+        IdMode::Keep => {
+            for record in records {
+                match store.get_prompt(&record.id, None)? {
+                    Some(existing)
+                        if existing.content == record.content
+                            && existing.parent == record.parent =>
+                    {
+                        plan.unchanged += 1
+                    }
+                    Some(existing) => plan.conflicts.push(Conflict {
+                        reason: match existing.parent == record.parent {
+                            true => "id is taken by a version with different content",
+                            false => "id is taken by a prompt in another lineage",
+                        }
+                        .to_string(),
+                        id: record.id,
+                    }),
+                    None => {
+                        if !exported.contains(&record.parent)
+                            && store.get_prompt(&record.parent, None)?.is_none()
+                        {
+                            return Err(unknown_parent(&record));
+                        }
+                        plan.prompts.push(record);
+                    }
+                }
+            }
+
+            // Versions of a root whose id is taken by another prompt would be linked into that
+            // prompt's lineage
+            let conflicting: HashSet<String> = plan
+                .conflicts
+                .iter()
+                .map(|conflict| conflict.id.clone())
+                .collect();
+            let (orphaned, prompts) = std::mem::take(&mut plan.prompts)
+                .into_iter()
+                .partition(|record| conflicting.contains(&record.parent));
+            plan.prompts = prompts;
+            plan.conflicts
+                .extend(orphaned.into_iter().map(|record: DbPrompt| Conflict {
+                    reason: format!("lineage root {} conflicts", record.parent),
+                    id: record.id,
+                }));
+        }
+        IdMode::Remap => {
+            plan.id_map = records
+                .iter()
+                .map(|record| (record.id.clone(), Uuid::new_v4().to_string()))
+                .collect();
+            for record in records {
+                let Some(parent) = plan.id_map.get(&record.parent).cloned() else {
+                    return Err(unknown_parent(&record));
+                };
+                let id = plan.id_map[&record.id].clone();
+                plan.prompts.push(DbPrompt {
+                    metadata: record.metadata.map(|metadata| DbPromptMetadata {
+                        id: id.clone(),
+                        ..metadata
+                    }),
+                    id,
+                    parent,
+                    ..record
+                });
+            }
+        }
+    }
+
+    // Roots first so every lineage link points at a row that's already there, then versions in
+    // order so the store keeps their numbers
+    plan.prompts.sort_by(|a, b| {
+        (a.id != a.parent, a.version, a.created_at, &a.id).cmp(&(
+            b.id != b.parent,
+            b.version,
+            b.created_at,
+            &b.id,
+        ))
+    });
+    Ok(plan)
+}
+
+fn unknown_parent(record: &DbPrompt) -> CacheError {
+    CacheError::InvalidRequest(format!(
+        "Prompt {} links to {}, which is neither exported nor in the store",
+        record.id, record.parent
+    ))
+}
+
+/// Plans the import and writes it, unless it's a dry run or conflicts stop it
+pub fn import(
+    store: &dyn PromptStore,
+    records: Vec<DbPrompt>,
+    options: ImportOptions,
+) -> CacheResult<ImportPlan> {
+    let mut plan = plan_import(store, records, options.ids)?;
+    let blocked = !plan.conflicts.is_empty() && options.on_conflict == ConflictPolicy::Fail;
+    if options.dry_run || blocked || plan.prompts.is_empty() {
+        return Ok(plan);
+    }
+
+    plan.prompts = store.insert_prompts(plan.prompts)?;
+    plan.applied = true;
+    info!(
+        "Imported {} prompts, {} unchanged, {} conflicts skipped",
+        plan.prompts.len(),
+        plan.unchanged,
+        plan.conflicts.len()
+    );
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+    use crate::store::all_versions;
+
+    fn prompt(id: &str, parent: &str, created_at: i64) -> DbPrompt {
+        DbPrompt {
+            id: id.to_string(),
+            version: 1,
+            content: format!("Content of {}", id),
+            parent: parent.to_string(),
+            branched: Some(false),
+            archived: Some(id.ends_with("archived")),
+            created_at,
+            metadata: Some(DbPromptMetadata {
+                id: id.to_string(),
+                name: Some(format!("Name of {}", id)),
+                description: None,
+                category: Some("support".to_string()),
+                tags: Some(vec!["a".to_string()]),
+                updated_at: created_at,
+            }),
+            commit: None,
+        }
+    }
+
+    fn source() -> MemoryStore {
+        let store = MemoryStore::new();
+        for prompt in [
+            prompt("a", "a", 1),
+            prompt("a2-archived", "a", 2),
+            prompt("b", "b", 3),
+        ] {
+            store.insert_prompt(prompt).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let exported = all_versions(&source(), None).unwrap();
+        for format in [ExportFormat::Ndjson, ExportFormat::Yaml] {
+            let text = match format {
+                ExportFormat::Ndjson => to_ndjson(exported.clone()),
+                ExportFormat::Yaml => to_yaml(exported.clone()),
+            };
+            let records = parse(&text, format).unwrap();
+
+            let target = MemoryStore::new();
+            let plan = import(&target, records, ImportOptions::default()).unwrap();
+            assert!(plan.applied);
+            assert_eq!(
+                to_ndjson(all_versions(&target, None).unwrap()),
+                to_ndjson(exported.clone())
+            );
+            // Archived versions land in the trash
+            assert_eq!(
+                target
+                    .get_trash(Default::default())
+                    .unwrap()
+                    .items
+                    .iter()
+                    .map(|p| p.id.as_str())
+                    .collect::<Vec<_>>(),
+                vec!["a2-archived"]
+            );
+        }
+
+        assert!(matches!(
+            parse("{\"id\": 1}\n", ExportFormat::Ndjson),
+            Err(CacheError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_conflicts_and_remapping() {
+        let records = all_versions(&source(), None).unwrap();
+        let target = MemoryStore::new();
+        target.insert_prompt(prompt("a", "a", 1)).unwrap();
+        target
+            .insert_prompt(DbPrompt {
+                content: "Something else".to_string(),
+                ..prompt("b", "b", 3)
+            })
+            .unwrap();
+
+        // b conflicts so nothing is written
+        let plan = import(&target, records.clone(), ImportOptions::default()).unwrap();
+        assert!(!plan.applied);
+        assert_eq!((plan.prompts.len(), plan.unchanged), (1, 1));
+        assert_eq!(
+            plan.conflicts
+                .iter()
+                .map(|c| c.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["b"]
+        );
+        assert!(target.get_prompt("a2-archived", None).unwrap().is_none());
+
+        // A dry run doesn't write either, skipping conflicts writes the rest
+        let options = ImportOptions {
+            on_conflict: ConflictPolicy::Skip,
+            ..ImportOptions::default()
+        };
+        let plan = import(
+            &target,
+            records.clone(),
+            ImportOptions {
+                dry_run: true,
+                ..options
+            },
+        )
+        .unwrap();
+        assert!(!plan.applied);
+        let plan = import(&target, records.clone(), options).unwrap();
+        assert!(plan.applied);
+        assert!(target.get_prompt("a2-archived", None).unwrap().is_some());
+
+        // Remapped ids never conflict and keep lineages together
+        let plan = import(
+            &target,
+            records.clone(),
+            ImportOptions {
+                ids: IdMode::Remap,
+                ..ImportOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(plan.applied && plan.conflicts.is_empty());
+        let new_a = &plan.id_map["a"];
+        let new_a2 = target
+            .get_prompt(&plan.id_map["a2-archived"], Some(true))
+            .unwrap()
+            .unwrap();
+        assert_eq!(&new_a2.parent, new_a);
+        assert_eq!(&new_a2.metadata.unwrap().id, &new_a2.id);
+
+        // Versions of a conflicting root aren't linked into the other prompt's lineage
+        let plan = import(
+            &target,
+            vec![prompt("b", "b", 3), prompt("b2", "b", 5)],
+            options,
+        )
+        .unwrap();
+        assert!(plan.prompts.is_empty());
+        assert_eq!(
+            plan.conflicts
+                .iter()
+                .map(|c| c.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["b", "b2"]
+        );
+        assert!(target.get_prompt("b2", None).unwrap().is_none());
+
+        // A lineage link has to resolve somewhere
+        assert!(matches!(
+            plan_import(&target, vec![prompt("c2", "c", 4)], IdMode::Keep),
+            Err(CacheError::InvalidRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,258 @@
+/*
+Checks on prompts and metadata sent to the API before they reach the store. Every offending field
+is reported rather than only the first, so a client can fix a request in one go. Limits come from
+the `[validation]` config section.
+*/
+
+use std::collections::HashMap;
+
+use crate::api_error::FieldError;
+use crate::api_models::{CreatePromptRequest, PatchMetadataRequest, UpdateMetadataRequest};
+use crate::cache::CacheResult;
+use crate::store::PromptStore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_content_bytes: usize,
+    pub max_name_chars: usize,
+    pub max_description_chars: usize,
+    pub max_category_chars: usize,
+    pub max_tags: usize,
+    pub max_tag_chars: usize,
+    pub max_batch_items: usize,
+}
+
+#[derive(Default)]
+struct Errors(Vec<FieldError>);
+
+impl Errors {
+    fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Optional text that may not be blank when it's set
+    fn text(&mut self, field: &str, value: Option<&str>, max_chars: usize) {
+        let Some(value) = value else {
+            return;
+        };
+        if value.trim().is_empty() {
+            self.add(field, "must not be blank");
+        } else if value.chars().count() > max_chars {
+            self.add(field, format!("must be at most {} characters", max_chars));
+        }
+    }
+
+    fn metadata(
+        &mut self,
+        name: Option<&str>,
+        description: Option<&str>,
+        category: Option<&str>,
+        tags: Option<&[String]>,
+        limits: &Limits,
+    ) {
+        self.text("name", name, limits.max_name_chars);
+        self.text("description", description, limits.max_description_chars);
+        self.text("category", category, limits.max_category_chars);
+        if category.is_some_and(|category| {
+            !category.trim().is_empty() && category.split('/').any(|part| part.trim().is_empty())
+        }) {
+            self.add("category", "must not have empty nested categories");
+        }
+
+        let Some(tags) = tags else {
+            return;
+        };
+        if tags.len() > limits.max_tags {
+            self.add(
+                "tags",
+                format!("must have at most {} tags", limits.max_tags),
+            );
+        }
+        // Tags are stored comma separated, so a comma would split a tag in two
+        let mut seen = HashMap::new();
+        for (i, tag) in tags.iter().enumerate() {
+            let field = format!("tags[{}]", i);
+            if tag.trim().is_empty() {
+                self.add(field, "must not be blank");
+            } else if tag.contains(',') {
+                self.add(field, "must not contain commas");
+            } else if tag.chars().count() > limits.max_tag_chars {
+                self.add(
+                    field,
+                    format!("must be at most {} characters", limits.max_tag_chars),
+                );
+            } else if let Some(first) = seen.get(tag.as_str()) {
+                self.add(field, format!("duplicates tags[{}]", first));
+            } else {
+                seen.insert(tag.as_str(), i);
+            }
+        }
+    }
+}
+
+/// Field errors of a new prompt or version, the parent is checked by `parent`
+pub fn create_request(request: &CreatePromptRequest, limits: &Limits) -> Vec<FieldError> {
+    let mut errors = Errors::default();
+    if request.content.trim().is_empty() {
+        errors.add("content", "must not be blank");
+    } else if request.content.len() > limits.max_content_bytes {
+        errors.add(
+            "content",
+            format!("must be at most {} bytes", limits.max_content_bytes),
+        );
+    }
+    errors.metadata(
+        request.name.as_deref(),
+        request.description.as_deref(),
+        request.category.as_deref(),
+        request.tags.as_deref(),
+        limits,
+    );
+    errors.0
+}
+
+/// The parent of a new version has to exist. Checked again when it's inserted, which also links
+/// the version to the root of the parent's lineage.
+pub fn parent(store: &dyn PromptStore, parent: Option<&str>) -> CacheResult<Option<FieldError>> {
+    let Some(parent) = parent else {
+        return Ok(None);
+    };
+    Ok(store
+        .get_prompt(parent, None)?
+        .is_none()
+        .then(|| unknown_parent(parent)))
+}
+
+pub fn unknown_parent(parent: &str) -> FieldError {
+    FieldError {
+        field: "parent".to_string(),
+        message: format!("prompt {} does not exist", parent),
+    }
+}
+
+pub fn metadata_request(request: &UpdateMetadataRequest, limits: &Limits) -> Vec<FieldError> {
+    let mut errors = Errors::default();
+    if request.id.trim().is_empty() {
+        errors.add("id", "must not be blank");
+    }
+    errors.metadata(
+        request.name.as_deref(),
+        request.description.as_deref(),
+        request.category.as_deref(),
+        request.tags.as_deref(),
+        limits,
+    );
+    errors.0
+}
+
+/// A batch over the limit is refused as a whole
+pub fn batch_size(field: &str, len: usize, limits: &Limits) -> Vec<FieldError> {
+    let mut errors = Errors::default();
+    if len > limits.max_batch_items {
+        errors.add(
+            field,
+            format!("must have at most {} items", limits.max_batch_items),
+        );
+    }
+    errors.0
+}
+
+/// Only the fields being set are checked, null clears a field and is always valid
+pub fn metadata_patch(request: &PatchMetadataRequest, limits: &Limits) -> Vec<FieldError> {
+    let mut errors = Errors::default();
+    errors.metadata(
+        request.name.as_ref().and_then(Option::as_deref),
+        request.description.as_ref().and_then(Option::as_deref),
+        request.category.as_ref().and_then(Option::as_deref),
+        request.tags.as_ref().and_then(Option::as_deref),
+        limits,
+    );
+    errors.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ValidationConfig;
+    use crate::memory_store::MemoryStore;
+
+    fn request(content: &str) -> CreatePromptRequest {
+        CreatePromptRequest {
+            content: content.to_string(),
+            name: None,
+            description: None,
+            category: None,
+            tags: None,
+            parent: None,
+            branched: None,
+        }
+    }
+
+    fn fields(errors: &[FieldError]) -> Vec<&str> {
+        errors.iter().map(|e| e.field.as_str()).collect()
+    }
+
+    #[test]
+    fn test_create_request() {
+        let limits = ValidationConfig::default().limits();
+        assert!(create_request(&request("Hello"), &limits).is_empty());
+
+        let errors = create_request(
+            &CreatePromptRequest {
+                name: Some(" ".to_string()),
+                description: Some("d".repeat(limits.max_description_chars + 1)),
+                category: Some("writing//email".to_string()),
+                tags: Some(
+                    ["a", "", "b,c", "a", &"t".repeat(limits.max_tag_chars + 1)]
+                        .map(str::to_string)
+                        .to_vec(),
+                ),
+                ..request("  \n")
+            },
+            &limits,
+        );
+        assert_eq!(
+            fields(&errors),
+            vec![
+                "content",
+                "name",
+                "description",
+                "category",
+                "tags[1]",
+                "tags[2]",
+                "tags[3]",
+                "tags[4]"
+            ]
+        );
+        assert_eq!(errors[6].message, "duplicates tags[0]");
+
+        let limits = Limits {
+            max_content_bytes: 4,
+            max_tags: 1,
+            ..limits
+        };
+        let errors = create_request(
+            &CreatePromptRequest {
+                tags: Some(vec!["a".to_string(), "b".to_string()]),
+                ..request("Hello")
+            },
+            &limits,
+        );
+        assert_eq!(fields(&errors), vec!["content", "tags"]);
+    }
+
+    #[test]
+    fn test_parent() {
+        let store = MemoryStore::new();
+        let prompt = store.insert_prompt(request("Hello").into()).unwrap();
+        assert!(parent(&store, None).unwrap().is_none());
+        assert!(parent(&store, Some(&prompt.id)).unwrap().is_none());
+        assert_eq!(
+            parent(&store, Some("missing")).unwrap().unwrap().field,
+            "parent"
+        );
+    }
+}
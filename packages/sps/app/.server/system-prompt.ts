@@ -2,7 +2,6 @@ import { SystemPromptStorage } from "system-prompt-storage";
 import type {
 	Prompt,
 	PromptCreateParams,
-	PromptListParams,
 	PromptRetrieveParams,
 	PromptUpdateMetadataParams,
 } from "system-prompt-storage/resources/prompts";
@@ -11,13 +10,29 @@ const client = new SystemPromptStorage({
 	apiKey: "empty",
 });
 
-export async function getPrompts(params: PromptListParams): Promise<Prompt[]> {
-	console.log("Fetching prompts with params:", params);
-	const prompts = await client.prompts.list(params);
+export interface PromptListQuery {
+	limit?: number;
+	cursor?: string;
+	category?: string;
+}
+
+export interface PromptPage {
+	items: Prompt[];
+	next_cursor: string | null;
+	total: number;
+}
+
+/**
+ * Fetches one page of prompts. Pass the previous page's `next_cursor` as
+ * `cursor` to continue; a `null` cursor means there are no more pages.
+ */
+export async function getPrompts(query: PromptListQuery): Promise<PromptPage> {
+	console.log("Fetching prompts with query:", query);
+	const page = (await client.get("/v1/prompts", { query })) as PromptPage;
 	console.log(
-		`Retrieved ${prompts.length} prompts${prompts.length > 0 ? `, first prompt: ${prompts[0].content.substring(0, 50)}...` : ""}`,
+		`Retrieved ${page.items.length} of ${page.total} prompts${page.items.length > 0 ? `, first prompt: ${page.items[0].content.substring(0, 50)}...` : ""}`,
 	);
-	return prompts;
+	return page;
 }
 
 /**
@@ -1,23 +1,25 @@
 import type { Route } from "./+types/api.prompts";
+import type { PromptListQuery } from "~/.server/system-prompt";
 
 export async function loader({ request }: Route.LoaderArgs) {
 	const { getPrompts } = await import("~/.server/system-prompt");
 	const url = new URL(request.url);
-	const offset = Number.parseInt(url.searchParams.get("offset") || "0");
 	const limit = Number.parseInt(url.searchParams.get("limit") || "20");
+	const cursor = url.searchParams.get("cursor");
 	const category = url.searchParams.get("category");
 
-	const params: { offset: number; limit: number; category?: string } = {
-		offset,
-		limit,
-	};
+	const query: PromptListQuery = { limit };
+
+	if (cursor) {
+		query.cursor = cursor;
+	}
 
 	if (category) {
-		params.category = category;
+		query.category = category;
 	}
 
-	const prompts = await getPrompts(params);
-	return new Response(JSON.stringify(prompts), {
+	const page = await getPrompts(query);
+	return new Response(JSON.stringify(page), {
 		headers: {
 			"Content-Type": "application/json",
 		},
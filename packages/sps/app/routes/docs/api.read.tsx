@@ -60,11 +60,11 @@ export default function ReadApiPage() {
 						description: "The category of the prompts to return",
 					},
 					{
-						name: "offset",
-						type: "integer",
+						name: "cursor",
+						type: "string",
 						required: false,
 						description:
-							"The pagination offset to start from (0-based). Default is 0.",
+							"The next_cursor of the previous page. Omit to start from the first page.",
 					},
 					{
 						name: "limit",
@@ -73,7 +73,7 @@ export default function ReadApiPage() {
 						description: "The number of prompts to return. Default is 10.",
 					},
 				]}
-				responseType="200 - Array of Prompt objects | 400 - Invalid request body"
+				responseType="200 - Page of prompts ({ items, next_cursor, total }) | 400 - Invalid request"
 				codeSnippets={codeSnippets.listPrompts}
 			/>
 		</div>
@@ -3,6 +3,7 @@ import type { Route } from "./+types/prompts";
 import AsciiTable from "~/wrapper-components/ascii-table";
 import type { Prompt } from "system-prompt-storage/resources/prompts";
 import { useFetcher } from "react-router";
+import type { PromptPage } from "~/.server/system-prompt";
 
 const ITEMS_PER_PAGE = 12;
 
@@ -11,37 +12,42 @@ export async function loader({ request }: Route.LoaderArgs) {
 		"~/.server/system-prompt"
 	);
 
-	const [prompts, categories] = await Promise.all([
+	const [page, categories] = await Promise.all([
 		getPrompts({
 			limit: ITEMS_PER_PAGE,
-			offset: 0,
 		}),
 		getPromptCategories(),
 	]);
 
-	return { prompts, categories };
+	return { page, categories };
 }
 
 export default function Prompts({ loaderData }: Route.ComponentProps) {
-	const [prompts, setPrompts] = useState<Prompt[]>(loaderData.prompts);
+	const [prompts, setPrompts] = useState<Prompt[]>(loaderData.page.items);
 	const [selectedCategory, setSelectedCategory] = useState<string | null>(null);
-	const fetcher = useFetcher<Prompt[]>();
-	const [hasMore, setHasMore] = useState(true);
+	const fetcher = useFetcher<PromptPage>();
+	const [nextCursor, setNextCursor] = useState<string | null>(
+		loaderData.page.next_cursor,
+	);
+	const [hasMore, setHasMore] = useState(loaderData.page.next_cursor !== null);
 	const observerTarget = useRef<HTMLDivElement>(null);
 
 	useEffect(() => {
-		if (fetcher.data && Array.isArray(fetcher.data)) {
-			if (fetcher.data.length === 0) {
-				setHasMore(false);
-			} else {
-				setPrompts((prev) => [...prev, ...(fetcher.data ?? [])]);
-			}
+		if (fetcher.data) {
+			const page = fetcher.data;
+			setPrompts((prev) => [...prev, ...page.items]);
+			setNextCursor(page.next_cursor);
+			setHasMore(page.next_cursor !== null);
 		}
 	}, [fetcher.data]);
 
 	function loadMore() {
+		if (!nextCursor) {
+			return;
+		}
+
 		const params = new URLSearchParams({
-			offset: prompts.length.toString(),
+			cursor: nextCursor,
 			limit: ITEMS_PER_PAGE.toString(),
 		});
 
@@ -55,10 +61,10 @@ export default function Prompts({ loaderData }: Route.ComponentProps) {
 	function handleCategoryChange(category: string | null) {
 		setSelectedCategory(category);
 		setPrompts([]);
+		setNextCursor(null);
 		setHasMore(true);
 
 		const params = new URLSearchParams({
-			offset: "0",
 			limit: ITEMS_PER_PAGE.toString(),
 		});
 
@@ -88,7 +94,7 @@ export default function Prompts({ loaderData }: Route.ComponentProps) {
 		}
 
 		return () => observer.disconnect();
-	}, [prompts.length, fetcher.state, hasMore]);
+	}, [nextCursor, fetcher.state, hasMore]);
 
 	const isLoading = fetcher.state === "loading";
 